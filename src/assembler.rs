@@ -0,0 +1,638 @@
+use std::collections::{BTreeMap, HashMap};
+
+//
+// Assembles Octo style source into a ROM for 0x200. Covers the instruction
+// syntax the disassembler prints plus
+//
+//   : name              a label here, calling it is just writing its name
+//   :const NAME 12      a name for a number
+//   :alias NAME v3      a name for a register
+//   :call name          call, also for numeric addresses
+//   if v0 == 5 then v1 += 1
+//   if v0 key begin ... else ... end
+//   loop ... while v1 != 0 ... again
+//   0xFF 0b10000001 7   bytes of data, sprites and tables
+//
+// Comments run from # to the end of the line. Errors give line and column.
+// When anything comes before : main the ROM starts with a jump to it.
+//
+pub fn assemble(path: &str, text: &str) -> Result<Program, String> {
+    let mut tokens = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or("");
+        for word in line.split_whitespace() {
+            // words are slices of line, so their offset is the column
+            let column = word.as_ptr() as usize - line.as_ptr() as usize + 1;
+            tokens.push(Token {
+                text: word,
+                line: number + 1,
+                column,
+            });
+        }
+    }
+    let mut assembler = Assembler {
+        tokens,
+        next: 0,
+        rom: Vec::new(),
+        labels: HashMap::new(),
+        constants: HashMap::new(),
+        aliases: HashMap::new(),
+        fixups: Vec::new(),
+        blocks: Vec::new(),
+        line: 0,
+        lines: BTreeMap::new(),
+    };
+    let error = |e: Error| format!("{}:{}:{}: {}", path, e.line, e.column, e.message);
+    // like Octo, running starts at : main, so jump there when it isn't first
+    let main = assembler.tokens.windows(2).position(|w| w[0].text == ":" && w[1].text == "main");
+    if let Some(at) = main.filter(|at| *at > 0) {
+        let token = assembler.tokens[at + 1];
+        assembler.line = token.line;
+        assembler.fixups.push((0, token));
+        assembler.emit(0x1000);
+    }
+    while assembler.next < assembler.tokens.len() {
+        let token = assembler.tokens[assembler.next];
+        assembler.statement().map_err(error)?;
+        // checked after every statement so addresses never run past 0xFFFF
+        if assembler.rom.len() > 0x1000 - 0x200 {
+            return Err(error(Error {
+                line: token.line,
+                column: token.column,
+                message: String::from("program is too big for memory"),
+            }));
+        }
+    }
+    assembler.finish().map_err(error)?;
+    // the first name in the alphabet when an address has more than one
+    let mut labels = BTreeMap::new();
+    for (name, address) in &assembler.labels {
+        let label = labels.entry(*address).or_insert_with(|| name.to_string());
+        if name < &label.as_str() {
+            *label = name.to_string();
+        }
+    }
+    Ok(Program {
+        rom: assembler.rom,
+        labels,
+        lines: assembler.lines,
+    })
+}
+
+// The ROM with where its labels ended up and the source line each
+// instruction came from, for debuggers to name addresses
+pub struct Program {
+    pub rom: Vec<u8>,
+    pub labels: BTreeMap<u16, String>,
+    pub lines: BTreeMap<u16, usize>,
+}
+
+#[derive(Clone, Copy)]
+struct Token<'a> {
+    text: &'a str,
+    line: usize,
+    column: usize,
+}
+
+struct Error {
+    line: usize,
+    column: usize,
+    message: String,
+}
+
+// Open if, loop and while blocks, with the jumps waiting for their target
+enum Block {
+    If { jump: usize },
+    Else { jump: usize },
+    Loop { start: u16, exits: Vec<usize> },
+}
+
+#[derive(Clone, Copy)]
+enum Condition {
+    Equal(u8, u8),
+    NotEqual(u8, u8),
+    EqualV(u8, u8),
+    NotEqualV(u8, u8),
+    Key(u8),
+    NotKey(u8),
+}
+
+impl Condition {
+    fn not(self) -> Condition {
+        match self {
+            Condition::Equal(x, n) => Condition::NotEqual(x, n),
+            Condition::NotEqual(x, n) => Condition::Equal(x, n),
+            Condition::EqualV(x, y) => Condition::NotEqualV(x, y),
+            Condition::NotEqualV(x, y) => Condition::EqualV(x, y),
+            Condition::Key(x) => Condition::NotKey(x),
+            Condition::NotKey(x) => Condition::Key(x),
+        }
+    }
+
+    // The skip that lets the next instruction run only when this holds
+    fn skip(self) -> u16 {
+        let x = |v: u8| (v as u16) << 8;
+        let y = |v: u8| (v as u16) << 4;
+        match self {
+            Condition::Equal(vx, n) => 0x4000 | x(vx) | n as u16,
+            Condition::NotEqual(vx, n) => 0x3000 | x(vx) | n as u16,
+            Condition::EqualV(vx, vy) => 0x9000 | x(vx) | y(vy),
+            Condition::NotEqualV(vx, vy) => 0x5000 | x(vx) | y(vy),
+            Condition::Key(vx) => 0xE0A1 | x(vx),
+            Condition::NotKey(vx) => 0xE09E | x(vx),
+        }
+    }
+}
+
+struct Assembler<'a> {
+    tokens: Vec<Token<'a>>,
+    next: usize,
+    rom: Vec<u8>,
+    labels: HashMap<&'a str, u16>,
+    constants: HashMap<&'a str, i64>,
+    aliases: HashMap<&'a str, u8>,
+    // 12 bit addresses to fill in once every label is known
+    fixups: Vec<(usize, Token<'a>)>,
+    blocks: Vec<(Block, Token<'a>)>,
+    // the line of the statement being assembled, and of each instruction
+    line: usize,
+    lines: BTreeMap<u16, usize>,
+}
+
+fn fail<T>(token: Token, message: String) -> Result<T, Error> {
+    Err(Error {
+        line: token.line,
+        column: token.column,
+        message,
+    })
+}
+
+impl<'a> Assembler<'a> {
+    fn here(&self) -> u16 {
+        0x200 + self.rom.len() as u16
+    }
+
+    fn token(&mut self) -> Result<Token<'a>, Error> {
+        match self.tokens.get(self.next) {
+            Some(token) => {
+                self.next += 1;
+                Ok(*token)
+            }
+            None => {
+                let last = self.tokens.last().map_or((1, 1), |t| (t.line, t.column + t.text.len()));
+                Err(Error {
+                    line: last.0,
+                    column: last.1,
+                    message: String::from("unexpected end of source"),
+                })
+            }
+        }
+    }
+
+    fn peek(&self) -> Option<&'a str> {
+        self.tokens.get(self.next).map(|t| t.text)
+    }
+
+    fn expect(&mut self, text: &str) -> Result<(), Error> {
+        let token = self.token()?;
+        if token.text != text {
+            return fail(token, format!("expected {}, found {}", text, token.text));
+        }
+        Ok(())
+    }
+
+    fn emit(&mut self, word: u16) {
+        self.lines.insert(self.here(), self.line);
+        self.rom.extend_from_slice(&word.to_be_bytes());
+    }
+
+    fn register_of(&self, text: &str) -> Option<u8> {
+        if let Some(v) = self.aliases.get(text) {
+            return Some(*v);
+        }
+        match text.as_bytes() {
+            [b'v' | b'V', digit] => (*digit as char).to_digit(16).map(|d| d as u8),
+            _ => None,
+        }
+    }
+
+    fn register(&mut self) -> Result<u8, Error> {
+        let token = self.token()?;
+        match self.register_of(token.text) {
+            Some(v) => Ok(v),
+            None => fail(token, format!("expected a register v0-vF, found {}", token.text)),
+        }
+    }
+
+    fn number_of(&self, text: &str) -> Option<i64> {
+        if let Some(value) = self.constants.get(text) {
+            return Some(*value);
+        }
+        let (negative, digits) = match text.strip_prefix('-') {
+            Some(rest) => (true, rest),
+            None => (false, text),
+        };
+        let value = if let Some(hex) = digits.strip_prefix("0x") {
+            i64::from_str_radix(hex, 16).ok()?
+        } else if let Some(binary) = digits.strip_prefix("0b") {
+            i64::from_str_radix(binary, 2).ok()?
+        } else {
+            digits.parse().ok()?
+        };
+        Some(if negative { -value } else { value })
+    }
+
+    // A number that has to fit in bits, negative ones wrap like Octo's
+    fn number(&mut self, bits: u32) -> Result<u16, Error> {
+        let token = self.token()?;
+        let value = match self.number_of(token.text) {
+            Some(value) => value,
+            None => return fail(token, format!("expected a number, found {}", token.text)),
+        };
+        let limit = 1i64 << bits;
+        if value >= limit || value < -(limit / 2) {
+            return fail(token, format!("{} doesn't fit in {} bits", value, bits));
+        }
+        Ok((value & (limit - 1)) as u16)
+    }
+
+    // An address, a label can be used before it is defined
+    fn address(&mut self) -> Result<u16, Error> {
+        let token = self.token()?;
+        if let Some(value) = self.number_of(token.text) {
+            if !(0..0x1000).contains(&value) {
+                return fail(token, format!("address {} is outside memory", value));
+            }
+            return Ok(value as u16);
+        }
+        if let Some(address) = self.labels.get(token.text) {
+            return Ok(*address);
+        }
+        if !is_name(token.text) {
+            return fail(token, format!("expected an address or label, found {}", token.text));
+        }
+        self.fixups.push((self.rom.len(), token));
+        Ok(0)
+    }
+
+    fn name(&mut self) -> Result<Token<'a>, Error> {
+        let token = self.token()?;
+        if !is_name(token.text) || self.register_of(token.text).is_some() {
+            return fail(token, format!("{} can't be used as a name", token.text));
+        }
+        Ok(token)
+    }
+
+    fn condition(&mut self) -> Result<Condition, Error> {
+        let x = self.register()?;
+        let op = self.token()?;
+        match op.text {
+            "key" => return Ok(Condition::Key(x)),
+            "-key" => return Ok(Condition::NotKey(x)),
+            "==" | "!=" => {}
+            _ => return fail(op, format!("expected ==, !=, key or -key, found {}", op.text)),
+        }
+        let condition = match self.peek().and_then(|t| self.register_of(t)) {
+            Some(y) => {
+                self.next += 1;
+                Condition::EqualV(x, y)
+            }
+            None => Condition::Equal(x, self.number(8)? as u8),
+        };
+        Ok(if op.text == "!=" { condition.not() } else { condition })
+    }
+
+    // Leaves a jump whose target comes later, returns where it is
+    fn forward_jump(&mut self) -> usize {
+        let at = self.rom.len();
+        self.emit(0x1000);
+        at
+    }
+
+    fn land(&mut self, jump: usize) {
+        let word = 0x1000 | self.here();
+        self.rom[jump..jump + 2].copy_from_slice(&word.to_be_bytes());
+    }
+
+    fn statement(&mut self) -> Result<(), Error> {
+        let token = self.token()?;
+        self.line = token.line;
+        let x = |v: u8| (v as u16) << 8;
+        let y = |v: u8| (v as u16) << 4;
+        match token.text {
+            ":" => {
+                let name = self.name()?;
+                if self.labels.insert(name.text, self.here()).is_some() {
+                    return fail(name, format!("label {} is already defined", name.text));
+                }
+            }
+            ":const" => {
+                let name = self.name()?;
+                let value = self.token()?;
+                match self.number_of(value.text).or_else(|| self.labels.get(value.text).map(|a| *a as i64)) {
+                    Some(v) => self.constants.insert(name.text, v),
+                    None => return fail(value, format!("expected a number, found {}", value.text)),
+                };
+            }
+            ":alias" => {
+                let name = self.name()?;
+                let v = self.register()?;
+                self.aliases.insert(name.text, v);
+            }
+            ":call" => {
+                let nnn = self.address()?;
+                self.emit(0x2000 | nnn);
+            }
+            "return" | ";" => self.emit(0x00EE),
+            "clear" => self.emit(0x00E0),
+            "exit" => self.emit(0x00FD),
+            "audio" => self.emit(0xF002),
+            "lores" => self.emit(0x00FE),
+            "hires" => self.emit(0x00FF),
+            "scroll-left" => self.emit(0x00FC),
+            "scroll-right" => self.emit(0x00FB),
+            "scroll-down" => {
+                let n = self.number(4)?;
+                self.emit(0x00C0 | n);
+            }
+            "jump" => {
+                let nnn = self.address()?;
+                self.emit(0x1000 | nnn);
+            }
+            "jump0" => {
+                let nnn = self.address()?;
+                self.emit(0xB000 | nnn);
+            }
+            "sprite" => {
+                let (vx, vy) = (self.register()?, self.register()?);
+                let n = self.number(4)?;
+                self.emit(0xD000 | x(vx) | y(vy) | n);
+            }
+            "bcd" => {
+                let vx = self.register()?;
+                self.emit(0xF033 | x(vx));
+            }
+            "save" => {
+                let vx = self.register()?;
+                self.emit(0xF055 | x(vx));
+            }
+            "load" => {
+                let vx = self.register()?;
+                self.emit(0xF065 | x(vx));
+            }
+            "saveflags" => {
+                let vx = self.register()?;
+                self.emit(0xF075 | x(vx));
+            }
+            "loadflags" => {
+                let vx = self.register()?;
+                self.emit(0xF085 | x(vx));
+            }
+            "delay" | "buzzer" | "pitch" => {
+                self.expect(":=")?;
+                let vx = self.register()?;
+                let opcode = match token.text {
+                    "delay" => 0xF015,
+                    "buzzer" => 0xF018,
+                    _ => 0xF03A,
+                };
+                self.emit(opcode | x(vx));
+            }
+            "i" => {
+                let op = self.token()?;
+                match (op.text, self.peek()) {
+                    (":=", Some("hex")) | (":=", Some("bighex")) => {
+                        let big = self.token()?.text == "bighex";
+                        let vx = self.register()?;
+                        self.emit(if big { 0xF030 } else { 0xF029 } | x(vx));
+                    }
+                    (":=", _) => {
+                        let nnn = self.address()?;
+                        self.emit(0xA000 | nnn);
+                    }
+                    ("+=", _) => {
+                        let vx = self.register()?;
+                        self.emit(0xF01E | x(vx));
+                    }
+                    _ => return fail(op, format!("expected := or +=, found {}", op.text)),
+                }
+            }
+            "if" => {
+                let condition = self.condition()?;
+                let then = self.token()?;
+                match then.text {
+                    "then" => {
+                        self.emit(condition.skip());
+                        if self.next >= self.tokens.len() {
+                            return fail(then, String::from("then needs an instruction after it"));
+                        }
+                        self.statement()?;
+                    }
+                    "begin" => {
+                        // skip the jump past the block when the condition holds
+                        self.emit(condition.not().skip());
+                        let jump = self.forward_jump();
+                        self.blocks.push((Block::If { jump }, token));
+                    }
+                    _ => return fail(then, format!("expected then or begin, found {}", then.text)),
+                }
+            }
+            "else" => match self.blocks.pop() {
+                Some((Block::If { jump }, start)) => {
+                    let past = self.forward_jump();
+                    self.land(jump);
+                    self.blocks.push((Block::Else { jump: past }, start));
+                }
+                _ => return fail(token, String::from("else without if ... begin")),
+            },
+            "end" => match self.blocks.pop() {
+                Some((Block::If { jump } | Block::Else { jump }, _)) => self.land(jump),
+                _ => return fail(token, String::from("end without if ... begin")),
+            },
+            "loop" => {
+                let start = self.here();
+                self.blocks.push((Block::Loop { start, exits: Vec::new() }, token));
+            }
+            "while" => {
+                // the jump out runs once the condition stops holding
+                let condition = self.condition()?;
+                self.emit(condition.not().skip());
+                let jump = self.forward_jump();
+                match self.blocks.iter_mut().rev().find(|(b, _)| matches!(b, Block::Loop { .. })) {
+                    Some((Block::Loop { exits, .. }, _)) => exits.push(jump),
+                    _ => return fail(token, String::from("while outside of loop ... again")),
+                }
+            }
+            "again" => match self.blocks.pop() {
+                Some((Block::Loop { start, exits }, _)) => {
+                    self.emit(0x1000 | start);
+                    for jump in exits {
+                        self.land(jump);
+                    }
+                }
+                _ => return fail(token, String::from("again without loop")),
+            },
+            text => {
+                if let Some(vx) = self.register_of(text) {
+                    return self.assignment(vx);
+                }
+                if let Some(value) = self.number_of(text) {
+                    if !(-128..256).contains(&value) {
+                        return fail(token, format!("data byte {} doesn't fit in 8 bits", value));
+                    }
+                    self.rom.push(value as u8);
+                    return Ok(());
+                }
+                // a bare name calls the label of that name
+                if !is_name(text) {
+                    return fail(token, format!("don't know {}", text));
+                }
+                self.next -= 1;
+                let nnn = self.address()?;
+                self.emit(0x2000 | nnn);
+            }
+        }
+        Ok(())
+    }
+
+    // vx := ..., vx += ... and the other register operators
+    fn assignment(&mut self, vx: u8) -> Result<(), Error> {
+        let x = (vx as u16) << 8;
+        let op = self.token()?;
+        let source = self.peek();
+        if let Some(vy) = source.and_then(|t| self.register_of(t)) {
+            self.next += 1;
+            let n = match op.text {
+                ":=" => 0,
+                "|=" => 1,
+                "&=" => 2,
+                "^=" => 3,
+                "+=" => 4,
+                "-=" => 5,
+                ">>=" => 6,
+                "=-" => 7,
+                "<<=" => 0xE,
+                _ => return fail(op, format!("{} doesn't work between registers", op.text)),
+            };
+            self.emit(0x8000 | x | (vy as u16) << 4 | n);
+            return Ok(());
+        }
+        match (op.text, source) {
+            (":=", Some("random")) => {
+                self.next += 1;
+                let nn = self.number(8)?;
+                self.emit(0xC000 | x | nn);
+            }
+            (":=", Some("delay")) => {
+                self.next += 1;
+                self.emit(0xF007 | x);
+            }
+            (":=", Some("key")) => {
+                self.next += 1;
+                self.emit(0xF00A | x);
+            }
+            (":=", _) => {
+                let nn = self.number(8)?;
+                self.emit(0x6000 | x | nn);
+            }
+            ("+=", _) => {
+                let nn = self.number(8)?;
+                self.emit(0x7000 | x | nn);
+            }
+            ("-=", _) => {
+                let nn = self.number(8)?;
+                self.emit(0x7000 | x | (0x100 - nn) & 0xFF);
+            }
+            _ => return fail(op, format!("expected an operator like := or +=, found {}", op.text)),
+        }
+        Ok(())
+    }
+
+    // Fills in forward references and checks every block was closed
+    fn finish(&mut self) -> Result<(), Error> {
+        if let Some((_, token)) = self.blocks.last() {
+            return fail(*token, format!("{} is never closed", token.text));
+        }
+        for (at, token) in &self.fixups {
+            let address = match self.labels.get(token.text) {
+                Some(address) => *address,
+                None => return fail(*token, format!("no label named {}", token.text)),
+            };
+            self.rom[*at] |= (address >> 8) as u8;
+            self.rom[*at + 1] = address as u8;
+        }
+        Ok(())
+    }
+}
+
+fn is_name(text: &str) -> bool {
+    let mut chars = text.chars();
+    chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn words(text: &str) -> Result<Vec<u16>, String> {
+        let program = assemble("test.8o", text)?;
+        Ok(program.rom.chunks(2).map(|w| u16::from_be_bytes([w[0], w[1]])).collect())
+    }
+
+    #[test]
+    fn keys_rom() {
+        let text = std::fs::read_to_string("rom/keys.8o").unwrap();
+        let program = assemble("rom/keys.8o", &text).unwrap();
+        let expected = [
+            0x61, 0x1E, 0x62, 0x0D, 0xF0, 0x0A, 0x00, 0xE0, 0xF0, 0x29, 0xD1, 0x25, 0x12, 0x04,
+        ];
+        assert_eq!(program.rom, expected);
+        assert_eq!(program.labels.get(&0x200).map(|l| l.as_str()), Some("main"));
+        assert_eq!(program.lines.get(&0x204), Some(&6));
+    }
+
+    #[test]
+    fn main_comes_first() {
+        let sub = ": draw sprite v0 v1 5 return\n: main v0 := 1 draw jump main";
+        assert_eq!(words(sub), Ok(vec![0x1206, 0xD015, 0x00EE, 0x6001, 0x2202, 0x1206]));
+        let program = assemble("test.8o", sub).unwrap();
+        assert_eq!(program.lines.get(&0x200), Some(&2));
+        assert_eq!(program.labels.get(&0x206).map(|l| l.as_str()), Some("main"));
+        // no jump when main is already at 0x200, or there is no main
+        assert_eq!(words(": main draw\n: draw return"), Ok(vec![0x2202, 0x00EE]));
+        assert_eq!(words("v0 := 1 : loop jump loop"), Ok(vec![0x6001, 0x1202]));
+    }
+
+    #[test]
+    fn blocks() {
+        let text = "loop if v0 == 5 begin v1 += 1 else v1 := 0 end while v2 != v3 again";
+        let expected = [0x3005, 0x1208, 0x7101, 0x120A, 0x6100, 0x9230, 0x1210, 0x1200];
+        assert_eq!(words(text), Ok(expected.to_vec()));
+        assert_eq!(words("if v4 -key then v0 -= 1"), Ok(vec![0xE49E, 0x70FF]));
+    }
+
+    #[test]
+    fn errors_point_at_the_token() {
+        let error = |text| words(text).unwrap_err();
+        assert_eq!(error("v0 := 1\n  v1 += 300"), "test.8o:2:9: 300 doesn't fit in 8 bits");
+        assert_eq!(error("v0 := 1\n\tjump nowhere"), "test.8o:2:7: no label named nowhere");
+        assert_eq!(error(": main\n  loop v0 += 1"), "test.8o:2:3: loop is never closed");
+        assert_eq!(error("v0 := 1 # a comment\n  + 2"), "test.8o:2:3: don't know +");
+        assert_eq!(error(": a\n: a"), "test.8o:2:3: label a is already defined");
+        assert_eq!(error("i := 0x1000"), "test.8o:1:6: address 4096 is outside memory");
+        assert_eq!(error("v0 :="), "test.8o:1:6: unexpected end of source");
+    }
+
+    #[test]
+    fn too_big() {
+        let fits = "0 ".repeat(0x1000 - 0x200);
+        assert_eq!(assemble("big.8o", &fits).map(|p| p.rom.len()), Ok(0xE00));
+        let error = assemble("big.8o", &(fits + "\n0")).map(|p| p.rom.len());
+        assert_eq!(error, Err(String::from("big.8o:2:1: program is too big for memory")));
+        // far past 64K, where addresses used to wrap around
+        let huge = "clear ".repeat(0x9000) + ": end jump end";
+        let error = assemble("huge.8o", &huge).map(|p| p.rom.len());
+        assert_eq!(error, Err(String::from("huge.8o:1:10753: program is too big for memory")));
+    }
+}
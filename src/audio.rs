@@ -0,0 +1,434 @@
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::process::{Child, ChildStdin, Command, Stdio};
+
+use crate::Audio;
+
+// Signed 16 bit mono samples at RATE, 735 to a 60 Hz frame
+pub const RATE: u64 = 44100;
+const TONE: u64 = 440;
+const VOLUME: i16 = 6000;
+
+// Where a beeper's samples go
+pub trait Sink {
+    fn write(&mut self, samples: &[i16]) -> std::io::Result<()>;
+}
+
+//
+// The beeper as a square wave, one frame of samples every frame, silence
+// while the tone is off. Once an XO-CHIP program loads a pattern the tone
+// is the pattern's bits instead, high for 1 and low for 0. Either keeps
+// its phase across frames so the tone doesn't click at frame edges.
+//
+pub struct Square<K> {
+    // dropped when it fails, the machine runs on without sound
+    sink: Option<K>,
+    failed: Option<String>,
+    on: bool,
+    samples: u64,
+    frames: u64,
+    // an XO-CHIP pattern and the bits it plays per sample, instead of TONE
+    pattern: Option<([u8; 16], f64)>,
+    // how far into the pattern, in bits
+    bit: f64,
+}
+
+impl<K: Sink> Square<K> {
+    pub fn new(sink: K) -> Self {
+        Square {
+            sink: Some(sink),
+            failed: None,
+            on: false,
+            samples: 0,
+            frames: 0,
+            pattern: None,
+            bit: 0.0,
+        }
+    }
+}
+
+impl<K: Sink> Audio for Square<K> {
+    fn start(&mut self) {
+        self.on = true;
+    }
+    fn stop(&mut self) {
+        self.on = false;
+    }
+    fn frame(&mut self) {
+        self.frames += 1;
+        let end = self.frames * RATE / 60;
+        let wave: Vec<i16> = match (self.on, self.pattern) {
+            (false, _) => vec![0; (end - self.samples) as usize],
+            (true, None) => (self.samples..end)
+                .map(|n| if (n * TONE * 2 / RATE).is_multiple_of(2) { VOLUME } else { -VOLUME })
+                .collect(),
+            (true, Some((pattern, step))) => (self.samples..end)
+                .map(|_| {
+                    let bit = self.bit as usize;
+                    self.bit = (self.bit + step) % 128.0;
+                    if pattern[bit / 8] & 0x80 >> (bit % 8) != 0 { VOLUME } else { -VOLUME }
+                })
+                .collect(),
+        };
+        self.samples = end;
+        if let Some(Err(e)) = self.sink.as_mut().map(|sink| sink.write(&wave)) {
+            self.sink = None;
+            self.failed = Some(format!("sound output failed: {}", e));
+        }
+    }
+    fn warning(&mut self) -> Option<String> {
+        self.failed.take()
+    }
+    fn pattern(&mut self, pattern: Option<&[u8; 16]>, pitch: u8) {
+        let rate = 4000.0 * 2f64.powf((pitch as f64 - 64.0) / 48.0);
+        self.pattern = pattern.map(|pattern| (*pattern, rate / RATE as f64));
+    }
+}
+
+// Plays through first until it gives out, then through second. The
+// beeper through aplay falls back to the bell this way.
+pub struct Fallback<A, B> {
+    first: Option<A>,
+    second: B,
+    on: bool,
+    warning: Option<String>,
+}
+
+impl<A: Audio, B: Audio> Fallback<A, B> {
+    pub fn new(first: A, second: B) -> Self {
+        Fallback {
+            first: Some(first),
+            second,
+            on: false,
+            warning: None,
+        }
+    }
+}
+
+impl<A: Audio, B: Audio> Audio for Fallback<A, B> {
+    fn start(&mut self) {
+        self.on = true;
+        match &mut self.first {
+            Some(first) => first.start(),
+            None => self.second.start(),
+        }
+    }
+    fn stop(&mut self) {
+        self.on = false;
+        match &mut self.first {
+            Some(first) => first.stop(),
+            None => self.second.stop(),
+        }
+    }
+    fn frame(&mut self) {
+        let first = match &mut self.first {
+            Some(first) => first,
+            None => return self.second.frame(),
+        };
+        first.frame();
+        if let Some(warning) = first.warning() {
+            self.first = None;
+            self.warning = Some(format!("{}, using the terminal bell", warning));
+            // a tone already sounding carries on as the bell
+            if self.on {
+                self.second.start();
+            }
+        }
+    }
+    fn warning(&mut self) -> Option<String> {
+        self.warning.take()
+    }
+    fn pattern(&mut self, pattern: Option<&[u8; 16]>, pitch: u8) {
+        if let Some(first) = &mut self.first {
+            first.pattern(pattern, pitch);
+        }
+        self.second.pattern(pattern, pitch);
+    }
+}
+
+// Plays samples through a command reading raw audio on stdin, aplay by
+// default
+pub struct Pipe {
+    child: Child,
+    stdin: Option<ChildStdin>,
+}
+
+impl Pipe {
+    pub fn aplay() -> std::io::Result<Self> {
+        let rate = RATE.to_string();
+        let args = ["-q", "-t", "raw", "-f", "S16_LE", "-c", "1", "-r", &rate, "-B", "100000"];
+        let mut child = Command::new("aplay")
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()?;
+        let stdin = child.stdin.take();
+        Ok(Pipe { child, stdin })
+    }
+}
+
+impl Sink for Pipe {
+    fn write(&mut self, samples: &[i16]) -> std::io::Result<()> {
+        // aplay quits straight away without a sound device, before the pipe
+        // fills up and a write would fail
+        if let Ok(Some(status)) = self.child.try_wait() {
+            self.stdin = None;
+            return Err(std::io::Error::other(format!("aplay stopped, {}", status)));
+        }
+        let bytes: Vec<u8> = samples.iter().flat_map(|s| s.to_le_bytes()).collect();
+        match &mut self.stdin {
+            Some(stdin) => stdin.write_all(&bytes),
+            None => Err(std::io::ErrorKind::BrokenPipe.into()),
+        }
+    }
+}
+
+impl Drop for Pipe {
+    fn drop(&mut self) {
+        // closing stdin lets the player finish what it has
+        self.stdin = None;
+        let _ = self.child.wait();
+    }
+}
+
+// A 16 bit mono WAV file. The header's sizes are brought up to date once
+// a second of audio, so a run that is killed still leaves a playable file.
+pub struct Wav {
+    out: BufWriter<File>,
+    samples: u64,
+}
+
+impl Wav {
+    pub fn create(path: &str) -> std::io::Result<Self> {
+        let mut wav = Wav {
+            out: BufWriter::new(File::create(path)?),
+            samples: 0,
+        };
+        wav.header()?;
+        Ok(wav)
+    }
+
+    fn header(&mut self) -> std::io::Result<()> {
+        let data = (self.samples * 2) as u32;
+        let out = &mut self.out;
+        out.seek(SeekFrom::Start(0))?;
+        out.write_all(b"RIFF")?;
+        out.write_all(&(36 + data).to_le_bytes())?;
+        out.write_all(b"WAVEfmt ")?;
+        out.write_all(&16u32.to_le_bytes())?;
+        // PCM, one channel
+        out.write_all(&1u16.to_le_bytes())?;
+        out.write_all(&1u16.to_le_bytes())?;
+        out.write_all(&(RATE as u32).to_le_bytes())?;
+        out.write_all(&(RATE as u32 * 2).to_le_bytes())?;
+        out.write_all(&2u16.to_le_bytes())?;
+        out.write_all(&16u16.to_le_bytes())?;
+        out.write_all(b"data")?;
+        out.write_all(&data.to_le_bytes())?;
+        out.seek(SeekFrom::End(0))?;
+        out.flush()
+    }
+}
+
+impl Sink for Wav {
+    fn write(&mut self, samples: &[i16]) -> std::io::Result<()> {
+        let before = self.samples / RATE;
+        for sample in samples {
+            self.out.write_all(&sample.to_le_bytes())?;
+        }
+        self.samples += samples.len() as u64;
+        if self.samples / RATE != before {
+            self.header()?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Wav {
+    fn drop(&mut self) {
+        let _ = self.header();
+    }
+}
+
+// Sends the beeper to another backend and to a WAV file as well. The file
+// gets exactly one frame of samples per emulated frame, however fast the
+// machine actually ran.
+pub struct WavRecorder<A> {
+    inner: A,
+    wave: Square<Wav>,
+}
+
+impl<A: Audio> WavRecorder<A> {
+    pub fn new(inner: A, wav: Wav) -> Self {
+        WavRecorder {
+            inner,
+            wave: Square::new(wav),
+        }
+    }
+}
+
+impl<A: Audio> Audio for WavRecorder<A> {
+    fn start(&mut self) {
+        self.inner.start();
+        self.wave.start();
+    }
+    fn stop(&mut self) {
+        self.inner.stop();
+        self.wave.stop();
+    }
+    fn frame(&mut self) {
+        self.inner.frame();
+        self.wave.frame();
+    }
+    fn warning(&mut self) -> Option<String> {
+        self.inner.warning().or_else(|| self.wave.warning())
+    }
+    fn pattern(&mut self, pattern: Option<&[u8; 16]>, pitch: u8) {
+        self.inner.pattern(pattern, pitch);
+        self.wave.pattern(pattern, pitch);
+    }
+}
+
+// Rings the terminal bell when the tone starts, for terminals without a
+// sound device
+pub struct Bell;
+
+impl Audio for Bell {
+    fn start(&mut self) {
+        print!("\x07");
+        let _ = std::io::stdout().flush();
+    }
+    fn stop(&mut self) {}
+}
+
+// No sound at all, for headless runs
+pub struct Silent;
+
+impl Audio for Silent {
+    fn start(&mut self) {}
+    fn stop(&mut self) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Takes this many frames of samples, then fails
+    struct Broken(usize);
+
+    impl Sink for Broken {
+        fn write(&mut self, _samples: &[i16]) -> std::io::Result<()> {
+            match self.0.checked_sub(1) {
+                Some(left) => {
+                    self.0 = left;
+                    Ok(())
+                }
+                None => Err(std::io::ErrorKind::BrokenPipe.into()),
+            }
+        }
+    }
+
+    impl Sink for Vec<i16> {
+        fn write(&mut self, samples: &[i16]) -> std::io::Result<()> {
+            self.extend_from_slice(samples);
+            Ok(())
+        }
+    }
+
+    // Sign changes, twice the frequency of a square wave
+    fn edges(samples: &[i16]) -> usize {
+        samples.windows(2).filter(|w| (w[0] > 0) != (w[1] > 0)).count()
+    }
+
+    // Frames of a square wave, on for the frames in tone
+    fn render(square: &mut Square<Vec<i16>>, frames: u64, tone: std::ops::Range<u64>) -> Vec<i16> {
+        for frame in 0..frames {
+            if frame == tone.start {
+                square.start();
+            }
+            if frame == tone.end {
+                square.stop();
+            }
+            square.frame();
+        }
+        square.sink.take().unwrap()
+    }
+
+    #[test]
+    fn beeper_window() {
+        let samples = render(&mut Square::new(Vec::new()), 120, 30..90);
+        // a second at 60 frames a second, 735 samples each
+        assert_eq!(samples.len(), 2 * RATE as usize);
+        let (before, rest) = samples.split_at(30 * 735);
+        let (tone, after) = rest.split_at(60 * 735);
+        assert!(before.iter().all(|s| *s == 0));
+        assert!(after.iter().all(|s| *s == 0));
+        assert!(tone.iter().all(|s| s.abs() == VOLUME));
+        // 440 Hz for a second, give or take where the edges fall
+        assert!((879..=881).contains(&edges(tone)), "{}", edges(tone));
+    }
+
+    #[test]
+    fn pattern_at_pitch() {
+        // eight bits high, eight low, a 16 bit period
+        let mut pattern = [0; 16];
+        for byte in pattern.iter_mut().step_by(2) {
+            *byte = 0xFF;
+        }
+        for (pitch, hz) in [(64, 250), (112, 500), (16, 125)] {
+            let mut square = Square::new(Vec::new());
+            square.pattern(Some(&pattern), pitch);
+            let samples = render(&mut square, 60, 0..60);
+            assert_eq!(samples.len(), RATE as usize);
+            assert_eq!(samples[0], VOLUME);
+            let edges = edges(&samples);
+            assert!((2 * hz - 1..=2 * hz + 1).contains(&edges), "pitch {}: {} edges", pitch, edges);
+        }
+    }
+
+    #[test]
+    fn pattern_is_played_bit_by_bit() {
+        let mut pattern = [0; 16];
+        pattern[0] = 0b1010_0000;
+        let mut square = Square::new(Vec::new());
+        // 4000 * 2^3 bits a second is 32000, about 0.73 bits a sample
+        square.pattern(Some(&pattern), 64 + 3 * 48);
+        let samples = render(&mut square, 1, 0..1);
+        let bits: Vec<bool> = samples[..6].iter().map(|s| *s > 0).collect();
+        // bits 0, 0, 1, 2, 2 and 3 of the pattern
+        assert_eq!(bits, [true, true, false, true, true, false]);
+    }
+
+    // Counts starts and stops
+    #[derive(Default)]
+    struct Count(usize, usize);
+
+    impl Audio for Count {
+        fn start(&mut self) {
+            self.0 += 1;
+        }
+        fn stop(&mut self) {
+            self.1 += 1;
+        }
+    }
+
+    #[test]
+    fn fallback_warns_once() {
+        let mut audio = Fallback::new(Square::new(Broken(2)), Count::default());
+        audio.start();
+        for _ in 0..2 {
+            audio.frame();
+            assert_eq!(audio.warning(), None);
+        }
+        audio.frame();
+        assert_eq!(audio.warning(), Some(String::from("sound output failed: broken pipe, using the terminal bell")));
+        // the tone that was on carries on through the fallback
+        assert_eq!(audio.second.0, 1);
+        audio.stop();
+        audio.start();
+        audio.frame();
+        assert_eq!(audio.warning(), None);
+        assert_eq!((audio.second.0, audio.second.1), (2, 1));
+    }
+}
@@ -0,0 +1,206 @@
+use std::net::{SocketAddr, TcpListener, ToSocketAddrs};
+
+use crate::Chip8;
+
+//
+// What --debug, --gdb and --dap share: names for addresses, the calls on
+// the stack, hex numbers and setting registers or memory. And base64, which
+// DAP's memory requests and kitty graphics both send, and TCP ports that
+// only this machine can reach, for them and remote input.
+//
+
+// The label at or before address and how far past it, empty without labels
+pub fn symbol(chip: &Chip8, address: u16) -> String {
+    match chip.symbols.range(..=address).next_back() {
+        Some((at, name)) if *at == address => name.clone(),
+        Some((at, name)) => format!("{}+{:X}", name, address - at),
+        None => String::new(),
+    }
+}
+
+// The pc, then each call still waiting for its return
+pub fn calls(chip: &Chip8) -> impl Iterator<Item = u16> + '_ {
+    std::iter::once(chip.pc).chain(chip.stack[..chip.sp as usize].iter().rev().copied())
+}
+
+// Hex, with or without 0x
+pub fn number(text: &str) -> Result<usize, String> {
+    let digits = text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")).unwrap_or(text);
+    usize::from_str_radix(digits, 16).map_err(|_| format!("{} is not a hex number", text))
+}
+
+// An address in memory, in hex
+pub fn memory_address(chip: &Chip8, text: &str) -> Result<u16, String> {
+    match number(text)? {
+        address if address < chip.memory.len() => Ok(address as u16),
+        _ => Err(format!("{} is past the end of memory", text)),
+    }
+}
+
+// Sets the register target names, or memory from the address it is. Values
+// too big for where they go are refused rather than cut short.
+pub fn set(chip: &mut Chip8, target: &str, values: &[usize]) -> Result<(), String> {
+    let value = |most: usize| match values {
+        [value] if *value <= most => Ok(*value),
+        [_] => Err(format!("{} goes up to {:X}", target, most)),
+        _ => Err(format!("set {} takes one value", target)),
+    };
+    let register = target.to_ascii_uppercase();
+    match register.as_str() {
+        "I" => chip.I = value(0xFFFF)? as u16,
+        // the instruction at pc is two bytes, both in memory
+        "PC" => chip.pc = value(chip.memory.len() - 2)? as u16,
+        "SP" => chip.sp = value(chip.stack.len())? as u16,
+        "DT" => chip.delay_timer = value(0xFF)? as u8,
+        "ST" => chip.sound_timer = value(0xFF)? as u8,
+        v if v.len() == 2 && v.starts_with('V') => {
+            let x = u8::from_str_radix(&v[1..], 16).map_err(|_| format!("no register {}", target))?;
+            chip.V[x as usize] = value(0xFF)? as u8;
+        }
+        _ => {
+            let start = number(target)?;
+            if start.checked_add(values.len()).is_none_or(|end| end > chip.memory.len()) {
+                return Err(String::from("past the end of memory"));
+            }
+            if let Some(big) = values.iter().find(|v| **v > 0xFF) {
+                return Err(format!("{:X} is more than a byte", big));
+            }
+            for (offset, byte) in values.iter().enumerate() {
+                chip.memory[start + offset] = *byte as u8;
+            }
+        }
+    }
+    Ok(())
+}
+
+// Whoever connects drives the machine, so other hosts are kept out
+pub fn listen_local(address: &str) -> std::io::Result<TcpListener> {
+    let addresses: Vec<SocketAddr> = address.to_socket_addrs()?.collect();
+    if let Some(far) = addresses.iter().find(|a| !a.ip().is_loopback()) {
+        let message = format!("{} is not a loopback address, use 127.0.0.1", far.ip());
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, message));
+    }
+    TcpListener::bind(&addresses[..])
+}
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+pub fn base64(data: &[u8]) -> String {
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+    for group in data.chunks(3) {
+        let b = [group[0], *group.get(1).unwrap_or(&0), *group.get(2).unwrap_or(&0)];
+        let n = (b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32;
+        out.push(BASE64[(n >> 18) as usize & 0x3F] as char);
+        out.push(BASE64[(n >> 12) as usize & 0x3F] as char);
+        out.push(if group.len() > 1 { BASE64[(n >> 6) as usize & 0x3F] as char } else { '=' });
+        out.push(if group.len() > 2 { BASE64[n as usize & 0x3F] as char } else { '=' });
+    }
+    out
+}
+
+// None for anything outside the alphabet
+pub fn unbase64(text: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::new();
+    let (mut n, mut bits) = (0u32, 0);
+    for c in text.bytes().filter(|c| *c != b'=') {
+        n = n << 6 | BASE64.iter().position(|b| *b == c)? as u32;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((n >> bits) as u8);
+        }
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::machine;
+
+    #[test]
+    fn set_registers_and_memory() {
+        let (mut chip, _) = machine(&[]);
+        set(&mut chip, "v3", &[0x2A]).unwrap();
+        set(&mut chip, "i", &[0x300]).unwrap();
+        set(&mut chip, "sp", &[16]).unwrap();
+        set(&mut chip, "300", &[1, 2, 0xFF]).unwrap();
+        assert_eq!((chip.V[3], chip.I, chip.sp), (0x2A, 0x300, 16));
+        assert_eq!(chip.memory[0x300..0x303], [1, 2, 0xFF]);
+        set(&mut chip, "ffe", &[1, 2]).unwrap();
+        assert_eq!(set(&mut chip, "vg", &[1]), Err(String::from("no register vg")));
+        assert_eq!(set(&mut chip, "v1", &[1, 2]), Err(String::from("set v1 takes one value")));
+        assert_eq!(set(&mut chip, "fff", &[1, 2]), Err(String::from("past the end of memory")));
+    }
+
+    #[test]
+    fn set_refuses_values_too_big() {
+        let (mut chip, _) = machine(&[]);
+        assert_eq!(set(&mut chip, "i", &[0x12345]), Err(String::from("i goes up to FFFF")));
+        assert_eq!(set(&mut chip, "v0", &[0x100]), Err(String::from("v0 goes up to FF")));
+        assert_eq!(set(&mut chip, "DT", &[0x100]), Err(String::from("DT goes up to FF")));
+        assert_eq!(set(&mut chip, "sp", &[17]), Err(String::from("sp goes up to 10")));
+        assert_eq!(set(&mut chip, "300", &[1, 0x102]), Err(String::from("102 is more than a byte")));
+        assert_eq!((chip.I, chip.V[0], chip.delay_timer, chip.sp), (0, 0, 0, 0));
+        assert_eq!(chip.memory[0x300], 0);
+        // pc stays where both bytes of an instruction can be read
+        assert_eq!(set(&mut chip, "pc", &[0xFFF]), Err(String::from("pc goes up to FFE")));
+        set(&mut chip, "pc", &[0xFFE]).unwrap();
+        chip.emulate_cycle();
+        set(&mut chip, "i", &[0xFFFF]).unwrap();
+        assert_eq!(chip.I, 0xFFFF);
+    }
+
+    #[test]
+    fn memory_addresses() {
+        let (chip, _) = machine(&[]);
+        assert_eq!(memory_address(&chip, "0x2A0"), Ok(0x2A0));
+        assert_eq!(memory_address(&chip, "fff"), Ok(0xFFF));
+        assert_eq!(memory_address(&chip, "10200"), Err(String::from("10200 is past the end of memory")));
+        assert_eq!(memory_address(&chip, "1000"), Err(String::from("1000 is past the end of memory")));
+    }
+
+    #[test]
+    fn set_past_the_end_of_addresses() {
+        let (mut chip, _) = machine(&[]);
+        let end = format!("{:x}", usize::MAX);
+        assert_eq!(set(&mut chip, &end, &[1]), Err(String::from("past the end of memory")));
+        assert_eq!(set(&mut chip, "1000", &[1]), Err(String::from("past the end of memory")));
+        assert_eq!(
+            set(&mut chip, "1ffffffffffffffffff", &[1]),
+            Err(String::from("1ffffffffffffffffff is not a hex number"))
+        );
+    }
+
+    #[test]
+    fn calls_and_symbols() {
+        let (mut chip, _) = machine(&[]);
+        chip.symbols.insert(0x200, String::from("main"));
+        chip.symbols.insert(0x280, String::from("draw"));
+        chip.stack[..2].copy_from_slice(&[0x204, 0x28A]);
+        chip.sp = 2;
+        chip.pc = 0x290;
+        assert_eq!(calls(&chip).collect::<Vec<_>>(), [0x290, 0x28A, 0x204]);
+        let names: Vec<String> = calls(&chip).map(|a| symbol(&chip, a)).collect();
+        assert_eq!(names, ["draw+10", "draw+A", "main+4"]);
+        assert_eq!(symbol(&chip, 0x100), "");
+    }
+
+    #[test]
+    fn base64_rfc4648() {
+        let cases = [
+            ("", ""),
+            ("f", "Zg=="),
+            ("fo", "Zm8="),
+            ("foo", "Zm9v"),
+            ("foob", "Zm9vYg=="),
+            ("fooba", "Zm9vYmE="),
+            ("foobar", "Zm9vYmFy"),
+        ];
+        for (data, encoded) in cases {
+            assert_eq!(base64(data.as_bytes()), encoded);
+            assert_eq!(unbase64(encoded), Some(data.as_bytes().to_vec()));
+        }
+        assert_eq!(unbase64("Zm9v!"), None);
+    }
+}
@@ -0,0 +1,670 @@
+use std::convert::TryFrom;
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::sync::mpsc::{channel, Receiver};
+
+use serde_json::{json, Value};
+
+use crate::common::{base64, calls, listen_local, number, set, symbol, unbase64};
+use crate::expr::Expr;
+use crate::instruction::Instruction;
+use crate::{Chip8, Hook};
+
+//
+// The Debug Adapter Protocol over TCP, for --dap 127.0.0.1:4711, so an
+// editor debugs the CHIP-8 program rather than the emulator. The machine
+// waits before its first instruction for a client to connect and finish
+// configuring, and again whenever a new client connects. Only clients on
+// this machine can connect.
+// https://microsoft.github.io/debug-adapter-protocol/specification
+//
+// Breakpoints go on lines of the .8o source being run, through the line
+// each instruction was assembled from, or on addresses from the disassembly.
+// Both take conditions in the debugger's expression language, see expr.rs,
+// which evaluate and hovers use too. There is one thread, the machine.
+//
+enum Message {
+    Connected(TcpStream),
+    Request(Value),
+    Closed,
+}
+
+enum Step {
+    // the next instruction
+    In,
+    // back at this address at this stack depth, past a call
+    Over(u16, u16),
+    // a return below this stack depth
+    Out(u16),
+    // a different source line: the line, where it started, the stack depth
+    // and whether calls are stepped over
+    Line(usize, u16, u16, bool),
+}
+
+struct Breakpoint {
+    address: u16,
+    condition: Option<Expr>,
+    // from setBreakpoints rather than setInstructionBreakpoints
+    source: bool,
+}
+
+pub struct Dap {
+    messages: Receiver<Message>,
+    client: Option<TcpStream>,
+    seq: u64,
+    // the .8o being run, as an absolute path
+    source: Option<String>,
+    // the client has sent configurationDone, nothing runs before
+    configured: bool,
+    stop_on_entry: bool,
+    breakpoints: Vec<Breakpoint>,
+    step: Option<Step>,
+    // stop before the next instruction for this reason
+    stop: Option<&'static str>,
+}
+
+// variablesReference numbers for the scopes
+const REGISTERS: u64 = 1;
+const STACK: u64 = 2;
+
+impl Dap {
+    pub fn listen(address: &str, source: Option<&str>) -> std::io::Result<Self> {
+        let listener = listen_local(address)?;
+        let (send, messages) = channel();
+        std::thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let reader = match stream.try_clone() {
+                    Ok(reader) => reader,
+                    Err(_) => continue,
+                };
+                if send.send(Message::Connected(stream)).is_err() {
+                    return;
+                }
+                let mut reader = BufReader::new(reader);
+                while let Some(request) = read(&mut reader) {
+                    if send.send(Message::Request(request)).is_err() {
+                        return;
+                    }
+                }
+                let _ = send.send(Message::Closed);
+            }
+        });
+        let source = source.and_then(|path| std::fs::canonicalize(path).ok());
+        Ok(Dap {
+            messages,
+            client: None,
+            seq: 1,
+            source: source.map(|path| path.to_string_lossy().into_owned()),
+            configured: false,
+            stop_on_entry: false,
+            breakpoints: Vec::new(),
+            step: None,
+            stop: None,
+        })
+    }
+
+    // Handles one message, true when it resumes the machine
+    fn receive(&mut self, chip: &mut Chip8, message: Message) -> bool {
+        match message {
+            Message::Connected(stream) => {
+                self.client = Some(stream);
+                self.configured = false;
+                false
+            }
+            Message::Request(request) => {
+                let command = request["command"].as_str().unwrap_or("").to_string();
+                let (result, resume) = match self.request(chip, &command, &request["arguments"]) {
+                    Ok(Some(body)) => (Ok(body), false),
+                    Ok(None) => (Ok(json!({})), true),
+                    Err(e) => (Err(e), false),
+                };
+                let mut response = json!({
+                    "type": "response",
+                    "request_seq": request["seq"],
+                    "command": command,
+                    "success": result.is_ok(),
+                });
+                match result {
+                    Ok(body) => response["body"] = body,
+                    Err(e) => response["message"] = json!(e),
+                }
+                self.send(response);
+                match command.as_str() {
+                    "initialize" => self.event("initialized", json!({})),
+                    "disconnect" => self.client = None,
+                    _ => {}
+                }
+                resume
+            }
+            Message::Closed => {
+                // nobody left to stop for
+                self.client = None;
+                self.configured = true;
+                self.breakpoints.clear();
+                self.step = None;
+                self.stop = None;
+                false
+            }
+        }
+    }
+
+    // Carries out one request: the body of its response, or None for one
+    // that resumes the machine
+    fn request(&mut self, chip: &mut Chip8, command: &str, arguments: &Value) -> Result<Option<Value>, String> {
+        let body = match command {
+            "initialize" => json!({
+                "supportsConfigurationDoneRequest": true,
+                "supportsConditionalBreakpoints": true,
+                "supportsInstructionBreakpoints": true,
+                "supportsDisassembleRequest": true,
+                "supportsReadMemoryRequest": true,
+                "supportsWriteMemoryRequest": true,
+                "supportsSteppingGranularity": true,
+                "supportsEvaluateForHovers": true,
+                "supportsSetVariable": true,
+                "supportsTerminateRequest": true,
+            }),
+            "launch" | "attach" => {
+                // the ROM was loaded from the command line already
+                self.stop_on_entry = arguments["stopOnEntry"].as_bool().unwrap_or(false);
+                json!({})
+            }
+            "configurationDone" => {
+                self.configured = true;
+                json!({})
+            }
+            "setBreakpoints" => {
+                let path = arguments["source"]["path"].as_str().and_then(|p| std::fs::canonicalize(p).ok());
+                let ours = path.is_some_and(|p| Some(p.to_string_lossy().as_ref()) == self.source.as_deref());
+                self.breakpoints.retain(|b| !b.source);
+                let mut verified = Vec::new();
+                for wanted in arguments["breakpoints"].as_array().into_iter().flatten() {
+                    let line = wanted["line"].as_u64().unwrap_or(0) as usize;
+                    let found = if ours { address_of(chip, line) } else { None };
+                    match (found, condition(wanted)) {
+                        (Some((address, line)), Ok(condition)) => {
+                            self.breakpoints.push(Breakpoint {
+                                address,
+                                condition,
+                                source: true,
+                            });
+                            verified.push(json!({ "verified": true, "line": line }));
+                        }
+                        (None, _) => verified.push(json!({ "verified": false, "line": line, "message": "no code here" })),
+                        (_, Err(e)) => verified.push(json!({ "verified": false, "line": line, "message": e })),
+                    }
+                }
+                json!({ "breakpoints": verified })
+            }
+            "setInstructionBreakpoints" => {
+                self.breakpoints.retain(|b| b.source);
+                let mut verified = Vec::new();
+                for wanted in arguments["breakpoints"].as_array().into_iter().flatten() {
+                    let reference = wanted["instructionReference"].as_str().and_then(reference);
+                    let address = reference.and_then(|r| r.checked_add(wanted["offset"].as_i64().unwrap_or(0)));
+                    match (address.filter(|a| (0..0x1000).contains(a)), condition(wanted)) {
+                        (Some(address), Ok(condition)) => {
+                            self.breakpoints.push(Breakpoint {
+                                address: address as u16,
+                                condition,
+                                source: false,
+                            });
+                            verified.push(json!({ "verified": true, "instructionReference": hex(address as u16) }));
+                        }
+                        (None, _) => verified.push(json!({ "verified": false, "message": "not an address in memory" })),
+                        (_, Err(e)) => verified.push(json!({ "verified": false, "message": e })),
+                    }
+                }
+                json!({ "breakpoints": verified })
+            }
+            "setExceptionBreakpoints" => json!({ "breakpoints": [] }),
+            "threads" => json!({ "threads": [{ "id": 1, "name": "CHIP-8" }] }),
+            "stackTrace" => {
+                let frames: Vec<Value> =
+                    calls(chip).enumerate().map(|(id, address)| self.frame(chip, id, address)).collect();
+                json!({ "stackFrames": frames, "totalFrames": frames.len() })
+            }
+            "scopes" => json!({ "scopes": [
+                { "name": "Registers", "variablesReference": REGISTERS, "expensive": false },
+                { "name": "Stack", "variablesReference": STACK, "expensive": false },
+            ] }),
+            "variables" => {
+                let variables: Vec<Value> = match arguments["variablesReference"].as_u64() {
+                    Some(REGISTERS) => registers(chip),
+                    Some(STACK) => (0..chip.sp as usize)
+                        .rev()
+                        .map(|depth| {
+                            let address = chip.stack[depth];
+                            variable(&depth.to_string(), format!("{} {}", hex(address), symbol(chip, address)))
+                        })
+                        .collect(),
+                    _ => Vec::new(),
+                };
+                json!({ "variables": variables })
+            }
+            "setVariable" => {
+                // only registers, the stack's variables are named by depth
+                let name = arguments["name"].as_str().unwrap_or("");
+                let named = |chip: &Chip8| registers(chip).into_iter().find(|r| r["name"] == name);
+                if arguments["variablesReference"].as_u64() != Some(REGISTERS) || named(chip).is_none() {
+                    return Err(format!("{} can't be set", name));
+                }
+                let value = Expr::parse(arguments["value"].as_str().unwrap_or(""))?.eval(chip)?;
+                set(chip, name, &[value as usize])?;
+                json!({ "value": named(chip).map(|r| r["value"].clone()) })
+            }
+            "evaluate" => {
+                let expression = arguments["expression"].as_str().unwrap_or("");
+                let value = Expr::parse(expression)?.eval(chip)?;
+                json!({ "result": format!("0x{:X} ({})", value, value), "variablesReference": 0 })
+            }
+            "readMemory" => {
+                let start = memory_reference(arguments)?;
+                // more than memory holds can't be read anyway
+                let count = arguments["count"].as_u64().unwrap_or(0).min(0x1000) as i64;
+                let from = start.clamp(0, 0x1000) as usize;
+                let to = start.saturating_add(count).clamp(0, 0x1000) as usize;
+                json!({
+                    "address": hex(from as u16),
+                    "data": base64(&chip.memory[from..to.max(from)]),
+                    "unreadableBytes": count - (to.max(from) - from) as i64,
+                })
+            }
+            "writeMemory" => {
+                let start = memory_reference(arguments)?;
+                let data = unbase64(arguments["data"].as_str().unwrap_or("")).ok_or("data is not base64")?;
+                let start = usize::try_from(start).map_err(|_| "before the start of memory")?;
+                if start.checked_add(data.len()).is_none_or(|end| end > chip.memory.len()) {
+                    return Err(String::from("past the end of memory"));
+                }
+                chip.memory[start..start + data.len()].copy_from_slice(&data);
+                json!({ "bytesWritten": data.len() })
+            }
+            "disassemble" => {
+                let start = memory_reference(arguments)?;
+                let offset = arguments["instructionOffset"].as_i64().unwrap_or(0);
+                let start = offset.checked_mul(2).and_then(|o| start.checked_add(o));
+                let start = start.ok_or("instructionOffset is outside memory")?;
+                // past that many every instruction is outside memory
+                let count = arguments["instructionCount"].as_i64().unwrap_or(0).min(0x1000);
+                let instructions: Vec<Value> =
+                    (0..count).map(|n| self.instruction(chip, start.saturating_add(n * 2))).collect();
+                json!({ "instructions": instructions })
+            }
+            "continue" => return Ok(None),
+            "next" | "stepIn" => {
+                let over = command == "next";
+                let line = chip.lines.get(&chip.pc).copied();
+                let call = chip.memory.get(chip.pc as usize).is_some_and(|b| b >> 4 == 2);
+                self.step = Some(match (line, arguments["granularity"].as_str()) {
+                    (Some(line), granularity) if granularity != Some("instruction") => {
+                        Step::Line(line, chip.pc, chip.sp, over)
+                    }
+                    _ if over && call => Step::Over(chip.pc + 2, chip.sp),
+                    _ => Step::In,
+                });
+                return Ok(None);
+            }
+            "stepOut" => {
+                self.step = Some(Step::Out(chip.sp));
+                return Ok(None);
+            }
+            "pause" => {
+                self.stop = Some("pause");
+                json!({})
+            }
+            "disconnect" | "terminate" => {
+                let terminate = command == "terminate" || arguments["terminateDebuggee"].as_bool().unwrap_or(false);
+                if terminate {
+                    chip.exit();
+                } else {
+                    self.breakpoints.clear();
+                }
+                self.configured = true;
+                return Ok(None);
+            }
+            _ => return Err(format!("{} is not supported", command)),
+        };
+        Ok(Some(body))
+    }
+
+    fn frame(&self, chip: &Chip8, id: usize, address: u16) -> Value {
+        let name = match symbol(chip, address) {
+            name if name.is_empty() => hex(address),
+            name => name,
+        };
+        let mut frame = json!({
+            "id": id,
+            "name": name,
+            "line": 0,
+            "column": 0,
+            "instructionPointerReference": hex(address),
+        });
+        let line = chip.lines.range(..=address).next_back().map(|(_, line)| *line);
+        if let (Some(line), Some(source)) = (line, self.source_json()) {
+            frame["line"] = json!(line);
+            frame["column"] = json!(1);
+            frame["source"] = source;
+        }
+        frame
+    }
+
+    fn instruction(&self, chip: &Chip8, address: i64) -> Value {
+        if !(0..0x0FFF).contains(&address) {
+            return json!({ "address": format!("0x{:04X}", address.max(0)), "instruction": "", "presentationHint": "invalid" });
+        }
+        let address = address as u16;
+        let at = address as usize;
+        let opcode = (chip.memory[at] as u16) << 8 | chip.memory[at + 1] as u16;
+        let name = |a: u16| chip.symbols.get(&a).cloned().unwrap_or_else(|| format!("0x{:03X}", a));
+        let text = Instruction::decode(opcode)
+            .and_then(|i| i.octo(&name))
+            .unwrap_or_else(|| format!("0x{:02X} 0x{:02X}", opcode >> 8, opcode & 0xFF));
+        let mut instruction = json!({
+            "address": hex(address),
+            "instructionBytes": format!("{:02X} {:02X}", opcode >> 8, opcode & 0xFF),
+            "instruction": text,
+        });
+        if let Some(label) = chip.symbols.get(&address) {
+            instruction["symbol"] = json!(label);
+        }
+        if let (Some(line), Some(source)) = (chip.lines.get(&address), self.source_json()) {
+            instruction["line"] = json!(line);
+            instruction["location"] = source;
+        }
+        instruction
+    }
+
+    fn source_json(&self) -> Option<Value> {
+        let path = self.source.as_ref()?;
+        let name = std::path::Path::new(path).file_name()?.to_string_lossy();
+        Some(json!({ "name": name, "path": path }))
+    }
+
+    fn event(&mut self, event: &str, body: Value) {
+        self.send(json!({ "type": "event", "event": event, "body": body }));
+    }
+
+    fn send(&mut self, mut message: Value) {
+        message["seq"] = json!(self.seq);
+        self.seq += 1;
+        let text = message.to_string();
+        if let Some(client) = &mut self.client {
+            if write!(client, "Content-Length: {}\r\n\r\n{}", text.len(), text).is_err() {
+                self.client = None;
+            }
+        }
+    }
+}
+
+impl Hook for Dap {
+    // Called before every instruction, stops when the client asks to
+    fn check(&mut self, chip: &mut Chip8) {
+        while let Ok(message) = self.messages.try_recv() {
+            self.receive(chip, message);
+        }
+        while !self.configured {
+            match self.messages.recv() {
+                Ok(message) => {
+                    self.receive(chip, message);
+                }
+                Err(_) => self.configured = true,
+            }
+            if self.configured && std::mem::take(&mut self.stop_on_entry) {
+                self.stop = Some("entry");
+            }
+        }
+        if self.client.is_none() {
+            return;
+        }
+        let stepped = match self.step {
+            Some(Step::In) => true,
+            Some(Step::Over(pc, sp)) => chip.pc == pc && chip.sp == sp,
+            Some(Step::Out(sp)) => chip.sp < sp,
+            Some(Step::Line(line, pc, sp, over)) => match chip.lines.get(&chip.pc) {
+                // once round a loop that is all on one line counts too
+                Some(at) => chip.sp < sp || (*at != line || chip.pc == pc) && (!over || chip.sp == sp),
+                None => false,
+            },
+            None => false,
+        };
+        let fault = chip.fault.take();
+        let reason = if fault.is_some() {
+            Some("exception")
+        } else if let Some(reason) = self.stop.take() {
+            Some(reason)
+        } else if stepped {
+            Some("step")
+        } else if self.breakpoints.iter().any(|b| b.address == chip.pc && met(b, chip)) {
+            Some("breakpoint")
+        } else {
+            None
+        };
+        if let Some(reason) = reason {
+            self.step = None;
+            let mut body = json!({ "reason": reason, "threadId": 1, "allThreadsStopped": true });
+            if let Some(fault) = fault {
+                body["text"] = json!(fault);
+            }
+            self.event("stopped", body);
+            // answers requests until one of them resumes the machine
+            while self.client.is_some() {
+                match self.messages.recv() {
+                    Ok(message) => {
+                        if self.receive(chip, message) {
+                            break;
+                        }
+                    }
+                    Err(_) => break,
+                }
+            }
+        }
+    }
+}
+
+impl Drop for Dap {
+    fn drop(&mut self) {
+        // the machine has stopped for good
+        self.event("exited", json!({ "exitCode": 0 }));
+        self.event("terminated", json!({}));
+    }
+}
+
+// One message: headers, a blank line and Content-Length bytes of JSON
+fn read(reader: &mut impl BufRead) -> Option<Value> {
+    let mut length = None;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header).ok()? == 0 {
+            return None;
+        }
+        let header = header.trim();
+        if header.is_empty() && length.is_some() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("Content-Length") {
+                length = value.trim().parse::<usize>().ok();
+            }
+        }
+    }
+    let mut body = vec![0; length?];
+    reader.read_exact(&mut body).ok()?;
+    // a message that isn't JSON gets an empty request, which fails politely
+    Some(serde_json::from_slice(&body).unwrap_or_else(|_| json!({})))
+}
+
+// The first instruction on line or after it, and the line it is really on
+fn address_of(chip: &Chip8, line: usize) -> Option<(u16, usize)> {
+    chip.lines
+        .iter()
+        .filter(|(_, at)| **at >= line)
+        .min_by_key(|(address, at)| (**at, **address))
+        .map(|(address, at)| (*address, *at))
+}
+
+fn condition(breakpoint: &Value) -> Result<Option<Expr>, String> {
+    match breakpoint["condition"].as_str() {
+        Some(text) if !text.trim().is_empty() => Expr::parse(text).map(Some),
+        _ => Ok(None),
+    }
+}
+
+// A condition that can't be worked out stops, better than running past
+fn met(breakpoint: &Breakpoint, chip: &Chip8) -> bool {
+    match &breakpoint.condition {
+        Some(condition) => condition.eval(chip) != Ok(0),
+        None => true,
+    }
+}
+
+fn registers(chip: &Chip8) -> Vec<Value> {
+    let mut registers: Vec<Value> =
+        (0..16).map(|x| variable(&format!("V{:X}", x), format!("0x{:02X}", chip.V[x]))).collect();
+    let mut i = variable("I", hex(chip.I));
+    i["memoryReference"] = json!(hex(chip.I));
+    let mut pc = variable("PC", hex(chip.pc));
+    pc["memoryReference"] = json!(hex(chip.pc));
+    registers.extend([
+        i,
+        pc,
+        variable("SP", chip.sp.to_string()),
+        variable("DT", format!("0x{:02X}", chip.delay_timer)),
+        variable("ST", format!("0x{:02X}", chip.sound_timer)),
+    ]);
+    registers
+}
+
+fn variable(name: &str, value: String) -> Value {
+    json!({ "name": name, "value": value, "variablesReference": 0 })
+}
+
+// The hex address in a reference
+fn reference(text: &str) -> Option<i64> {
+    i64::try_from(number(text).ok()?).ok()
+}
+
+fn memory_reference(arguments: &Value) -> Result<i64, String> {
+    let reference = arguments["memoryReference"].as_str().and_then(reference).ok_or("no memoryReference")?;
+    let offset = arguments["offset"].as_i64().unwrap_or(0);
+    reference.checked_add(offset).ok_or_else(|| String::from("offset is outside memory"))
+}
+
+// An address as memory and instruction references show it
+fn hex(address: u16) -> String {
+    format!("0x{:04X}", address)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::machine;
+
+    fn request(chip: &mut Chip8, command: &str, arguments: Value) -> Result<Value, String> {
+        let mut dap = Dap::listen("127.0.0.1:0", None).unwrap();
+        dap.request(chip, command, &arguments).map(Option::unwrap)
+    }
+
+    #[test]
+    fn read_memory() {
+        let (mut chip, _) = machine(&[0x12, 0x34, 0x56]);
+        let read = request(&mut chip, "readMemory", json!({ "memoryReference": "0x0200", "offset": 1, "count": 2 }));
+        assert_eq!(read, Ok(json!({ "address": "0x0201", "data": "NFY=", "unreadableBytes": 0 })));
+        let read = request(&mut chip, "readMemory", json!({ "memoryReference": "0x0FFE", "count": 4 }));
+        assert_eq!(read, Ok(json!({ "address": "0x0FFE", "data": "AAA=", "unreadableBytes": 2 })));
+        let huge = json!({ "memoryReference": "0x7fffffffffffffff", "count": u64::MAX });
+        let read = request(&mut chip, "readMemory", huge);
+        assert_eq!(read, Ok(json!({ "address": "0x1000", "data": "", "unreadableBytes": 0x1000 })));
+        let past = json!({ "memoryReference": "0x7fffffffffffffff", "offset": 1, "count": 1 });
+        assert_eq!(request(&mut chip, "readMemory", past), Err(String::from("offset is outside memory")));
+    }
+
+    #[test]
+    fn write_memory() {
+        let (mut chip, _) = machine(&[]);
+        let write = json!({ "memoryReference": "0x0300", "data": "AQID" });
+        assert_eq!(request(&mut chip, "writeMemory", write), Ok(json!({ "bytesWritten": 3 })));
+        assert_eq!(chip.memory[0x300..0x303], [1, 2, 3]);
+        for reference in ["0x0FFE", "0x7fffffffffffffff"].iter() {
+            let write = json!({ "memoryReference": reference, "data": "AQID" });
+            assert_eq!(request(&mut chip, "writeMemory", write), Err(String::from("past the end of memory")));
+        }
+        let write = json!({ "memoryReference": "0x0000", "offset": -1, "data": "AQID" });
+        assert_eq!(request(&mut chip, "writeMemory", write), Err(String::from("before the start of memory")));
+    }
+
+    #[test]
+    fn disassemble() {
+        let (mut chip, _) = machine(&[0x00, 0xE0]);
+        let arguments = json!({ "memoryReference": "0x0200", "instructionOffset": -1, "instructionCount": 2 });
+        let body = request(&mut chip, "disassemble", arguments).unwrap();
+        assert_eq!(body["instructions"][0]["instruction"], "0x00 0x00");
+        assert_eq!(body["instructions"][1]["instruction"], "clear");
+        let arguments = json!({ "memoryReference": "0x0FFE", "instructionCount": i64::MAX });
+        let body = request(&mut chip, "disassemble", arguments).unwrap();
+        assert_eq!(body["instructions"].as_array().map(Vec::len), Some(0x1000));
+        assert_eq!(body["instructions"][1]["presentationHint"], "invalid");
+        let arguments = json!({ "memoryReference": "0x0200", "instructionOffset": i64::MAX, "instructionCount": 1 });
+        let error = request(&mut chip, "disassemble", arguments);
+        assert_eq!(error, Err(String::from("instructionOffset is outside memory")));
+    }
+
+    #[test]
+    fn stack_trace() {
+        let (mut chip, _) = machine(&[]);
+        chip.symbols.insert(0x200, String::from("main"));
+        chip.stack[0] = 0x204;
+        chip.sp = 1;
+        chip.pc = 0x300;
+        let body = request(&mut chip, "stackTrace", json!({})).unwrap();
+        let names: Vec<&Value> = body["stackFrames"].as_array().unwrap().iter().map(|f| &f["name"]).collect();
+        assert_eq!(names, ["main+100", "main+4"]);
+        assert_eq!(body["stackFrames"][1]["instructionPointerReference"], "0x0204");
+    }
+
+    #[test]
+    fn set_variable() {
+        let (mut chip, _) = machine(&[]);
+        chip.V[1] = 3;
+        let set = json!({ "variablesReference": REGISTERS, "name": "V2", "value": "v1 * 0x10 + 1" });
+        assert_eq!(request(&mut chip, "setVariable", set), Ok(json!({ "value": "0x31" })));
+        assert_eq!(chip.V[2], 0x31);
+        let set = json!({ "variablesReference": REGISTERS, "name": "I", "value": "0x2A0" });
+        assert_eq!(request(&mut chip, "setVariable", set), Ok(json!({ "value": "0x02A0" })));
+        assert_eq!(chip.I, 0x2A0);
+        let set = json!({ "variablesReference": STACK, "name": "0", "value": "1" });
+        assert_eq!(request(&mut chip, "setVariable", set), Err(String::from("0 can't be set")));
+        let set = json!({ "variablesReference": REGISTERS, "name": "V2", "value": "1 / 0" });
+        assert_eq!(request(&mut chip, "setVariable", set), Err(String::from("division by zero")));
+        assert_eq!(chip.memory[0], 0);
+        // the instruction at pc has to be in memory
+        for far in ["0xFFF", "0x1000", "-1"].iter() {
+            let set = json!({ "variablesReference": REGISTERS, "name": "PC", "value": far });
+            assert_eq!(request(&mut chip, "setVariable", set), Err(String::from("PC goes up to FFE")));
+        }
+        assert_eq!(chip.pc, 0x200);
+        let set = json!({ "variablesReference": REGISTERS, "name": "PC", "value": "0xFFE" });
+        assert_eq!(request(&mut chip, "setVariable", set), Ok(json!({ "value": "0x0FFE" })));
+        chip.emulate_cycle();
+    }
+
+    #[test]
+    fn local_clients_only() {
+        let e = Dap::listen("0.0.0.0:0", None).err().unwrap();
+        assert_eq!(e.kind(), std::io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn instruction_breakpoints() {
+        let (mut chip, _) = machine(&[]);
+        let breakpoints = json!({ "breakpoints": [
+            { "instructionReference": "0x0200", "offset": 2 },
+            { "instructionReference": "0x7fffffffffffffff", "offset": 1 },
+            { "instructionReference": "0x1000" },
+        ] });
+        let body = request(&mut chip, "setInstructionBreakpoints", breakpoints).unwrap();
+        let verified: Vec<&Value> = body["breakpoints"].as_array().unwrap().iter().map(|b| &b["verified"]).collect();
+        assert_eq!(verified, [true, false, false]);
+        assert_eq!(body["breakpoints"][0]["instructionReference"], "0x0202");
+    }
+}
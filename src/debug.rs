@@ -0,0 +1,659 @@
+use std::collections::VecDeque;
+use std::io::{BufRead, Write};
+use std::ops::Range;
+use std::sync::mpsc::{channel, Receiver};
+
+use crate::common::{calls, memory_address, number, set, symbol};
+use crate::expr::Expr;
+use crate::instruction::Instruction;
+use crate::{Chip8, Hook};
+
+//
+// The --debug command line, read from stdin. The machine stops before its
+// first instruction; pressing Enter while it runs stops it again, other
+// lines typed meanwhile wait for the next stop. Numbers
+// are hex, with or without 0x, and an empty line repeats the last command.
+// Conditions and log messages are expressions, see expr.rs, where numbers
+// are decimal unless they start with 0x.
+//
+const HELP: &str = "\
+break ADDR [hit N] [if EXPR]
+                b   stop before the instruction at ADDR, when EXPR is true
+                    and from the Nth time on, no ADDR lists them
+log ADDR TEXT   l   print TEXT at ADDR and carry on, {EXPR} in it is
+                    replaced by its value in hex
+delete ADDR     d   remove the breakpoints and logs at ADDR
+step [N]        s   run N instructions, 1 when left out
+next            n   step, running a whole subroutine when it is a call
+frame           f   run to the end of the current 60 Hz frame
+back [N]            go back N instructions, 1 when left out
+continue        c   run until a breakpoint or Enter
+regs            r   show V0-VF, I, PC, SP and the timers
+backtrace       bt  show the calls that led here and how deep the stack got
+mem ADDR [LEN]  m   show LEN bytes of memory from ADDR, 0x40 when left out
+set REG VALUE       set V0-VF, I, PC, SP, DT or ST
+set ADDR BYTES      write bytes to memory from ADDR
+watch ADDR[-END] [r|w|rw]
+                w   stop after memory is read or written, w when left out
+watch REG           stop after V0-VF or I changes
+watch               list the watches with their numbers
+unwatch N           remove watch N
+quit            q   stop the machine";
+
+struct Breakpoint {
+    address: u16,
+    condition: Option<(String, Expr)>,
+    // times reached with the condition true, stopping from the `from`th
+    hits: u64,
+    from: u64,
+    // print this and carry on rather than stop
+    log: Option<(String, Vec<Part>)>,
+}
+
+// A log message in pieces, {EXPR} becoming Value
+enum Part {
+    Text(String),
+    Value(Expr),
+}
+
+enum Watch {
+    Memory { range: Range<usize>, read: bool, write: bool },
+    V(usize),
+    I,
+}
+
+// The machine as it was before the instruction the watches look at
+struct Before {
+    pc: u16,
+    v: [u8; 16],
+    i: u16,
+    reads: Range<usize>,
+    writes: Range<usize>,
+    // each memory watch's bytes
+    memory: Vec<Vec<u8>>,
+}
+
+pub struct Debugger {
+    lines: Receiver<String>,
+    typed_ahead: VecDeque<String>,
+    breakpoints: Vec<Breakpoint>,
+    // stop before the next instruction whatever it is
+    pause: bool,
+    // instructions left to run before stopping
+    steps: Option<u64>,
+    // stop on getting back to this address at this stack depth
+    over: Option<(u16, u16)>,
+    // stop once this frame has ended
+    frame: Option<u64>,
+    watches: Vec<Watch>,
+    before: Option<Before>,
+    // what the watches saw the last instruction do
+    hit: Option<String>,
+    last: String,
+}
+
+impl Debugger {
+    pub fn new() -> Self {
+        let (send, lines) = channel();
+        std::thread::spawn(move || {
+            for line in std::io::stdin().lock().lines() {
+                let sent = line.map(|line| send.send(line).is_ok());
+                if sent.ok() != Some(true) {
+                    break;
+                }
+            }
+        });
+        Debugger::reading(lines)
+    }
+
+    fn reading(lines: Receiver<String>) -> Self {
+        Debugger {
+            lines,
+            typed_ahead: VecDeque::new(),
+            breakpoints: Vec::new(),
+            pause: true,
+            steps: None,
+            over: None,
+            frame: None,
+            watches: Vec::new(),
+            before: None,
+            hit: None,
+            last: String::new(),
+        }
+    }
+
+    // Counts the breakpoints at pc that are hit and prints their logs, the
+    // reason to stop when one of them wants to
+    fn breakpoints(&mut self, chip: &Chip8) -> Option<String> {
+        let mut reason = None;
+        for breakpoint in self.breakpoints.iter_mut().filter(|b| b.address == chip.pc) {
+            let met = match &breakpoint.condition {
+                Some((text, condition)) => match condition.eval(chip) {
+                    Ok(value) => value != 0,
+                    Err(e) => {
+                        reason = Some(format!("breakpoint {:04X}: {}: {}", chip.pc, text, e));
+                        continue;
+                    }
+                },
+                None => true,
+            };
+            if !met {
+                continue;
+            }
+            breakpoint.hits += 1;
+            match &breakpoint.log {
+                Some((_, parts)) => println!("{}", message(parts, chip)),
+                None if breakpoint.hits >= breakpoint.from => {
+                    reason = Some(format!("breakpoint {:04X}, hit {}", chip.pc, breakpoint.hits));
+                }
+                None => {}
+            }
+        }
+        reason
+    }
+
+    fn before(&self, chip: &Chip8) -> Before {
+        let (reads, writes) = accesses(chip);
+        let memory = self
+            .watches
+            .iter()
+            .filter_map(|w| match w {
+                Watch::Memory { range, .. } => Some(chip.memory[range.clone()].to_vec()),
+                _ => None,
+            })
+            .collect();
+        Before {
+            pc: chip.pc,
+            v: chip.V,
+            i: chip.I,
+            reads,
+            writes,
+            memory,
+        }
+    }
+
+    // Runs commands until one of them resumes the machine
+    fn prompt(&mut self, chip: &mut Chip8) {
+        loop {
+            print!("(debug) ");
+            let _ = std::io::stdout().flush();
+            let line = match self.typed_ahead.pop_front().ok_or(()).or_else(|_| self.lines.recv()) {
+                Ok(line) => line,
+                // stdin closed, nobody left to type continue
+                Err(_) => {
+                    chip.exit();
+                    return;
+                }
+            };
+            let line = if line.trim().is_empty() { self.last.clone() } else { line };
+            self.last = line.clone();
+            match self.command(chip, &line) {
+                Ok(true) => return,
+                Ok(false) => {}
+                Err(e) => println!("{}", e),
+            }
+        }
+    }
+
+    // Carries out one command, true when the machine should run again
+    fn command(&mut self, chip: &mut Chip8, line: &str) -> Result<bool, String> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words.as_slice() {
+            [] => {}
+            ["break" | "b"] => {
+                for breakpoint in &self.breakpoints {
+                    let mut text = format!("{:04X}", breakpoint.address);
+                    if let Some((log, _)) = &breakpoint.log {
+                        text += &format!(" log {}", log);
+                    }
+                    if breakpoint.from > 1 {
+                        text += &format!(" hit {:X}", breakpoint.from);
+                    }
+                    if let Some((condition, _)) = &breakpoint.condition {
+                        text += &format!(" if {}", condition);
+                    }
+                    println!("{}  hit {} times", text, breakpoint.hits);
+                }
+            }
+            ["break" | "b", address, rest @ ..] => {
+                let address = memory_address(chip, address)?;
+                let (from, condition) = match rest {
+                    ["hit", n, rest @ ..] => (number(n)?.max(1) as u64, rest),
+                    rest => (1, rest),
+                };
+                let condition = match condition {
+                    [] => None,
+                    ["if", expr @ ..] if !expr.is_empty() => {
+                        let text = expr.join(" ");
+                        let condition = Expr::parse(&text)?;
+                        Some((text, condition))
+                    }
+                    _ => return Err(String::from("break ADDR [hit N] [if EXPR]")),
+                };
+                // one breakpoint to an address, logs aside
+                self.breakpoints.retain(|b| b.address != address || b.log.is_some());
+                self.breakpoints.push(Breakpoint {
+                    address,
+                    condition,
+                    hits: 0,
+                    from,
+                    log: None,
+                });
+            }
+            ["log" | "l", address, _, ..] => {
+                let address = memory_address(chip, address)?;
+                let text = line.trim().splitn(3, char::is_whitespace).nth(2).unwrap().trim();
+                self.breakpoints.push(Breakpoint {
+                    address,
+                    condition: None,
+                    hits: 0,
+                    from: 1,
+                    log: Some((text.to_string(), parts(text)?)),
+                });
+            }
+            ["log" | "l", ..] => return Err(String::from("log ADDR TEXT")),
+            ["delete" | "d", address] => {
+                let address = memory_address(chip, address)?;
+                self.breakpoints.retain(|b| b.address != address);
+            }
+            ["step" | "s"] => {
+                self.steps = Some(1);
+                return Ok(true);
+            }
+            ["step" | "s", count] => {
+                self.steps = Some(number(count)?.max(1) as u64);
+                return Ok(true);
+            }
+            ["next" | "n"] => {
+                let pc = chip.pc as usize;
+                if chip.memory.get(pc).is_some_and(|b| b >> 4 == 2) {
+                    self.over = Some((chip.pc + 2, chip.sp));
+                } else {
+                    self.steps = Some(1);
+                }
+                return Ok(true);
+            }
+            ["frame" | "f"] => {
+                self.frame = Some(chip.frames);
+                return Ok(true);
+            }
+            ["back", count @ ..] if count.len() <= 1 => {
+                let n = match count {
+                    [n] => number(n)? as u64,
+                    _ => 1,
+                };
+                let mut rewind = chip.rewind.take().ok_or_else(|| String::from("rewind is off"))?;
+                let went = rewind.back(chip, n);
+                chip.rewind = Some(rewind);
+                if went < n {
+                    println!("back {:X}, as far as rewind goes", went);
+                }
+                where_is(chip);
+            }
+            ["continue" | "c"] => return Ok(true),
+            ["regs" | "r"] => {
+                for row in 0..4 {
+                    let regs: Vec<String> = (row * 4..row * 4 + 4)
+                        .map(|r| format!("V{:X} {:02X}", r, chip.V[r]))
+                        .collect();
+                    println!("{}", regs.join("  "));
+                }
+                println!("I  {:04X}  PC {:04X}  SP {:X}", chip.I, chip.pc, chip.sp);
+                println!("DT {:02X}  ST {:02X}  frame {}", chip.delay_timer, chip.sound_timer, chip.frames);
+            }
+            ["backtrace" | "bt" | "stack"] => {
+                for (n, address) in calls(chip).enumerate() {
+                    println!("#{:<2} {:04X}  {}", n, address, symbol(chip, address));
+                }
+                println!("depth {}, deepest {} of {}", chip.sp, chip.deepest, chip.stack_depth);
+            }
+            ["mem" | "m", address, rest @ ..] => {
+                let start = number(address)?;
+                let length = match rest {
+                    [] => 0x40,
+                    [length] => number(length)?,
+                    _ => return Err(String::from("mem ADDR [LEN]")),
+                };
+                // a long dump stops at the end of memory
+                let end = start.checked_add(length).ok_or("past the end of memory")?.min(chip.memory.len());
+                for row in (start..end).step_by(16) {
+                    let bytes: Vec<String> = chip.memory[row..end.min(row + 16)]
+                        .iter()
+                        .map(|b| format!("{:02X}", b))
+                        .collect();
+                    println!("{:04X}  {}", row, bytes.join(" "));
+                }
+            }
+            ["set", target, values @ ..] if !values.is_empty() => {
+                let values = values.iter().map(|v| number(v)).collect::<Result<Vec<_>, _>>()?;
+                set(chip, target, &values)?;
+            }
+            ["set", ..] => return Err(String::from("set REG VALUE or set ADDR BYTES")),
+            ["watch" | "w"] => {
+                for (n, watch) in self.watches.iter().enumerate() {
+                    match watch {
+                        Watch::Memory { range, read, write } => {
+                            let how = match (read, write) {
+                                (true, true) => "rw",
+                                (true, false) => "r",
+                                _ => "w",
+                            };
+                            println!("{}: {:04X}-{:04X} {}", n, range.start, range.end - 1, how);
+                        }
+                        Watch::V(x) => println!("{}: V{:X}", n, x),
+                        Watch::I => println!("{}: I", n),
+                    }
+                }
+            }
+            ["watch" | "w", target, how @ ..] => {
+                let register = target.to_ascii_uppercase();
+                let watch = match (register.as_str(), how) {
+                    ("I", []) => Watch::I,
+                    (v, []) if v.len() == 2 && v.starts_with('V') => {
+                        let x = usize::from_str_radix(&v[1..], 16).map_err(|_| format!("no register {}", target))?;
+                        Watch::V(x)
+                    }
+                    (_, [] | ["r" | "w" | "rw"]) => {
+                        let (from, to) = target.split_once('-').unwrap_or((target, target));
+                        let (from, to) = (number(from)?, number(to)?);
+                        if from > to || to >= chip.memory.len() {
+                            return Err(format!("{} is not a range in memory", target));
+                        }
+                        let how = how.first().copied().unwrap_or("w");
+                        Watch::Memory {
+                            range: from..to + 1,
+                            read: how.contains('r'),
+                            write: how.contains('w'),
+                        }
+                    }
+                    _ => return Err(String::from("watch ADDR[-END] [r|w|rw] or watch REG")),
+                };
+                self.watches.push(watch);
+                // the instruction about to run is watched too
+                self.before = Some(self.before(chip));
+            }
+            ["unwatch", n] => {
+                let n: usize = n.parse().map_err(|_| format!("{} is not a watch number", n))?;
+                if n >= self.watches.len() {
+                    return Err(format!("no watch {}", n));
+                }
+                self.watches.remove(n);
+                self.before = None;
+            }
+            ["quit" | "q"] => {
+                chip.exit();
+                return Ok(true);
+            }
+            ["help" | "h"] => println!("{}", HELP),
+            _ => return Err(format!("don't know {}, try help", line.trim())),
+        }
+        Ok(false)
+    }
+}
+
+impl Hook for Debugger {
+    // Called before every instruction, stops when something asks to
+    fn check(&mut self, chip: &mut Chip8) {
+        if let Some(n) = &mut self.steps {
+            *n -= 1;
+        }
+        let mut interrupted = false;
+        for line in self.lines.try_iter() {
+            if line.trim().is_empty() {
+                interrupted = true;
+            } else {
+                self.typed_ahead.push_back(line);
+            }
+        }
+        let breakpoint = self.breakpoints(chip);
+        let reason = if let Some(fault) = chip.fault.take() {
+            Some(fault)
+        } else if let Some(hit) = self.hit.take() {
+            Some(hit)
+        } else if interrupted {
+            Some(String::from("interrupted"))
+        } else if self.pause || self.steps == Some(0) {
+            Some(String::new())
+        } else if breakpoint.is_some() {
+            breakpoint
+        } else if self.over.is_some_and(|(pc, sp)| chip.pc == pc && chip.sp == sp) {
+            Some(String::new())
+        } else if self.frame.is_some_and(|frame| chip.frames > frame) {
+            Some(format!("end of frame {}", chip.frames - 1))
+        } else {
+            None
+        };
+        if let Some(reason) = reason {
+            self.pause = false;
+            self.steps = None;
+            self.over = None;
+            self.frame = None;
+            if !reason.is_empty() {
+                println!("{}", reason);
+            }
+            where_is(chip);
+            self.prompt(chip);
+        }
+        if !self.watches.is_empty() {
+            self.before = Some(self.before(chip));
+        }
+    }
+
+    // Called after every instruction, sees what it did to the watches
+    fn after(&mut self, chip: &Chip8) {
+        let before = match self.before.take() {
+            Some(before) => before,
+            None => return,
+        };
+        let mut hits = Vec::new();
+        let mut memory = before.memory.iter();
+        for watch in &self.watches {
+            match watch {
+                Watch::Memory { range, read, write } => {
+                    let old = memory.next().unwrap();
+                    if *read && overlaps(range, &before.reads) {
+                        let from = range.start.max(before.reads.start);
+                        let to = range.end.min(before.reads.end);
+                        hits.push(format!("read {:04X}-{:04X}: {}", from, to - 1, hex(&chip.memory[from..to])));
+                    }
+                    if *write && overlaps(range, &before.writes) {
+                        let from = range.start.max(before.writes.start);
+                        let to = range.end.min(before.writes.end);
+                        let offset = from - range.start..to - range.start;
+                        hits.push(format!(
+                            "write {:04X}-{:04X}: {} -> {}",
+                            from,
+                            to - 1,
+                            hex(&old[offset]),
+                            hex(&chip.memory[from..to])
+                        ));
+                    }
+                }
+                Watch::V(x) if before.v[*x] != chip.V[*x] => {
+                    hits.push(format!("V{:X}: {:02X} -> {:02X}", x, before.v[*x], chip.V[*x]));
+                }
+                Watch::I if before.i != chip.I => hits.push(format!("I: {:04X} -> {:04X}", before.i, chip.I)),
+                _ => {}
+            }
+        }
+        if !hits.is_empty() {
+            hits.push(format!("by the instruction at {:04X}", before.pc));
+            self.hit = Some(hits.join("\n"));
+        }
+    }
+}
+
+// Where the machine stopped
+fn where_is(chip: &Chip8) {
+    let pc = chip.pc as usize;
+    match chip.memory.get(pc..pc + 2) {
+        Some(word) => {
+            let opcode = (word[0] as u16) << 8 | word[1] as u16;
+            let text = Instruction::decode(opcode).and_then(|i| i.octo(&|a| format!("0x{:03X}", a)));
+            println!("{:04X}: {:04X}  {}", pc, opcode, text.unwrap_or_default());
+        }
+        None => println!("{:04X}: outside memory", pc),
+    }
+}
+
+// Splits a log message at its {EXPR}s
+fn parts(text: &str) -> Result<Vec<Part>, String> {
+    let mut parts = Vec::new();
+    let mut rest = text;
+    while let Some(open) = rest.find('{') {
+        let close = rest[open..].find('}').ok_or_else(|| format!("no }} after {}", &rest[open..]))?;
+        parts.push(Part::Text(rest[..open].to_string()));
+        parts.push(Part::Value(Expr::parse(&rest[open + 1..open + close])?));
+        rest = &rest[open + close + 1..];
+    }
+    parts.push(Part::Text(rest.to_string()));
+    Ok(parts)
+}
+
+fn message(parts: &[Part], chip: &Chip8) -> String {
+    parts
+        .iter()
+        .map(|part| match part {
+            Part::Text(text) => text.clone(),
+            Part::Value(expr) => match expr.eval(chip) {
+                Ok(value) => format!("{:X}", value),
+                Err(e) => format!("<{}>", e),
+            },
+        })
+        .collect()
+}
+
+// Memory the instruction at pc is about to read and write
+fn accesses(chip: &Chip8) -> (Range<usize>, Range<usize>) {
+    let pc = chip.pc as usize;
+    let i = chip.I as usize;
+    let opcode = match chip.memory.get(pc..pc + 2) {
+        Some(word) => (word[0] as u16) << 8 | word[1] as u16,
+        None => return (0..0, 0..0),
+    };
+    match Instruction::decode(opcode) {
+        // a hires sprite is 15 rows of two bytes
+        Some(Instruction::Draw(_, _, 0)) if chip.hgr => (i..i + 30, 0..0),
+        Some(Instruction::Draw(_, _, n)) => (i..i + n as usize, 0..0),
+        Some(Instruction::Read(x)) => (i..i + x as usize + 1, 0..0),
+        Some(Instruction::Store(x)) => (0..0, i..i + x as usize + 1),
+        Some(Instruction::Bcd(_)) => (0..0, i..i + 3),
+        // an audio pattern is 16 bytes
+        Some(Instruction::Pattern) => (i..i + 16, 0..0),
+        _ => (0..0, 0..0),
+    }
+}
+
+fn overlaps(a: &Range<usize>, b: &Range<usize>) -> bool {
+    a.start < b.end && b.start < a.end
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02X}", b)).collect::<Vec<_>>().join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::machine;
+
+    // A debugger with nobody typing
+    fn debugger() -> Debugger {
+        Debugger::reading(channel().1)
+    }
+
+    // A running debugger watching what each command asks for
+    fn watching(chip: &mut Chip8, commands: &[&str]) -> Debugger {
+        let mut debugger = debugger();
+        debugger.pause = false;
+        for command in commands {
+            assert_eq!(debugger.command(chip, command), Ok(false), "{}", command);
+        }
+        debugger
+    }
+
+    // Runs one instruction under the debugger, what its watches saw
+    fn step(debugger: &mut Debugger, chip: &mut Chip8) -> Option<String> {
+        debugger.check(chip);
+        chip.emulate_cycle();
+        debugger.after(chip);
+        debugger.hit.take()
+    }
+
+    #[test]
+    fn addresses_and_values_must_fit() {
+        let (mut chip, _) = machine(&[]);
+        let mut debugger = debugger();
+        let error = |text: &str| Err(String::from(text));
+        assert_eq!(debugger.command(&mut chip, "break 10200"), error("10200 is past the end of memory"));
+        assert!(debugger.breakpoints.is_empty());
+        assert_eq!(debugger.command(&mut chip, "b 0x2a0"), Ok(false));
+        assert_eq!(debugger.breakpoints[0].address, 0x2A0);
+        assert_eq!(debugger.command(&mut chip, "set i 12345"), error("i goes up to FFFF"));
+        assert_eq!(debugger.command(&mut chip, "set pc fff"), error("pc goes up to FFE"));
+        assert_eq!(debugger.command(&mut chip, "set 300 1 100"), error("100 is more than a byte"));
+        assert_eq!((chip.I, chip.pc, chip.memory[0x300]), (0, 0x200, 0));
+    }
+
+    #[test]
+    fn watch_writes() {
+        // i := 0x300, v0 := 0x42, v1 := 7, save v1, bcd v0
+        let program = [0xA3, 0x00, 0x60, 0x42, 0x61, 0x07, 0xF1, 0x55, 0xF0, 0x33];
+        let (mut chip, _) = machine(&program);
+        let mut debugger = watching(&mut chip, &["watch 301", "watch 303-310 w", "watch 300 r"]);
+        for _ in 0..3 {
+            assert_eq!(step(&mut debugger, &mut chip), None);
+        }
+        // save v1 writes 300 and 301 only
+        let hit = "write 0301-0301: 00 -> 07\nby the instruction at 0206";
+        assert_eq!(step(&mut debugger, &mut chip).as_deref(), Some(hit));
+        // i moved on to 302, the digits go to 302-304
+        let hit = "write 0303-0304: 00 00 -> 06 06\nby the instruction at 0208";
+        assert_eq!(step(&mut debugger, &mut chip).as_deref(), Some(hit));
+    }
+
+    #[test]
+    fn watch_reads() {
+        // i := 0x300, load v2, i := 0x310, audio
+        let program = [0xA3, 0x00, 0xF2, 0x65, 0xA3, 0x10, 0xF0, 0x02];
+        let (mut chip, _) = machine(&program);
+        chip.memory[0x300..0x304].copy_from_slice(&[1, 2, 3, 4]);
+        chip.memory[0x31F] = 0xAA;
+        let mut debugger = watching(&mut chip, &["watch 302-303 r", "watch 31f-320 rw", "watch 300 w"]);
+        assert_eq!(step(&mut debugger, &mut chip), None);
+        let hit = "read 0302-0302: 03\nby the instruction at 0202";
+        assert_eq!(step(&mut debugger, &mut chip).as_deref(), Some(hit));
+        assert_eq!(step(&mut debugger, &mut chip), None);
+        // the pattern is the 16 bytes from i
+        let hit = "read 031F-031F: AA\nby the instruction at 0206";
+        assert_eq!(step(&mut debugger, &mut chip).as_deref(), Some(hit));
+    }
+
+    #[test]
+    fn watch_registers() {
+        // v3 := 9, v3 := 9, i := 0x234, v3 += 1
+        let program = [0x63, 0x09, 0x63, 0x09, 0xA2, 0x34, 0x73, 0x01];
+        let (mut chip, _) = machine(&program);
+        let mut debugger = watching(&mut chip, &["watch v3", "watch i"]);
+        assert_eq!(step(&mut debugger, &mut chip).as_deref(), Some("V3: 00 -> 09\nby the instruction at 0200"));
+        // setting it to what it was is no change
+        assert_eq!(step(&mut debugger, &mut chip), None);
+        assert_eq!(step(&mut debugger, &mut chip).as_deref(), Some("I: 0000 -> 0234\nby the instruction at 0204"));
+        assert_eq!(step(&mut debugger, &mut chip).as_deref(), Some("V3: 09 -> 0A\nby the instruction at 0206"));
+    }
+
+    #[test]
+    fn watch_ranges() {
+        let (mut chip, _) = machine(&[]);
+        let mut debugger = debugger();
+        let error = |text: &str| Err(String::from(text));
+        assert_eq!(debugger.command(&mut chip, "watch 310-300"), error("310-300 is not a range in memory"));
+        assert_eq!(debugger.command(&mut chip, "watch ff0-1000"), error("ff0-1000 is not a range in memory"));
+        assert_eq!(debugger.command(&mut chip, "watch vg"), error("no register vg"));
+        assert_eq!(debugger.command(&mut chip, "watch 300 x"), error("watch ADDR[-END] [r|w|rw] or watch REG"));
+        assert!(debugger.watches.is_empty());
+        assert_eq!(debugger.command(&mut chip, "watch ff0-fff rw"), Ok(false));
+        assert_eq!(debugger.command(&mut chip, "unwatch 0"), Ok(false));
+        assert!(debugger.watches.is_empty());
+    }
+}
@@ -0,0 +1,175 @@
+use std::collections::HashMap;
+use std::convert::TryFrom;
+
+use crate::instruction::Instruction;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Syntax {
+    Octo,
+    Classic,
+}
+
+impl Syntax {
+    pub fn parse(name: &str) -> Option<Syntax> {
+        match name {
+            "octo" => Some(Syntax::Octo),
+            "classic" => Some(Syntax::Classic),
+            _ => None,
+        }
+    }
+}
+
+//
+// A listing of a ROM loaded at 0x200: address, the bytes and what they
+// mean, one word at a time. Words that don't decode are data. Jump and
+// call targets inside the ROM get labels, sub_ for calls and label_ for
+// jumps.
+//
+//   0200  2206  :call sub_206
+//   0202  1200  jump label_200
+//
+pub fn listing(rom: &[u8], syntax: Syntax) -> String {
+    let start = 0x200;
+    // a file bigger than memory runs on past 0xFFFF
+    let end = start + rom.len();
+    let word = |offset: usize| (rom[offset] as u16) << 8 | rom[offset + 1] as u16;
+    let mut labels = HashMap::new();
+    for offset in (0..rom.len() / 2 * 2).step_by(2) {
+        let instruction = Instruction::decode(word(offset));
+        if let Some(target) = instruction.and_then(|i| i.target()) {
+            if (start..end).contains(&(target as usize)) && target % 2 == 0 {
+                let name = match instruction {
+                    Some(Instruction::Call(_)) => format!("sub_{:03X}", target),
+                    _ => format!("label_{:03X}", target),
+                };
+                // calls win, sub_ says more than label_
+                let old = labels.entry(target).or_insert_with(|| name.clone());
+                if name.starts_with("sub_") {
+                    *old = name;
+                }
+            }
+        }
+    }
+    let name = |address: u16| match labels.get(&address) {
+        Some(label) => label.clone(),
+        None => format!("0x{:03X}", address),
+    };
+    let mut out = String::new();
+    for offset in (0..rom.len()).step_by(2) {
+        let address = start + offset;
+        if let Some(label) = u16::try_from(address).ok().and_then(|a| labels.get(&a)) {
+            match syntax {
+                Syntax::Octo => out += &format!(": {}\n", label),
+                Syntax::Classic => out += &format!("{}:\n", label),
+            }
+        }
+        if offset + 1 == rom.len() {
+            let byte = rom[offset];
+            let text = match syntax {
+                Syntax::Octo => format!("0x{:02X}", byte),
+                Syntax::Classic => format!("DB 0x{:02X}", byte),
+            };
+            out += &format!("{:04X}  {:02X}    {}\n", address, byte, text);
+            break;
+        }
+        let opcode = word(offset);
+        let instruction = Instruction::decode(opcode);
+        let text = match (syntax, instruction) {
+            (Syntax::Octo, Some(i)) => i.octo(&name),
+            (Syntax::Classic, Some(i)) => Some(i.classic(&name)),
+            (_, None) => None,
+        };
+        let text = text.unwrap_or_else(|| match syntax {
+            Syntax::Octo => format!("0x{:02X} 0x{:02X}", opcode >> 8, opcode & 0xFF),
+            Syntax::Classic => format!("DW 0x{:04X}", opcode),
+        });
+        out += &format!("{:04X}  {:04X}  {}\n", address, opcode, text);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ROM: &[u8] = &[
+        0x22, 0x08, // :call sub_208
+        0x12, 0x00, // jump label_200
+        0x13, 0x00, // out of the ROM
+        0x50, 0x01, // not an instruction
+        0xF0, 0x02, 0xF3, 0x3A, 0x00, 0xEE, //
+        0xAB, // odd byte at the end
+    ];
+
+    #[test]
+    fn octo() {
+        let expected = "\
+: label_200
+0200  2208  :call sub_208
+0202  1200  jump label_200
+0204  1300  jump 0x300
+0206  5001  0x50 0x01
+: sub_208
+0208  F002  audio
+020A  F33A  pitch := v3
+020C  00EE  return
+020E  AB    0xAB
+";
+        assert_eq!(listing(ROM, Syntax::Octo), expected);
+    }
+
+    #[test]
+    fn classic() {
+        let expected = "\
+label_200:
+0200  2208  CALL sub_208
+0202  1200  JP label_200
+0204  1300  JP 0x300
+0206  5001  DW 0x5001
+sub_208:
+0208  F002  LD AUDIO, [I]
+020A  F33A  LD PITCH, V3
+020C  00EE  RET
+020E  AB    DB 0xAB
+";
+        assert_eq!(listing(ROM, Syntax::Classic), expected);
+    }
+
+    #[test]
+    fn labels() {
+        // a jump and a call to the same place, and a jump to an odd address
+        let rom = [0x12, 0x04, 0x22, 0x04, 0x12, 0x03];
+        let expected = "\
+0200  1204  jump sub_204
+0202  2204  :call sub_204
+: sub_204
+0204  1203  jump 0x203
+";
+        assert_eq!(listing(&rom, Syntax::Octo), expected);
+    }
+
+    #[test]
+    fn skips_read_as_conditions() {
+        let rom = [0x31, 0x05, 0x42, 0xFF, 0x53, 0x40, 0x96, 0x70, 0xE1, 0x9E, 0xE2, 0xA1];
+        let lines: Vec<String> = listing(&rom, Syntax::Octo).lines().map(|l| l[12..].to_string()).collect();
+        let expected = [
+            "if v1 != 0x05 then",
+            "if v2 == 0xFF then",
+            "if v3 != v4 then",
+            "if v6 == v7 then",
+            "if v1 -key then",
+            "if v2 key then",
+        ];
+        assert_eq!(lines, expected);
+    }
+
+    #[test]
+    fn bigger_than_memory() {
+        let rom: Vec<u8> = [0x00, 0xE0].repeat(0x8000);
+        let listing = listing(&rom, Syntax::Octo);
+        assert_eq!(listing.lines().count(), 0x8000);
+        assert_eq!(listing.lines().last(), Some("101FE  00E0  clear"));
+        assert_eq!(Syntax::parse("octo"), Some(Syntax::Octo));
+        assert_eq!(Syntax::parse("intel"), None);
+    }
+}
@@ -0,0 +1,313 @@
+use std::convert::TryFrom;
+
+use crate::Chip8;
+
+//
+// Expressions over the machine for breakpoint conditions and log messages,
+// with C's operators and precedence:
+//
+//   V3 == 0x10 && I > 0x300
+//   mem[0x2F0] != 0
+//   (VF | V0) & 1
+//
+// Names are V0-VF, I, PC, SP, DT, ST and FRAME, in any case. Numbers are
+// decimal unless they start with 0x. Comparisons and ! give 1 or 0.
+//
+#[derive(Clone, Debug)]
+pub enum Expr {
+    Number(i64),
+    V(usize),
+    I,
+    Pc,
+    Sp,
+    Delay,
+    Sound,
+    Frame,
+    Memory(Box<Expr>),
+    Not(Box<Expr>),
+    Negate(Box<Expr>),
+    Binary(Op, Box<Expr>, Box<Expr>),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Op {
+    Or,
+    And,
+    Equal,
+    NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+    BitOr,
+    BitXor,
+    BitAnd,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+}
+
+// Loosest first, each level's operators with their spelling
+const LEVELS: &[&[(&str, Op)]] = &[
+    &[("||", Op::Or)],
+    &[("&&", Op::And)],
+    &[("==", Op::Equal), ("!=", Op::NotEqual)],
+    &[("<=", Op::LessEqual), (">=", Op::GreaterEqual), ("<", Op::Less), (">", Op::Greater)],
+    &[("|", Op::BitOr)],
+    &[("^", Op::BitXor)],
+    &[("&", Op::BitAnd)],
+    &[("+", Op::Add), ("-", Op::Sub)],
+    &[("*", Op::Mul), ("/", Op::Div), ("%", Op::Rem)],
+];
+
+impl Expr {
+    pub fn parse(text: &str) -> Result<Expr, String> {
+        let tokens = tokens(text)?;
+        let mut parser = Parser { tokens, next: 0 };
+        let expr = parser.level(0)?;
+        match parser.tokens.get(parser.next) {
+            None => Ok(expr),
+            Some(token) => Err(format!("unexpected {} in {}", token, text)),
+        }
+    }
+
+    pub fn eval(&self, chip: &Chip8) -> Result<i64, String> {
+        let value = match self {
+            Expr::Number(n) => *n,
+            Expr::V(x) => chip.V[*x] as i64,
+            Expr::I => chip.I as i64,
+            Expr::Pc => chip.pc as i64,
+            Expr::Sp => chip.sp as i64,
+            Expr::Delay => chip.delay_timer as i64,
+            Expr::Sound => chip.sound_timer as i64,
+            Expr::Frame => chip.frames as i64,
+            Expr::Memory(address) => {
+                let address = address.eval(chip)?;
+                match usize::try_from(address).ok().and_then(|a| chip.memory.get(a)) {
+                    Some(byte) => *byte as i64,
+                    None => return Err(format!("mem[{:#X}] is outside memory", address)),
+                }
+            }
+            Expr::Not(e) => (e.eval(chip)? == 0) as i64,
+            Expr::Negate(e) => e.eval(chip)?.wrapping_neg(),
+            // && and || only look at the right when they need to
+            Expr::Binary(Op::And, a, b) => (a.eval(chip)? != 0 && b.eval(chip)? != 0) as i64,
+            Expr::Binary(Op::Or, a, b) => (a.eval(chip)? != 0 || b.eval(chip)? != 0) as i64,
+            Expr::Binary(op, a, b) => {
+                let (a, b) = (a.eval(chip)?, b.eval(chip)?);
+                match op {
+                    Op::Equal => (a == b) as i64,
+                    Op::NotEqual => (a != b) as i64,
+                    Op::Less => (a < b) as i64,
+                    Op::LessEqual => (a <= b) as i64,
+                    Op::Greater => (a > b) as i64,
+                    Op::GreaterEqual => (a >= b) as i64,
+                    Op::BitOr => a | b,
+                    Op::BitXor => a ^ b,
+                    Op::BitAnd => a & b,
+                    Op::Add => a.wrapping_add(b),
+                    Op::Sub => a.wrapping_sub(b),
+                    Op::Mul => a.wrapping_mul(b),
+                    Op::Div | Op::Rem if b == 0 => return Err(String::from("division by zero")),
+                    Op::Div => a.wrapping_div(b),
+                    Op::Rem => a.wrapping_rem(b),
+                    Op::And | Op::Or => unreachable!(),
+                }
+            }
+        };
+        Ok(value)
+    }
+}
+
+// Splits text into numbers, names and operators
+fn tokens(text: &str) -> Result<Vec<String>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = text.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c.is_ascii_alphanumeric() || c == '_' {
+            let mut word = String::new();
+            while let Some(&c) = chars.peek().filter(|c| c.is_ascii_alphanumeric() || **c == '_') {
+                word.push(c);
+                chars.next();
+            }
+            tokens.push(word);
+        } else {
+            chars.next();
+            let pair: String = [c].iter().chain(chars.peek()).collect();
+            if ["||", "&&", "==", "!=", "<=", ">="].contains(&pair.as_str()) {
+                chars.next();
+                tokens.push(pair);
+            } else if "|^&+-*/%<>!()[]".contains(c) {
+                tokens.push(c.to_string());
+            } else {
+                return Err(format!("unexpected {} in {}", c, text));
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<String>,
+    next: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&str> {
+        self.tokens.get(self.next).map(String::as_str)
+    }
+
+    fn token(&mut self) -> Result<String, String> {
+        let token = self.tokens.get(self.next).cloned();
+        self.next += 1;
+        token.ok_or_else(|| String::from("expression ends too soon"))
+    }
+
+    fn expect(&mut self, text: &str) -> Result<(), String> {
+        match self.token()? {
+            token if token == text => Ok(()),
+            token => Err(format!("expected {}, found {}", text, token)),
+        }
+    }
+
+    fn level(&mut self, level: usize) -> Result<Expr, String> {
+        let ops = match LEVELS.get(level) {
+            Some(ops) => ops,
+            None => return self.unary(),
+        };
+        let mut left = self.level(level + 1)?;
+        while let Some(&(_, op)) = ops.iter().find(|(text, _)| Some(*text) == self.peek()) {
+            self.next += 1;
+            let right = self.level(level + 1)?;
+            left = Expr::Binary(op, Box::new(left), Box::new(right));
+        }
+        Ok(left)
+    }
+
+    fn unary(&mut self) -> Result<Expr, String> {
+        let token = self.token()?;
+        let expr = match token.to_ascii_uppercase().as_str() {
+            "!" => Expr::Not(Box::new(self.unary()?)),
+            "-" => Expr::Negate(Box::new(self.unary()?)),
+            "(" => {
+                let expr = self.level(0)?;
+                self.expect(")")?;
+                expr
+            }
+            "MEM" => {
+                self.expect("[")?;
+                let address = self.level(0)?;
+                self.expect("]")?;
+                Expr::Memory(Box::new(address))
+            }
+            "I" => Expr::I,
+            "PC" => Expr::Pc,
+            "SP" => Expr::Sp,
+            "DT" => Expr::Delay,
+            "ST" => Expr::Sound,
+            "FRAME" => Expr::Frame,
+            v if v.len() == 2 && v.starts_with('V') && v.as_bytes()[1].is_ascii_hexdigit() => {
+                Expr::V(usize::from_str_radix(&v[1..], 16).unwrap())
+            }
+            _ => {
+                let number = match token.strip_prefix("0x").or_else(|| token.strip_prefix("0X")) {
+                    Some(hex) => i64::from_str_radix(hex, 16),
+                    None => token.parse(),
+                };
+                Expr::Number(number.map_err(|_| format!("don't know {}", token))?)
+            }
+        };
+        Ok(expr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::machine;
+
+    fn eval(text: &str, chip: &Chip8) -> Result<i64, String> {
+        Expr::parse(text)?.eval(chip)
+    }
+
+    #[test]
+    fn precedence() {
+        let (chip, _) = machine(&[]);
+        let cases = [
+            ("1 + 2 * 3", 7),
+            ("(1 + 2) * 3", 9),
+            ("10 - 4 - 3", 3),
+            ("7 / 2 % 2", 1),
+            ("1 | 2 ^ 3 & 6", 1),
+            ("1 + 1 == 2 && 3 < 2 || 4 >= 4", 1),
+            ("1 < 2 == 1", 1),
+            ("!0 + -3", -2),
+            ("!!5", 1),
+            ("- -0x10", 16),
+        ];
+        for (text, expected) in cases.iter() {
+            assert_eq!(eval(text, &chip), Ok(*expected), "{}", text);
+        }
+    }
+
+    #[test]
+    fn names() {
+        let (mut chip, _) = machine(&[0x12, 0x34]);
+        chip.V[3] = 0x10;
+        chip.V[0xF] = 1;
+        chip.I = 0x300;
+        chip.memory[0x2F0] = 7;
+        chip.delay_timer = 5;
+        chip.sound_timer = 6;
+        chip.frames = 99;
+        let cases = [
+            ("V3 == 0x10 && I > 0x2FF", 1),
+            ("vf + v3", 0x11),
+            ("mem[0x2F0] != 0", 1),
+            ("MEM[I - 0x10] * 2", 14),
+            ("PC", 0x200),
+            ("sp", 0),
+            ("dt + st", 11),
+            ("Frame", 99),
+            ("0X1f", 31),
+        ];
+        for (text, expected) in cases.iter() {
+            assert_eq!(eval(text, &chip), Ok(*expected), "{}", text);
+        }
+        assert_eq!(eval("mem[PC] * 0x100 + mem[PC + 1]", &chip), Ok(0x1234));
+    }
+
+    #[test]
+    fn errors() {
+        let (chip, _) = machine(&[]);
+        let cases = [
+            ("1 +", "expression ends too soon"),
+            ("(1 + 2", "expression ends too soon"),
+            ("1 2", "unexpected 2 in 1 2"),
+            ("V0 = 1", "unexpected = in V0 = 1"),
+            ("mem 5", "expected [, found 5"),
+            ("(1]", "expected ), found ]"),
+            ("VG", "don't know VG"),
+            ("0x", "don't know 0x"),
+            ("1 / (V0 - V0)", "division by zero"),
+            ("5 % 0", "division by zero"),
+            ("mem[0x1000]", "mem[0x1000] is outside memory"),
+            ("mem[-1]", "mem[0xFFFFFFFFFFFFFFFF] is outside memory"),
+        ];
+        for (text, expected) in cases.iter() {
+            assert_eq!(eval(text, &chip), Err(String::from(*expected)), "{}", text);
+        }
+    }
+
+    #[test]
+    fn short_circuit() {
+        let (chip, _) = machine(&[]);
+        assert_eq!(eval("0 && 1 / 0", &chip), Ok(0));
+        assert_eq!(eval("1 || mem[0x5000]", &chip), Ok(1));
+        assert_eq!(eval("1 && 1 / 0", &chip), Err(String::from("division by zero")));
+    }
+}
@@ -0,0 +1,172 @@
+use crate::hud::Status;
+use crate::{Hotkey, Screen};
+
+// Phosphor brightness kept from one 60 Hz frame to the next, out of 256
+const DECAY: u32 = 160;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FilterMode {
+    None,
+    // a pixel is lit if it was lit in either of the last two frames
+    Or2,
+    // lit pixels glow and fade out over a few frames
+    Phosphor,
+}
+
+impl FilterMode {
+    pub fn parse(text: &str) -> Option<FilterMode> {
+        match text {
+            "none" => Some(FilterMode::None),
+            "or2" => Some(FilterMode::Or2),
+            "phosphor" => Some(FilterMode::Phosphor),
+            _ => None,
+        }
+    }
+}
+
+//
+// Sits between Chip8 and a Screen to hide the flicker of sprites being
+// erased and redrawn with XOR. Works on the 60 Hz frames, the screen
+// behind it sees a filtered draw whenever the picture changes.
+//
+pub struct Filter<S: Screen> {
+    screen: S,
+    mode: FilterMode,
+    previous: [u8; 64 * 32],
+    glow: [u8; 64 * 32],
+    shown: [u8; 64 * 32],
+}
+
+impl<S: Screen> Filter<S> {
+    pub fn new(screen: S, mode: FilterMode) -> Self {
+        Filter {
+            screen,
+            mode,
+            previous: [0; 64 * 32],
+            glow: [0; 64 * 32],
+            shown: [0; 64 * 32],
+        }
+    }
+
+    // What the next frame shows for gfx, without moving the filter on
+    fn filtered(&self, gfx: &[u8; 64 * 32]) -> [u8; 64 * 32] {
+        let mut out = *gfx;
+        match self.mode {
+            FilterMode::None => {}
+            FilterMode::Or2 => {
+                for (i, pixel) in out.iter_mut().enumerate() {
+                    *pixel = gfx[i].max(self.previous[i]);
+                }
+            }
+            FilterMode::Phosphor => {
+                for (i, pixel) in out.iter_mut().enumerate() {
+                    let faded = (self.glow[i] as u32 * DECAY / 256) as u8;
+                    *pixel = gfx[i].max(faded);
+                }
+            }
+        }
+        out
+    }
+
+    fn apply(&mut self, gfx: &[u8; 64 * 32]) -> [u8; 64 * 32] {
+        let out = self.filtered(gfx);
+        self.glow = out;
+        self.previous = *gfx;
+        out
+    }
+
+    fn show(&mut self, out: [u8; 64 * 32]) {
+        if out[..] != self.shown[..] {
+            self.screen.draw(&out);
+            self.shown = out;
+        }
+    }
+}
+
+impl<S: Screen> Screen for Filter<S> {
+    // Shows what the coming frame will, so that frame draws nothing more
+    // and a last draw after the final frame still gets through
+    fn draw(&mut self, gfx: &[u8; 64 * 32]) {
+        let out = self.filtered(gfx);
+        self.show(out);
+    }
+    fn frame(&mut self, gfx: &[u8; 64 * 32]) {
+        let out = self.apply(gfx);
+        self.show(out);
+        self.screen.frame(&out);
+    }
+    fn hotkey(&mut self, key: Hotkey) {
+        self.screen.hotkey(key);
+    }
+    fn status(&mut self, status: &Status) {
+        self.screen.status(status);
+    }
+    fn shows_status(&self) -> bool {
+        self.screen.shows_status()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    // Keeps every picture it is asked to draw
+    #[derive(Clone, Default)]
+    struct Shots(Rc<RefCell<Vec<Vec<u8>>>>);
+
+    impl Screen for Shots {
+        fn draw(&mut self, gfx: &[u8; 64 * 32]) {
+            self.0.borrow_mut().push(gfx.to_vec());
+        }
+    }
+
+    fn lit(pixels: &[usize]) -> [u8; 64 * 32] {
+        let mut gfx = [0; 64 * 32];
+        for pixel in pixels {
+            gfx[*pixel] = 0xFF;
+        }
+        gfx
+    }
+
+    #[test]
+    fn or2_keeps_the_last_frame_lit() {
+        let shots = Shots::default();
+        let mut filter = Filter::new(shots.clone(), FilterMode::Or2);
+        for gfx in [lit(&[0]), lit(&[1]), lit(&[1])].iter() {
+            filter.frame(gfx);
+        }
+        let expected = [lit(&[0]).to_vec(), lit(&[0, 1]).to_vec(), lit(&[1]).to_vec()];
+        assert_eq!(*shots.0.borrow(), expected);
+    }
+
+    #[test]
+    fn phosphor_fades() {
+        let shots = Shots::default();
+        let mut filter = Filter::new(shots.clone(), FilterMode::Phosphor);
+        filter.frame(&lit(&[5]));
+        for _ in 0..3 {
+            filter.frame(&lit(&[]));
+        }
+        let glow: Vec<u8> = shots.0.borrow().iter().map(|shot| shot[5]).collect();
+        // 160/256 of the last frame's brightness each frame
+        assert_eq!(glow, [0xFF, 159, 99, 61]);
+        // relit at full brightness
+        filter.frame(&lit(&[5]));
+        assert_eq!(shots.0.borrow().last().map(|shot| shot[5]), Some(0xFF));
+    }
+
+    #[test]
+    fn draws_are_sent_once() {
+        let shots = Shots::default();
+        let mut filter = Filter::new(shots.clone(), FilterMode::Or2);
+        filter.draw(&lit(&[0]));
+        filter.frame(&lit(&[0]));
+        assert_eq!(shots.0.borrow().len(), 1);
+        // a last draw with no frame after it
+        filter.draw(&lit(&[0, 2]));
+        assert_eq!(shots.0.borrow().last(), Some(&lit(&[0, 2]).to_vec()));
+        assert_eq!(shots.0.borrow().len(), 2);
+    }
+}
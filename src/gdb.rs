@@ -0,0 +1,528 @@
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::sync::mpsc::{channel, Receiver};
+
+use crate::common::listen_local;
+use crate::{Chip8, Hook};
+
+//
+// The GDB remote serial protocol over TCP, for --gdb 127.0.0.1:1234. The
+// machine waits before its first instruction for a client to connect and
+// stops again whenever a new one does. Ctrl-C from the client interrupts it.
+// https://sourceware.org/gdb/current/onlinedocs/gdb.html/Remote-Protocol.html
+//
+// Registers are numbered V0-VF 0-15, I 16, PC 17, SP 18, DT 19 and ST 20,
+// and go over the wire big endian like CHIP-8 memory. target.xml describes
+// them to clients that ask. Memory is the 4K of the machine, and PC has to
+// leave room in it for the two bytes of an instruction. Only clients on
+// this machine can connect.
+//
+const TARGET: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.chip8.core">
+    <reg name="v0" bitsize="8" regnum="0"/>
+    <reg name="v1" bitsize="8"/>
+    <reg name="v2" bitsize="8"/>
+    <reg name="v3" bitsize="8"/>
+    <reg name="v4" bitsize="8"/>
+    <reg name="v5" bitsize="8"/>
+    <reg name="v6" bitsize="8"/>
+    <reg name="v7" bitsize="8"/>
+    <reg name="v8" bitsize="8"/>
+    <reg name="v9" bitsize="8"/>
+    <reg name="va" bitsize="8"/>
+    <reg name="vb" bitsize="8"/>
+    <reg name="vc" bitsize="8"/>
+    <reg name="vd" bitsize="8"/>
+    <reg name="ve" bitsize="8"/>
+    <reg name="vf" bitsize="8"/>
+    <reg name="i" bitsize="16" type="data_ptr"/>
+    <reg name="pc" bitsize="16" type="code_ptr"/>
+    <reg name="sp" bitsize="8"/>
+    <reg name="dt" bitsize="8"/>
+    <reg name="st" bitsize="8"/>
+  </feature>
+</target>
+"#;
+
+// register sizes in bytes, by number
+const REGISTERS: [usize; 21] = [1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 2, 2, 1, 1, 1];
+
+enum Message {
+    Connected(TcpStream),
+    Bytes(Vec<u8>),
+    Closed,
+}
+
+pub struct Gdb {
+    messages: Receiver<Message>,
+    client: Option<TcpStream>,
+    // received but not yet a whole packet
+    pending: Vec<u8>,
+    // stopped waiting for the first client
+    waiting: bool,
+    ack: bool,
+    breakpoints: Vec<u16>,
+    stepping: bool,
+    interrupted: bool,
+}
+
+impl Gdb {
+    pub fn listen(address: &str) -> std::io::Result<Self> {
+        let listener = listen_local(address)?;
+        let (send, messages) = channel();
+        std::thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let mut reader = match stream.try_clone() {
+                    Ok(reader) => reader,
+                    Err(_) => continue,
+                };
+                if send.send(Message::Connected(stream)).is_err() {
+                    return;
+                }
+                let mut buffer = [0; 4096];
+                loop {
+                    match reader.read(&mut buffer) {
+                        Ok(n) if n > 0 => {
+                            if send.send(Message::Bytes(buffer[..n].to_vec())).is_err() {
+                                return;
+                            }
+                        }
+                        _ => break,
+                    }
+                }
+                let _ = send.send(Message::Closed);
+            }
+        });
+        Ok(Gdb {
+            messages,
+            client: None,
+            pending: Vec::new(),
+            waiting: true,
+            ack: true,
+            breakpoints: Vec::new(),
+            stepping: false,
+            interrupted: false,
+        })
+    }
+
+    fn receive(&mut self, message: Message) {
+        match message {
+            Message::Connected(stream) => {
+                self.client = Some(stream);
+                self.pending.clear();
+                self.ack = true;
+            }
+            Message::Bytes(bytes) => {
+                // Ctrl-C comes outside any packet
+                if self.pending.is_empty() && bytes.first() == Some(&0x03) {
+                    self.interrupted = true;
+                    self.pending.extend_from_slice(&bytes[1..]);
+                } else {
+                    self.pending.extend_from_slice(&bytes);
+                }
+            }
+            Message::Closed => {
+                self.client = None;
+                self.pending.clear();
+            }
+        }
+    }
+
+    // Answers packets until one resumes the machine or the client goes. The
+    // instruction at pc runs next whatever breakpoint is on it.
+    fn serve(&mut self, chip: &mut Chip8) {
+        loop {
+            while let Some(packet) = self.packet() {
+                if self.command(chip, &packet) {
+                    return;
+                }
+            }
+            match self.messages.recv() {
+                Ok(message) => self.receive(message),
+                Err(_) => return,
+            }
+            if self.client.is_none() {
+                return;
+            }
+        }
+    }
+
+    // The next whole packet in pending, acknowledged. Garbled ones are
+    // answered with - for the client to send again.
+    fn packet(&mut self) -> Option<String> {
+        loop {
+            // acks, stray Ctrl-Cs and noise before the $ mean nothing here
+            let start = self.pending.iter().position(|b| *b == b'$')?;
+            self.pending.drain(..start);
+            let end = self.pending.iter().position(|b| *b == b'#')?;
+            if self.pending.len() < end + 3 {
+                return None;
+            }
+            let packet: Vec<u8> = self.pending.drain(..end + 3).collect();
+            let body = &packet[1..end];
+            let sum = std::str::from_utf8(&packet[end + 1..]).ok().and_then(|s| u8::from_str_radix(s, 16).ok());
+            let good = sum == Some(checksum(body));
+            if self.ack {
+                self.write(if good { b"+" } else { b"-" });
+            }
+            if good {
+                return Some(String::from_utf8_lossy(body).into_owned());
+            }
+        }
+    }
+
+    fn send(&mut self, reply: &str) {
+        let packet = format!("${}#{:02x}", reply, checksum(reply.as_bytes()));
+        self.write(packet.as_bytes());
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        if let Some(client) = &mut self.client {
+            if client.write_all(bytes).is_err() {
+                self.client = None;
+            }
+        }
+    }
+
+    // Carries out one packet, true when the machine should run again
+    fn command(&mut self, chip: &mut Chip8, packet: &str) -> bool {
+        let kind = packet.chars().next().map_or(0, char::len_utf8);
+        let reply = match packet.split_at(kind) {
+            ("?", _) => String::from("S05"),
+            ("g", _) => (0..REGISTERS.len()).map(|r| hex(&register(chip, r))).collect(),
+            ("G", values) => match bytes(values) {
+                Some(values) if values.len() == REGISTERS.iter().sum::<usize>() => {
+                    // all of them or none, pc is the one that can be refused
+                    let pc = REGISTERS[..17].iter().sum::<usize>();
+                    if runnable(chip, u16::from_be_bytes([values[pc], values[pc + 1]])) {
+                        let mut at = 0;
+                        for (r, size) in REGISTERS.iter().enumerate() {
+                            set_register(chip, r, &values[at..at + size]);
+                            at += size;
+                        }
+                        String::from("OK")
+                    } else {
+                        String::from("E01")
+                    }
+                }
+                _ => String::from("E01"),
+            },
+            ("p", number) => match usize::from_str_radix(number, 16).ok().filter(|r| *r < REGISTERS.len()) {
+                Some(r) => hex(&register(chip, r)),
+                None => String::from("E01"),
+            },
+            ("P", assignment) => {
+                let parsed = assignment.split_once('=').and_then(|(r, v)| {
+                    let r = usize::from_str_radix(r, 16).ok().filter(|r| *r < REGISTERS.len())?;
+                    Some((r, bytes(v).filter(|v| v.len() == REGISTERS[r])?))
+                });
+                match parsed {
+                    Some((r, value)) if set_register(chip, r, &value) => String::from("OK"),
+                    _ => String::from("E01"),
+                }
+            }
+            ("m", range) => match span(range, chip) {
+                Some((start, end)) => hex(&chip.memory[start..end]),
+                None => String::from("E01"),
+            },
+            ("M", write) => {
+                let parsed = write.split_once(':').and_then(|(range, data)| Some((span(range, chip)?, bytes(data)?)));
+                match parsed {
+                    Some(((start, end), data)) if data.len() == end - start => {
+                        chip.memory[start..end].copy_from_slice(&data);
+                        String::from("OK")
+                    }
+                    _ => String::from("E01"),
+                }
+            }
+            ("Z" | "z", rest) if rest.starts_with('0') || rest.starts_with('1') => {
+                let address = rest.split(',').nth(1).and_then(|a| u16::from_str_radix(a, 16).ok());
+                match address {
+                    Some(address) if packet.starts_with('Z') => {
+                        if !self.breakpoints.contains(&address) {
+                            self.breakpoints.push(address);
+                        }
+                        String::from("OK")
+                    }
+                    Some(address) => {
+                        self.breakpoints.retain(|b| *b != address);
+                        String::from("OK")
+                    }
+                    None => String::from("E01"),
+                }
+            }
+            ("c" | "s", address) => {
+                if !address.is_empty() {
+                    match u16::from_str_radix(address, 16).ok().filter(|pc| runnable(chip, *pc)) {
+                        Some(pc) => chip.pc = pc,
+                        None => {
+                            self.send("E01");
+                            return false;
+                        }
+                    }
+                }
+                self.stepping = packet.starts_with('s');
+                return true;
+            }
+            ("k", _) => {
+                chip.exit();
+                return true;
+            }
+            ("D", _) => {
+                self.send("OK");
+                self.client = None;
+                return true;
+            }
+            ("H", _) => String::from("OK"),
+            _ => return self.query(chip, packet),
+        };
+        self.send(&reply);
+        false
+    }
+
+    // The longer named packets
+    fn query(&mut self, chip: &mut Chip8, packet: &str) -> bool {
+        let reply = match packet {
+            "vCont?" => String::from("vCont;c;s"),
+            "vCont;c" => return self.command(chip, "c"),
+            p if p.starts_with("vCont;s") => return self.command(chip, "s"),
+            "vKill" | "vKill;1" => {
+                self.send("OK");
+                return self.command(chip, "k");
+            }
+            "QStartNoAckMode" => {
+                self.send("OK");
+                self.ack = false;
+                return false;
+            }
+            "qAttached" => String::from("1"),
+            "qC" => String::from("QC1"),
+            "qfThreadInfo" => String::from("m1"),
+            "qsThreadInfo" => String::from("l"),
+            p if p.starts_with("qSupported") => {
+                String::from("PacketSize=4000;qXfer:features:read+;swbreak+;QStartNoAckMode+;vContSupported+")
+            }
+            p if p.starts_with("qXfer:features:read:target.xml:") => {
+                let range = &p["qXfer:features:read:target.xml:".len()..];
+                let (offset, length) = range.split_once(',').unwrap_or((range, "0"));
+                let offset = usize::from_str_radix(offset, 16).unwrap_or(0).min(TARGET.len());
+                let length = usize::from_str_radix(length, 16).unwrap_or(0);
+                let end = offset.saturating_add(length).min(TARGET.len());
+                let more = if end < TARGET.len() { "m" } else { "l" };
+                format!("{}{}", more, &TARGET[offset..end])
+            }
+            // anything else is not supported, which is an empty reply
+            _ => String::new(),
+        };
+        self.send(&reply);
+        false
+    }
+}
+
+impl Hook for Gdb {
+    // Called before every instruction, stops when the client asks to
+    fn check(&mut self, chip: &mut Chip8) {
+        if self.waiting {
+            self.waiting = false;
+            match self.messages.recv() {
+                Ok(message) => self.receive(message),
+                Err(_) => return,
+            }
+            self.serve(chip);
+            return;
+        }
+        let connected = self.client.is_some();
+        while let Ok(message) = self.messages.try_recv() {
+            self.receive(message);
+        }
+        let reason = if self.client.is_none() {
+            None
+        } else if !connected {
+            // a new client finds the machine stopped
+            self.pending.clear();
+            Some(None)
+        } else if let Some(fault) = chip.fault.take() {
+            chip.log(&fault);
+            Some(Some("S0B"))
+        } else if std::mem::take(&mut self.interrupted) {
+            Some(Some("S02"))
+        } else if self.stepping {
+            Some(Some("S05"))
+        } else if self.breakpoints.contains(&chip.pc) {
+            Some(Some("T05swbreak:;"))
+        } else {
+            None
+        };
+        if let Some(reply) = reason {
+            self.stepping = false;
+            if let Some(reply) = reply {
+                self.send(reply);
+            }
+            self.serve(chip);
+        }
+    }
+}
+
+impl Drop for Gdb {
+    fn drop(&mut self) {
+        // the machine has stopped for good
+        self.send("W00");
+    }
+}
+
+fn register(chip: &Chip8, r: usize) -> Vec<u8> {
+    match r {
+        0..=15 => vec![chip.V[r]],
+        16 => chip.I.to_be_bytes().to_vec(),
+        17 => chip.pc.to_be_bytes().to_vec(),
+        18 => vec![chip.sp as u8],
+        19 => vec![chip.delay_timer],
+        _ => vec![chip.sound_timer],
+    }
+}
+
+// false, changing nothing, for a pc that can't run
+fn set_register(chip: &mut Chip8, r: usize, value: &[u8]) -> bool {
+    let word = || u16::from_be_bytes([value[0], value[1]]);
+    match r {
+        0..=15 => chip.V[r] = value[0],
+        16 => chip.I = word(),
+        17 if !runnable(chip, word()) => return false,
+        17 => chip.pc = word(),
+        18 => chip.sp = (value[0] as u16).min(chip.stack.len() as u16),
+        19 => chip.delay_timer = value[0],
+        _ => chip.sound_timer = value[0],
+    }
+    true
+}
+
+// Whether both bytes of the instruction at pc are in memory
+fn runnable(chip: &Chip8, pc: u16) -> bool {
+    (pc as usize) < chip.memory.len() - 1
+}
+
+// ADDR,LENGTH as a range of memory, cut short at its end, None when it
+// starts outside memory or ends past any address
+fn span(range: &str, chip: &Chip8) -> Option<(usize, usize)> {
+    let (start, length) = range.split_once(',')?;
+    let start = usize::from_str_radix(start, 16).ok()?;
+    let end = start.checked_add(usize::from_str_radix(length, 16).ok()?)?;
+    (start < chip.memory.len()).then(|| (start, end.min(chip.memory.len())))
+}
+
+fn checksum(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// Hex pairs to bytes, None for an odd digit out
+fn bytes(text: &str) -> Option<Vec<u8>> {
+    (0..text.len())
+        .step_by(2)
+        .map(|at| u8::from_str_radix(text.get(at..at + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::machine;
+    use std::net::TcpListener;
+
+    // A stub with a client on the other end of a socket
+    fn connected() -> (Gdb, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let mut gdb = Gdb::listen("127.0.0.1:0").unwrap();
+        gdb.client = Some(listener.accept().unwrap().0);
+        (gdb, client)
+    }
+
+    fn reply(gdb: &mut Gdb, client: &mut TcpStream, chip: &mut Chip8, packet: &str) -> String {
+        gdb.command(chip, packet);
+        let mut reply = Vec::new();
+        let mut byte = [0];
+        while !reply.ends_with(b"#") {
+            client.read_exact(&mut byte).unwrap();
+            reply.push(byte[0]);
+        }
+        client.read_exact(&mut [0; 2]).unwrap();
+        String::from_utf8(reply[1..reply.len() - 1].to_vec()).unwrap()
+    }
+
+    #[test]
+    fn memory() {
+        let (mut chip, _) = machine(&[0x12, 0x34, 0x56]);
+        let (mut gdb, mut client) = connected();
+        let mut reply = |chip: &mut Chip8, packet| reply(&mut gdb, &mut client, chip, packet);
+        assert_eq!(reply(&mut chip, "m200,3"), "123456");
+        // cut short at the end of memory
+        assert_eq!(reply(&mut chip, "mffe,4"), "0000");
+        assert_eq!(reply(&mut chip, "m1000,1"), "E01");
+        assert_eq!(reply(&mut chip, "m1,ffffffffffffffff"), "E01");
+        assert_eq!(reply(&mut chip, "m1,10000000000000000"), "E01");
+        assert_eq!(reply(&mut chip, "m200"), "E01");
+        assert_eq!(reply(&mut chip, "M201,2:abcd"), "OK");
+        assert_eq!(chip.memory[0x200..0x204], [0x12, 0xAB, 0xCD, 0x00]);
+        assert_eq!(reply(&mut chip, "M1,ffffffffffffffff:ab"), "E01");
+        assert_eq!(reply(&mut chip, "Mfff,2:abcd"), "E01");
+        assert_eq!(reply(&mut chip, "M200,2:ab"), "E01");
+        assert_eq!(chip.memory[0xFFF], 0);
+    }
+
+    #[test]
+    fn target_description() {
+        let (mut chip, _) = machine(&[]);
+        let (mut gdb, mut client) = connected();
+        let packet = "qXfer:features:read:target.xml:0,ffffffffffffffff";
+        assert_eq!(reply(&mut gdb, &mut client, &mut chip, packet), format!("l{}", TARGET));
+        let packet = "qXfer:features:read:target.xml:5,3";
+        assert_eq!(reply(&mut gdb, &mut client, &mut chip, packet), format!("m{}", &TARGET[5..8]));
+    }
+
+    #[test]
+    fn pc_stays_in_memory() {
+        let (mut chip, _) = machine(&[]);
+        let (mut gdb, mut client) = connected();
+        let mut reply = |chip: &mut Chip8, packet| reply(&mut gdb, &mut client, chip, packet);
+        assert_eq!(reply(&mut chip, "P11=0fff"), "E01");
+        assert_eq!(reply(&mut chip, "P11=ffff"), "E01");
+        assert_eq!(reply(&mut chip, "c fff"), "E01");
+        assert_eq!(reply(&mut chip, "sfff"), "E01");
+        assert_eq!(reply(&mut chip, "cxyz"), "E01");
+        let far = format!("G{}0300{}000000", "00".repeat(16), "0fff");
+        assert_eq!(reply(&mut chip, &far), "E01");
+        assert_eq!(chip.I, 0);
+        assert_eq!(chip.pc, 0x200);
+        assert_eq!(reply(&mut chip, "P11=0ffe"), "OK");
+        assert_eq!(chip.pc, 0xFFE);
+        chip.emulate_cycle();
+        let near = format!("G{}0300{}000000", "00".repeat(16), "0240");
+        assert_eq!(reply(&mut chip, &near), "OK");
+        assert_eq!((chip.I, chip.pc), (0x300, 0x240));
+    }
+
+    #[test]
+    fn continue_from_an_address() {
+        let (mut chip, _) = machine(&[]);
+        let (mut gdb, _client) = connected();
+        assert!(gdb.command(&mut chip, "c2a0"));
+        assert_eq!(chip.pc, 0x2A0);
+        assert!(gdb.command(&mut chip, "sffe"));
+        assert_eq!(chip.pc, 0xFFE);
+        assert!(gdb.stepping);
+        assert!(gdb.command(&mut chip, "c"));
+        assert_eq!(chip.pc, 0xFFE);
+    }
+
+    #[test]
+    fn local_clients_only() {
+        let e = Gdb::listen("0.0.0.0:0").err().unwrap();
+        assert_eq!(e.kind(), std::io::ErrorKind::InvalidInput);
+    }
+}
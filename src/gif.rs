@@ -0,0 +1,382 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+use crate::palette::{shade_index, Palette};
+use crate::hud::Status;
+use crate::{Hotkey, Screen};
+
+// Each CHIP-8 pixel becomes a SCALE x SCALE block in the image
+const SCALE: usize = 4;
+const WIDTH: usize = 64 * SCALE;
+const HEIGHT: usize = 32 * SCALE;
+
+//
+// Minimal GIF89a writer: one global color table, LZW compressed frames
+// https://www.w3.org/Graphics/GIF/spec-gif89a.txt
+//
+pub struct GifEncoder<W: Write> {
+    out: W,
+    width: u16,
+    height: u16,
+    min_code_size: u8,
+}
+
+impl<W: Write> GifEncoder<W> {
+    // colors must hold a power of two entries, 2 to 256
+    pub fn new(mut out: W, width: u16, height: u16, colors: &[[u8; 3]]) -> std::io::Result<Self> {
+        let bits = colors.len().trailing_zeros() as u8;
+        out.write_all(b"GIF89a")?;
+        out.write_all(&width.to_le_bytes())?;
+        out.write_all(&height.to_le_bytes())?;
+        // global color table, 8 bit color resolution
+        out.write_all(&[0x80 | 0x70 | (bits - 1), 0, 0])?;
+        for color in colors {
+            out.write_all(color)?;
+        }
+        // NETSCAPE2.0 extension, loop forever
+        out.write_all(&[0x21, 0xFF, 0x0B])?;
+        out.write_all(b"NETSCAPE2.0")?;
+        out.write_all(&[0x03, 0x01, 0x00, 0x00, 0x00])?;
+        Ok(GifEncoder {
+            out,
+            width,
+            height,
+            min_code_size: bits.max(2),
+        })
+    }
+
+    // pixels are color table indices, delay is in hundredths of a second
+    pub fn add_frame(&mut self, pixels: &[u8], delay: u16) -> std::io::Result<()> {
+        // graphic control extension, leave the frame in place
+        self.out.write_all(&[0x21, 0xF9, 0x04, 0x04])?;
+        self.out.write_all(&delay.to_le_bytes())?;
+        self.out.write_all(&[0x00, 0x00])?;
+        // image descriptor covering the whole screen
+        self.out.write_all(&[0x2C, 0, 0, 0, 0])?;
+        self.out.write_all(&self.width.to_le_bytes())?;
+        self.out.write_all(&self.height.to_le_bytes())?;
+        self.out.write_all(&[0x00, self.min_code_size])?;
+        let data = lzw(pixels, self.min_code_size);
+        for block in data.chunks(255) {
+            self.out.write_all(&[block.len() as u8])?;
+            self.out.write_all(block)?;
+        }
+        self.out.write_all(&[0x00])
+    }
+
+    pub fn finish(mut self) -> std::io::Result<W> {
+        self.out.write_all(&[0x3B])?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
+
+// Packs variable width codes least significant bit first
+struct BitWriter {
+    bytes: Vec<u8>,
+    acc: u32,
+    count: u8,
+}
+
+impl BitWriter {
+    fn write(&mut self, code: u16, size: u8) {
+        self.acc |= (code as u32) << self.count;
+        self.count += size;
+        while self.count >= 8 {
+            self.bytes.push(self.acc as u8);
+            self.acc >>= 8;
+            self.count -= 8;
+        }
+    }
+    fn finish(mut self) -> Vec<u8> {
+        if self.count > 0 {
+            self.bytes.push(self.acc as u8);
+        }
+        self.bytes
+    }
+}
+
+// Variable width LZW as GIF uses it, growing to 12 bit codes then clearing
+fn lzw(pixels: &[u8], min_code_size: u8) -> Vec<u8> {
+    let clear: u16 = 1 << min_code_size;
+    let end = clear + 1;
+    let mut bits = BitWriter {
+        bytes: Vec::new(),
+        acc: 0,
+        count: 0,
+    };
+    let mut table: HashMap<(u16, u8), u16> = HashMap::new();
+    let mut next = end + 1;
+    let mut size = min_code_size + 1;
+
+    bits.write(clear, size);
+    let mut prefix = match pixels.first() {
+        Some(p) => *p as u16,
+        None => {
+            bits.write(end, size);
+            return bits.finish();
+        }
+    };
+    for &pixel in &pixels[1..] {
+        if let Some(&code) = table.get(&(prefix, pixel)) {
+            prefix = code;
+            continue;
+        }
+        bits.write(prefix, size);
+        if next >= 1 << size && size < 12 {
+            size += 1;
+        }
+        if next >= 4095 {
+            bits.write(clear, size);
+            table.clear();
+            next = end + 1;
+            size = min_code_size + 1;
+        } else {
+            table.insert((prefix, pixel), next);
+            next += 1;
+        }
+        prefix = pixel as u16;
+    }
+    bits.write(prefix, size);
+    if next >= 1 << size && size < 12 {
+        size += 1;
+    }
+    bits.write(end, size);
+    bits.finish()
+}
+
+//
+// Screen wrapper that passes everything through and tees one frame
+// per 60 Hz tick into an animated GIF. Frames that do not change are
+// folded into the previous frame's delay.
+//
+pub struct GifRecorder<S: Screen> {
+    screen: S,
+    palette: Palette,
+    path: String,
+    takes: u32,
+    encoder: Option<GifEncoder<BufWriter<File>>>,
+    // frame waiting for its delay to be known
+    pending: Vec<u8>,
+    ticks: u64,
+    centis: u64,
+}
+
+impl<S: Screen> GifRecorder<S> {
+    pub fn new(screen: S, palette: Palette, path: String, recording: bool) -> Self {
+        let mut recorder = GifRecorder {
+            screen,
+            palette,
+            path,
+            takes: 0,
+            encoder: None,
+            pending: Vec::new(),
+            ticks: 0,
+            centis: 0,
+        };
+        if recording {
+            recorder.start();
+        }
+        recorder
+    }
+
+    // The first recording goes to path, later ones to name-2.gif, name-3.gif...
+    fn next_path(&mut self) -> String {
+        self.takes += 1;
+        if self.takes == 1 {
+            return self.path.clone();
+        }
+        match self.path.rfind('.') {
+            Some(dot) => format!("{}-{}{}", &self.path[..dot], self.takes, &self.path[dot..]),
+            None => format!("{}-{}", self.path, self.takes),
+        }
+    }
+
+    fn start(&mut self) {
+        let path = self.next_path();
+        let colors = self.palette.shades();
+        let encoder = File::create(&path).and_then(|file| {
+            GifEncoder::new(BufWriter::new(file), WIDTH as u16, HEIGHT as u16, &colors)
+        });
+        match encoder {
+            Ok(encoder) => {
+                self.encoder = Some(encoder);
+                self.pending.clear();
+                self.ticks = 0;
+                self.centis = 0;
+            }
+            Err(e) => eprintln!("can't record to {}: {}", path, e),
+        }
+    }
+
+    fn stop(&mut self) {
+        let result = self.flush().and_then(|_| match self.encoder.take() {
+            Some(encoder) => encoder.finish().map(|_| ()),
+            None => Ok(()),
+        });
+        if let Err(e) = result {
+            eprintln!("recording failed: {}", e);
+        }
+    }
+
+    // Write the pending frame, delayed by every tick it was on screen
+    fn flush(&mut self) -> std::io::Result<()> {
+        if let Some(encoder) = self.encoder.as_mut() {
+            if !self.pending.is_empty() {
+                let total = self.ticks * 100 / 60;
+                let delay = (total - self.centis).min(u16::MAX as u64);
+                self.centis += delay;
+                encoder.add_frame(&self.pending, delay as u16)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn capture(&mut self, gfx: &[u8; 64 * 32]) -> std::io::Result<()> {
+        let mut pixels = vec![0u8; WIDTH * HEIGHT];
+        for (i, pixel) in pixels.iter_mut().enumerate() {
+            let col = (i % WIDTH) / SCALE;
+            let row = (i / WIDTH) / SCALE;
+            *pixel = shade_index(gfx[col + row * 64]) as u8;
+        }
+        if pixels != self.pending {
+            self.flush()?;
+            self.pending = pixels;
+        }
+        self.ticks += 1;
+        Ok(())
+    }
+}
+
+impl<S: Screen> Screen for GifRecorder<S> {
+    fn draw(&mut self, gfx: &[u8; 64 * 32]) {
+        self.screen.draw(gfx);
+    }
+    fn frame(&mut self, gfx: &[u8; 64 * 32]) {
+        if self.encoder.is_some() {
+            if let Err(e) = self.capture(gfx) {
+                eprintln!("recording failed: {}", e);
+                self.encoder = None;
+            }
+        }
+        self.screen.frame(gfx);
+    }
+    fn hotkey(&mut self, key: Hotkey) {
+        if key == Hotkey::Record {
+            if self.encoder.is_some() {
+                self.stop();
+            } else {
+                self.start();
+            }
+        }
+        self.screen.hotkey(key);
+    }
+    fn status(&mut self, status: &Status) {
+        self.screen.status(status);
+    }
+    fn shows_status(&self) -> bool {
+        self.screen.shows_status()
+    }
+}
+
+impl<S: Screen> Drop for GifRecorder<S> {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::Headless;
+
+    // Reads GIF's LZW codes back into color indices
+    fn unlzw(data: &[u8], min_code_size: u8) -> Vec<u8> {
+        let clear = 1usize << min_code_size;
+        let mut table: Vec<Vec<u8>> = Vec::new();
+        let mut size = min_code_size + 1;
+        let mut previous: Option<Vec<u8>> = None;
+        let mut out = Vec::new();
+        let (mut acc, mut count, mut bytes) = (0u32, 0, data.iter());
+        loop {
+            while count < size {
+                acc |= (*bytes.next().unwrap() as u32) << count;
+                count += 8;
+            }
+            let code = (acc & ((1 << size) - 1)) as usize;
+            acc >>= size;
+            count -= size;
+            if code == clear {
+                table = (0..clear).map(|c| vec![c as u8]).collect();
+                table.extend([Vec::new(), Vec::new()]);
+                size = min_code_size + 1;
+                previous = None;
+                continue;
+            }
+            if code == clear + 1 {
+                return out;
+            }
+            let entry = match (table.get(code), &previous) {
+                (Some(entry), _) => entry.clone(),
+                (None, Some(p)) => [&p[..], &p[..1]].concat(),
+                (None, None) => panic!("code {} before any other", code),
+            };
+            if let Some(p) = previous {
+                table.push([&p[..], &entry[..1]].concat());
+                if table.len() == 1 << size && size < 12 {
+                    size += 1;
+                }
+            }
+            out.extend_from_slice(&entry);
+            previous = Some(entry);
+        }
+    }
+
+    #[test]
+    fn lzw_codes() {
+        // clear, 0, 0 0, 0 and end, the last one code wider
+        assert_eq!(lzw(&[0, 0, 0, 0], 2), [0x84, 0x51]);
+        // clear and end
+        assert_eq!(lzw(&[], 2), [0x2C]);
+    }
+
+    #[test]
+    fn lzw_round_trip() {
+        // enough different runs to fill the table and clear it
+        let mut seed = 1u32;
+        let pixels: Vec<u8> = (0..WIDTH * HEIGHT)
+            .map(|_| {
+                seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+                (seed >> 16) as u8 % 16
+            })
+            .collect();
+        assert_eq!(unlzw(&lzw(&pixels, 4), 4), pixels);
+        let flat = vec![3; WIDTH * HEIGHT];
+        assert_eq!(unlzw(&lzw(&flat, 4), 4), flat);
+    }
+
+    #[test]
+    fn frames_last_as_long_as_they_were_shown() {
+        let path = std::env::temp_dir().join(format!("chip-great-frames-{}.gif", std::process::id()));
+        let path = path.to_string_lossy().into_owned();
+        let mut recorder = GifRecorder::new(Headless::default(), Palette::default(), path.clone(), true);
+        let (dark, mut lit) = ([0; 64 * 32], [0; 64 * 32]);
+        lit[0] = 0xFF;
+        for gfx in [&dark, &dark, &dark, &lit, &lit, &dark].iter() {
+            recorder.frame(gfx);
+        }
+        drop(recorder);
+        let gif = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(gif.starts_with(b"GIF89a\x00\x01\x80\x00"));
+        assert_eq!(gif.last(), Some(&0x3B));
+        // 6 frames at 60 Hz are 10 hundredths, split 5, 3 and 2
+        let delays: Vec<u16> = gif
+            .windows(6)
+            .filter(|w| w[..4] == [0x21, 0xF9, 0x04, 0x04])
+            .map(|w| u16::from_le_bytes([w[4], w[5]]))
+            .collect();
+        assert_eq!(delays, [5, 3, 2]);
+    }
+}
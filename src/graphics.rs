@@ -0,0 +1,203 @@
+use std::io::Write;
+
+use crate::common::base64;
+use crate::palette::{shade_index, Palette, SHADES};
+use crate::Screen;
+
+fn present(bytes: &[u8]) {
+    let mut out = std::io::stdout();
+    // Errors here mean the terminal went away, nothing left to draw to
+    let _ = out.write_all(b"\x1B[1;1H");
+    let _ = out.write_all(bytes);
+    let _ = out.flush();
+}
+
+//
+// Sixel image, each pixel a scale x scale block
+// https://vt100.net/docs/vt3xx-gp/chapter14.html
+//
+pub fn sixel(gfx: &[u8; 64 * 32], palette: &Palette, scale: usize) -> Vec<u8> {
+    let width = 64 * scale;
+    let height = 32 * scale;
+    let mut out = Vec::new();
+    // P2 = 1 leaves unset pixels alone, every pixel gets a color below
+    out.extend_from_slice(b"\x1BP0;1;0q");
+    out.extend_from_slice(format!("\"1;1;{};{}", width, height).as_bytes());
+    for (i, [r, g, b]) in palette.shades().into_iter().enumerate() {
+        let percent = |c: u8| (c as usize * 100 + 0x7F) / 0xFF;
+        out.extend_from_slice(format!("#{};2;{};{};{}", i, percent(r), percent(g), percent(b)).as_bytes());
+    }
+    let shade_at = |x: usize, y: usize| shade_index(gfx[x / scale + (y / scale) * 64]);
+    for band in (0..height).step_by(6) {
+        let mut first = true;
+        for color in 0..SHADES {
+            // the six pixels of each column in this band that use color
+            let sixels: Vec<u8> = (0..width)
+                .map(|x| {
+                    (0..6)
+                        .filter(|bit| band + bit < height && shade_at(x, band + bit) == color)
+                        .fold(0, |acc, bit| acc | 1 << bit)
+                })
+                .collect();
+            if sixels.iter().all(|s| *s == 0) {
+                continue;
+            }
+            if !first {
+                out.push(b'$');
+            }
+            first = false;
+            out.extend_from_slice(format!("#{}", color).as_bytes());
+            let mut x = 0;
+            while x < width {
+                let run = sixels[x..].iter().take_while(|s| **s == sixels[x]).count();
+                let c = b'?' + sixels[x];
+                if run > 3 {
+                    out.extend_from_slice(format!("!{}", run).as_bytes());
+                    out.push(c);
+                } else {
+                    out.extend(std::iter::repeat_n(c, run));
+                }
+                x += run;
+            }
+        }
+        out.push(b'-');
+    }
+    out.extend_from_slice(b"\x1B\\");
+    out
+}
+
+//
+// Kitty graphics protocol, raw RGB sent in base64 chunks. The image and
+// placement ids stay the same so each frame replaces the last.
+// https://sw.kovidgoyal.net/kitty/graphics-protocol/
+//
+pub fn kitty(gfx: &[u8; 64 * 32], palette: &Palette, scale: usize) -> Vec<u8> {
+    let width = 64 * scale;
+    let height = 32 * scale;
+    let mut rgb = Vec::with_capacity(width * height * 3);
+    for y in 0..height {
+        for x in 0..width {
+            rgb.extend_from_slice(&palette.shade(gfx[x / scale + (y / scale) * 64]));
+        }
+    }
+    let payload = base64(&rgb);
+    let mut out = Vec::new();
+    let chunks: Vec<&[u8]> = payload.as_bytes().chunks(4096).collect();
+    for (i, chunk) in chunks.iter().enumerate() {
+        let more = if i + 1 < chunks.len() { 1 } else { 0 };
+        if i == 0 {
+            let keys = format!("a=T,f=24,s={},v={},i=1,p=1,q=2,C=1,m={}", width, height, more);
+            out.extend_from_slice(format!("\x1B_G{};", keys).as_bytes());
+        } else {
+            out.extend_from_slice(format!("\x1B_Gm={};", more).as_bytes());
+        }
+        out.extend_from_slice(chunk);
+        out.extend_from_slice(b"\x1B\\");
+    }
+    out
+}
+
+pub struct Sixel {
+    palette: Palette,
+    scale: usize,
+}
+
+impl Sixel {
+    pub fn new(palette: Palette, scale: usize) -> Self {
+        print!("\x1B[2J");
+        Sixel { palette, scale }
+    }
+}
+
+impl Screen for Sixel {
+    fn draw(&mut self, gfx: &[u8; 64 * 32]) {
+        present(&sixel(gfx, &self.palette, self.scale));
+    }
+}
+
+pub struct Kitty {
+    palette: Palette,
+    scale: usize,
+}
+
+impl Kitty {
+    pub fn new(palette: Palette, scale: usize) -> Self {
+        print!("\x1B[2J");
+        Kitty { palette, scale }
+    }
+}
+
+impl Screen for Kitty {
+    fn draw(&mut self, gfx: &[u8; 64 * 32]) {
+        present(&kitty(gfx, &self.palette, self.scale));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GRAYS: &str = "#0;2;0;0;0#1;2;7;7;7#2;2;13;13;13#3;2;20;20;20#4;2;27;27;27#5;2;33;33;33\
+        #6;2;40;40;40#7;2;47;47;47#8;2;53;53;53#9;2;60;60;60#10;2;67;67;67#11;2;73;73;73\
+        #12;2;80;80;80#13;2;87;87;87#14;2;93;93;93#15;2;100;100;100";
+
+    #[test]
+    fn sixel_dark_screen() {
+        let gfx = [0; 64 * 32];
+        let mut expected = format!("\x1BP0;1;0q\"1;1;64;32{}", GRAYS);
+        // five full bands, then the last two rows
+        expected += &"#0!64~-".repeat(5);
+        expected += "#0!64B-\x1B\\";
+        assert_eq!(String::from_utf8(sixel(&gfx, &Palette::default(), 1)).unwrap(), expected);
+    }
+
+    #[test]
+    fn sixel_one_pixel() {
+        let mut gfx = [0; 64 * 32];
+        gfx[0] = 0xFF;
+        let mut expected = format!("\x1BP0;1;0q\"1;1;64;32{}", GRAYS);
+        expected += "#0}!63~$#15@!63?-";
+        expected += &"#0!64~-".repeat(4);
+        expected += "#0!64B-\x1B\\";
+        assert_eq!(String::from_utf8(sixel(&gfx, &Palette::default(), 1)).unwrap(), expected);
+    }
+
+    #[test]
+    fn sixel_short_runs_are_spelled_out() {
+        let mut gfx = [0; 64 * 32];
+        gfx[..3].copy_from_slice(&[0xFF; 3]);
+        let out = String::from_utf8(sixel(&gfx, &Palette::default(), 1)).unwrap();
+        assert!(out.contains("#0}}}!61~$#15@@@!61?-"));
+    }
+
+    #[test]
+    fn sixel_scaled() {
+        let gfx = [0; 64 * 32];
+        let out = String::from_utf8(sixel(&gfx, &Palette::default(), 2)).unwrap();
+        assert!(out.starts_with("\x1BP0;1;0q\"1;1;128;64#0;2;0;0;0"));
+        // 64 rows are ten full bands and four rows
+        assert_eq!(out.matches("#0!128~-").count(), 10);
+        assert!(out.ends_with("#0!128N-\x1B\\"));
+    }
+
+    #[test]
+    fn kitty_chunks() {
+        let mut gfx = [0; 64 * 32];
+        gfx[0] = 0xFF;
+        // 64 x 32 x 3 bytes are 8192 base64 characters, two chunks
+        let mut expected = String::from("\x1B_Ga=T,f=24,s=64,v=32,i=1,p=1,q=2,C=1,m=1;////");
+        expected += &"A".repeat(4092);
+        expected += "\x1B\\\x1B_Gm=0;";
+        expected += &"A".repeat(4096);
+        expected += "\x1B\\";
+        assert_eq!(String::from_utf8(kitty(&gfx, &Palette::default(), 1)).unwrap(), expected);
+    }
+
+    #[test]
+    fn kitty_palette() {
+        let gfx = [0xFF; 64 * 32];
+        let palette = Palette::parse("102030:000000").unwrap();
+        let out = String::from_utf8(kitty(&gfx, &palette, 1)).unwrap();
+        assert!(out.starts_with("\x1B_Ga=T,f=24,s=64,v=32,i=1,p=1,q=2,C=1,m=1;ECAwECAw"));
+    }
+}
@@ -0,0 +1,223 @@
+use std::time::Instant;
+
+// The panel sits to the right of the 64 column display
+pub const HUD_COLUMN: usize = 67;
+
+// Machine state shown next to the display, taken once per frame
+pub struct Status {
+    pub pc: u16,
+    // the instruction at pc, about to run
+    pub opcode: u16,
+    pub v: [u8; 16],
+    pub i: u16,
+    pub stack: Vec<u16>,
+    pub delay: u8,
+    pub sound: u8,
+    pub ips: u64,
+    pub fps: u64,
+    // blocked in FX0A until a key is pressed and released
+    pub waiting_for_key: bool,
+    pub keys: [bool; 16],
+}
+
+impl Status {
+    // The panel as text lines, 24 columns wide
+    pub fn lines(&self) -> Vec<String> {
+        let mut lines = vec![
+            format!("PC {:04X}  OP {:04X}", self.pc, self.opcode),
+            format!("I  {:04X}  SP {:X}", self.i, self.stack.len()),
+        ];
+        for row in 0..4 {
+            let regs: Vec<String> = (row * 4..row * 4 + 4)
+                .map(|r| format!("V{:X} {:02X}", r, self.v[r]))
+                .collect();
+            lines.push(regs.join(" "));
+        }
+        lines.push(format!("DT {:02X}  ST {:02X}", self.delay, self.sound));
+        lines.push(format!("IPS {:<5} FPS {}", self.ips, self.fps));
+        if self.waiting_for_key {
+            lines.push(String::from("Waiting for key"));
+        }
+        lines.push(String::from("Stack"));
+        for (depth, address) in self.stack.iter().enumerate().rev() {
+            lines.push(format!(" {:X}: {:04X}", depth, address));
+        }
+        lines
+    }
+}
+
+// The keypad overlay, drawn from the top of the panel
+const KEYPAD: [[u8; 4]; 4] = [[0x1, 0x2, 0x3, 0xC], [0x4, 0x5, 0x6, 0xD], [0x7, 0x8, 0x9, 0xE], [0xA, 0x0, 0xB, 0xF]];
+const CELL: usize = 6;
+pub const KEYPAD_ROWS: usize = 8;
+
+// Each keypad row is two lines, the keypad key then the host key for it.
+// Pressed keys are drawn in reverse video.
+pub fn keypad(keys: &[bool; 16], hosts: &[String; 16]) -> Vec<String> {
+    let mut lines = Vec::new();
+    for row in &KEYPAD {
+        let mut top = String::new();
+        let mut bottom = String::new();
+        for &key in row {
+            let on = if keys[key as usize] { "\x1B[7m" } else { "" };
+            top += &format!("{} {:X}{:w$}\x1B[0m", on, key, "", w = CELL - 3);
+            bottom += &format!("{} {:<w$.w$}\x1B[0m", on, hosts[key as usize], w = CELL - 2);
+            top.push(' ');
+            bottom.push(' ');
+        }
+        lines.push(top);
+        lines.push(bottom);
+    }
+    lines
+}
+
+// The keypad key under a terminal cell, column and row counted from 1
+pub fn keypad_at(column: usize, row: usize) -> Option<u8> {
+    let x = column.checked_sub(HUD_COLUMN)?;
+    let y = row.checked_sub(1)?;
+    // the gap after each cell belongs to no key
+    if x % CELL == CELL - 1 {
+        return None;
+    }
+    KEYPAD.get(y / 2)?.get(x / CELL).copied()
+}
+
+// Counts instructions and drawn frames, reporting the rates once a second
+pub struct Meter {
+    since: Instant,
+    cycles: u64,
+    draws: u64,
+    pub ips: u64,
+    pub fps: u64,
+}
+
+impl Meter {
+    pub fn new() -> Self {
+        Meter {
+            since: Instant::now(),
+            cycles: 0,
+            draws: 0,
+            ips: 0,
+            fps: 0,
+        }
+    }
+
+    pub fn count(&mut self, cycles: u64, draws: u64) {
+        self.cycles += cycles;
+        self.draws += draws;
+        let elapsed = self.since.elapsed().as_millis() as u64;
+        if elapsed >= 1000 {
+            self.ips = self.cycles * 1000 / elapsed;
+            self.fps = self.draws * 1000 / elapsed;
+            self.cycles = 0;
+            self.draws = 0;
+            self.since = Instant::now();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn status_lines() {
+        let mut status = Status {
+            pc: 0x2A4,
+            opcode: 0xF00A,
+            v: std::array::from_fn(|r| r as u8 * 0x11),
+            i: 0x300,
+            stack: vec![0x204, 0x28A],
+            delay: 0x3C,
+            sound: 0,
+            ips: 700,
+            fps: 60,
+            waiting_for_key: true,
+            keys: [false; 16],
+        };
+        assert_eq!(
+            status.lines(),
+            [
+                "PC 02A4  OP F00A",
+                "I  0300  SP 2",
+                "V0 00 V1 11 V2 22 V3 33",
+                "V4 44 V5 55 V6 66 V7 77",
+                "V8 88 V9 99 VA AA VB BB",
+                "VC CC VD DD VE EE VF FF",
+                "DT 3C  ST 00",
+                "IPS 700   FPS 60",
+                "Waiting for key",
+                "Stack",
+                " 1: 028A",
+                " 0: 0204",
+            ]
+        );
+        status.waiting_for_key = false;
+        status.stack.clear();
+        let lines = status.lines();
+        assert_eq!(lines.len(), 9);
+        assert!(lines.iter().all(|line| line.len() <= 24));
+        assert_eq!(lines.last().unwrap(), "Stack");
+    }
+
+    #[test]
+    fn keypad_cells() {
+        // the top left key's cell, both its lines
+        for column in HUD_COLUMN..HUD_COLUMN + CELL - 1 {
+            assert_eq!(keypad_at(column, 1), Some(0x1), "column {}", column);
+            assert_eq!(keypad_at(column, 2), Some(0x1), "column {}", column);
+        }
+        assert_eq!(keypad_at(HUD_COLUMN + CELL, 1), Some(0x2));
+        assert_eq!(keypad_at(HUD_COLUMN + 3 * CELL, 1), Some(0xC));
+        assert_eq!(keypad_at(HUD_COLUMN, 3), Some(0x4));
+        assert_eq!(keypad_at(HUD_COLUMN + CELL, 8), Some(0x0));
+        // the bottom right corner
+        assert_eq!(keypad_at(HUD_COLUMN + 4 * CELL - 2, KEYPAD_ROWS), Some(0xF));
+    }
+
+    #[test]
+    fn keypad_edges() {
+        // left of the panel, above and below the keypad
+        assert_eq!(keypad_at(HUD_COLUMN - 1, 1), None);
+        assert_eq!(keypad_at(1, 1), None);
+        assert_eq!(keypad_at(HUD_COLUMN, 0), None);
+        assert_eq!(keypad_at(HUD_COLUMN, KEYPAD_ROWS + 1), None);
+        // the gaps between cells and past the last one
+        for gap in 1..=4 {
+            assert_eq!(keypad_at(HUD_COLUMN + gap * CELL - 1, 1), None, "gap {}", gap);
+        }
+        assert_eq!(keypad_at(HUD_COLUMN + 4 * CELL, 1), None);
+        assert_eq!(keypad_at(usize::MAX, usize::MAX), None);
+    }
+
+    #[test]
+    fn keypad_lines() {
+        let mut keys = [false; 16];
+        keys[0x5] = true;
+        let hosts = std::array::from_fn(|key| format!("{:X}key", key));
+        let lines = keypad(&keys, &hosts);
+        assert_eq!(lines.len(), KEYPAD_ROWS);
+        assert_eq!(lines[0], " 1   \x1B[0m  2   \x1B[0m  3   \x1B[0m  C   \x1B[0m ");
+        assert_eq!(lines[1], " 1key\x1B[0m  2key\x1B[0m  3key\x1B[0m  Ckey\x1B[0m ");
+        assert!(lines[2].contains("\x1B[7m 5"));
+        assert!(!lines[0].contains("\x1B[7m"));
+    }
+
+    #[test]
+    fn meter_reports_once_a_second() {
+        let mut meter = Meter::new();
+        meter.count(500, 30);
+        assert_eq!((meter.ips, meter.fps), (0, 0));
+        meter.since -= Duration::from_secs(2);
+        meter.count(700, 30);
+        // 1200 cycles and 60 draws over two seconds, give or take a millisecond
+        assert!((599..=600).contains(&meter.ips), "ips {}", meter.ips);
+        assert!((29..=30).contains(&meter.fps), "fps {}", meter.fps);
+        // counting starts over
+        meter.since -= Duration::from_secs(1);
+        meter.count(100, 10);
+        assert!((99..=100).contains(&meter.ips), "ips {}", meter.ips);
+        assert!((9..=10).contains(&meter.fps), "fps {}", meter.fps);
+    }
+}
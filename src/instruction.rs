@@ -0,0 +1,233 @@
+//
+// The instruction set, decoded once for both the interpreter and the
+// disassembler. x and y are V register numbers, n, nn and nnn the low 4, 8
+// and 12 bits of the opcode.
+//
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Instruction {
+    ScrollDown(u8),
+    ScrollRight,
+    ScrollLeft,
+    Exit,
+    Low,
+    High,
+    Clear,
+    Return,
+    Native(u16),
+    Jump(u16),
+    Call(u16),
+    SkipEqual(u8, u8),
+    SkipNotEqual(u8, u8),
+    SkipEqualV(u8, u8),
+    Set(u8, u8),
+    Add(u8, u8),
+    SetV(u8, u8),
+    Or(u8, u8),
+    And(u8, u8),
+    Xor(u8, u8),
+    AddV(u8, u8),
+    Sub(u8, u8),
+    ShiftRight(u8, u8),
+    SubFrom(u8, u8),
+    ShiftLeft(u8, u8),
+    SkipNotEqualV(u8, u8),
+    SetI(u16),
+    JumpV0(u16),
+    Random(u8, u8),
+    Draw(u8, u8, u8),
+    SkipKey(u8),
+    SkipNotKey(u8),
+    GetDelay(u8),
+    WaitKey(u8),
+    SetDelay(u8),
+    SetSound(u8),
+    AddI(u8),
+    Font(u8),
+    BigFont(u8),
+    Bcd(u8),
+    Store(u8),
+    Read(u8),
+    StoreFlags(u8),
+    ReadFlags(u8),
+    // XO-CHIP sound
+    Pattern,
+    Pitch(u8),
+}
+
+use Instruction::*;
+
+impl Instruction {
+    pub fn decode(opcode: u16) -> Option<Instruction> {
+        let n0 = (opcode >> 12) as u8;
+        let x = (opcode >> 8 & 0xF) as u8;
+        let y = (opcode >> 4 & 0xF) as u8;
+        let n = (opcode & 0xF) as u8;
+        let nn = opcode as u8;
+        let nnn = opcode & 0xFFF;
+        let instruction = match (n0, x, y, n) {
+            (0, 0, 0xC, n) => ScrollDown(n),
+            (0, 0, 0xF, 0xB) => ScrollRight,
+            (0, 0, 0xF, 0xC) => ScrollLeft,
+            (0, 0, 0xF, 0xD) => Exit,
+            (0, 0, 0xF, 0xE) => Low,
+            (0, 0, 0xF, 0xF) => High,
+            (0, 0, 0xE, 0) => Clear,
+            (0, 0, 0xE, 0xE) => Return,
+            (0, _, _, _) => Native(nnn),
+            (1, _, _, _) => Jump(nnn),
+            (2, _, _, _) => Call(nnn),
+            (3, x, _, _) => SkipEqual(x, nn),
+            (4, x, _, _) => SkipNotEqual(x, nn),
+            (5, x, y, 0) => SkipEqualV(x, y),
+            (6, x, _, _) => Set(x, nn),
+            (7, x, _, _) => Add(x, nn),
+            (8, x, y, 0) => SetV(x, y),
+            (8, x, y, 1) => Or(x, y),
+            (8, x, y, 2) => And(x, y),
+            (8, x, y, 3) => Xor(x, y),
+            (8, x, y, 4) => AddV(x, y),
+            (8, x, y, 5) => Sub(x, y),
+            (8, x, y, 6) => ShiftRight(x, y),
+            (8, x, y, 7) => SubFrom(x, y),
+            (8, x, y, 0xE) => ShiftLeft(x, y),
+            (9, x, y, 0) => SkipNotEqualV(x, y),
+            (0xA, _, _, _) => SetI(nnn),
+            (0xB, _, _, _) => JumpV0(nnn),
+            (0xC, x, _, _) => Random(x, nn),
+            (0xD, x, y, n) => Draw(x, y, n),
+            (0xE, x, 9, 0xE) => SkipKey(x),
+            (0xE, x, 0xA, 1) => SkipNotKey(x),
+            (0xF, 0, 0, 2) => Pattern,
+            (0xF, x, 0, 7) => GetDelay(x),
+            (0xF, x, 0, 0xA) => WaitKey(x),
+            (0xF, x, 1, 5) => SetDelay(x),
+            (0xF, x, 1, 8) => SetSound(x),
+            (0xF, x, 1, 0xE) => AddI(x),
+            (0xF, x, 2, 9) => Font(x),
+            (0xF, x, 3, 0) => BigFont(x),
+            (0xF, x, 3, 3) => Bcd(x),
+            (0xF, x, 3, 0xA) => Pitch(x),
+            (0xF, x, 5, 5) => Store(x),
+            (0xF, x, 6, 5) => Read(x),
+            (0xF, x, 7, 5) => StoreFlags(x),
+            (0xF, x, 8, 5) => ReadFlags(x),
+            _ => return None,
+        };
+        Some(instruction)
+    }
+
+    // The address a jump or call goes to
+    pub fn target(&self) -> Option<u16> {
+        match self {
+            Jump(nnn) | Call(nnn) => Some(*nnn),
+            _ => None,
+        }
+    }
+
+    // Octo's syntax, addresses go through name so labels can stand in.
+    // None when Octo has no instruction for it and it has to be data.
+    pub fn octo(&self, name: &dyn Fn(u16) -> String) -> Option<String> {
+        let text = match *self {
+            ScrollDown(n) => format!("scroll-down {}", n),
+            ScrollRight => String::from("scroll-right"),
+            ScrollLeft => String::from("scroll-left"),
+            Exit => String::from("exit"),
+            Low => String::from("lores"),
+            High => String::from("hires"),
+            Clear => String::from("clear"),
+            Return => String::from("return"),
+            Native(_) => return None,
+            Jump(nnn) => format!("jump {}", name(nnn)),
+            Call(nnn) => format!(":call {}", name(nnn)),
+            // skips read as the condition the next instruction runs on
+            SkipEqual(x, nn) => format!("if v{:x} != 0x{:02X} then", x, nn),
+            SkipNotEqual(x, nn) => format!("if v{:x} == 0x{:02X} then", x, nn),
+            SkipEqualV(x, y) => format!("if v{:x} != v{:x} then", x, y),
+            Set(x, nn) => format!("v{:x} := 0x{:02X}", x, nn),
+            Add(x, nn) => format!("v{:x} += 0x{:02X}", x, nn),
+            SetV(x, y) => format!("v{:x} := v{:x}", x, y),
+            Or(x, y) => format!("v{:x} |= v{:x}", x, y),
+            And(x, y) => format!("v{:x} &= v{:x}", x, y),
+            Xor(x, y) => format!("v{:x} ^= v{:x}", x, y),
+            AddV(x, y) => format!("v{:x} += v{:x}", x, y),
+            Sub(x, y) => format!("v{:x} -= v{:x}", x, y),
+            ShiftRight(x, y) => format!("v{:x} >>= v{:x}", x, y),
+            SubFrom(x, y) => format!("v{:x} =- v{:x}", x, y),
+            ShiftLeft(x, y) => format!("v{:x} <<= v{:x}", x, y),
+            SkipNotEqualV(x, y) => format!("if v{:x} == v{:x} then", x, y),
+            SetI(nnn) => format!("i := {}", name(nnn)),
+            JumpV0(nnn) => format!("jump0 {}", name(nnn)),
+            Random(x, nn) => format!("v{:x} := random 0x{:02X}", x, nn),
+            Draw(x, y, n) => format!("sprite v{:x} v{:x} {}", x, y, n),
+            SkipKey(x) => format!("if v{:x} -key then", x),
+            SkipNotKey(x) => format!("if v{:x} key then", x),
+            GetDelay(x) => format!("v{:x} := delay", x),
+            WaitKey(x) => format!("v{:x} := key", x),
+            SetDelay(x) => format!("delay := v{:x}", x),
+            SetSound(x) => format!("buzzer := v{:x}", x),
+            AddI(x) => format!("i += v{:x}", x),
+            Font(x) => format!("i := hex v{:x}", x),
+            BigFont(x) => format!("i := bighex v{:x}", x),
+            Bcd(x) => format!("bcd v{:x}", x),
+            Store(x) => format!("save v{:x}", x),
+            Read(x) => format!("load v{:x}", x),
+            StoreFlags(x) => format!("saveflags v{:x}", x),
+            ReadFlags(x) => format!("loadflags v{:x}", x),
+            Pattern => String::from("audio"),
+            Pitch(x) => format!("pitch := v{:x}", x),
+        };
+        Some(text)
+    }
+
+    // The classic mnemonics from Cowgod's reference
+    pub fn classic(&self, name: &dyn Fn(u16) -> String) -> String {
+        match *self {
+            ScrollDown(n) => format!("SCD {}", n),
+            ScrollRight => String::from("SCR"),
+            ScrollLeft => String::from("SCL"),
+            Exit => String::from("EXIT"),
+            Low => String::from("LOW"),
+            High => String::from("HIGH"),
+            Clear => String::from("CLS"),
+            Return => String::from("RET"),
+            Native(nnn) => format!("SYS {}", name(nnn)),
+            Jump(nnn) => format!("JP {}", name(nnn)),
+            Call(nnn) => format!("CALL {}", name(nnn)),
+            SkipEqual(x, nn) => format!("SE V{:X}, 0x{:02X}", x, nn),
+            SkipNotEqual(x, nn) => format!("SNE V{:X}, 0x{:02X}", x, nn),
+            SkipEqualV(x, y) => format!("SE V{:X}, V{:X}", x, y),
+            Set(x, nn) => format!("LD V{:X}, 0x{:02X}", x, nn),
+            Add(x, nn) => format!("ADD V{:X}, 0x{:02X}", x, nn),
+            SetV(x, y) => format!("LD V{:X}, V{:X}", x, y),
+            Or(x, y) => format!("OR V{:X}, V{:X}", x, y),
+            And(x, y) => format!("AND V{:X}, V{:X}", x, y),
+            Xor(x, y) => format!("XOR V{:X}, V{:X}", x, y),
+            AddV(x, y) => format!("ADD V{:X}, V{:X}", x, y),
+            Sub(x, y) => format!("SUB V{:X}, V{:X}", x, y),
+            ShiftRight(x, y) => format!("SHR V{:X}, V{:X}", x, y),
+            SubFrom(x, y) => format!("SUBN V{:X}, V{:X}", x, y),
+            ShiftLeft(x, y) => format!("SHL V{:X}, V{:X}", x, y),
+            SkipNotEqualV(x, y) => format!("SNE V{:X}, V{:X}", x, y),
+            SetI(nnn) => format!("LD I, {}", name(nnn)),
+            JumpV0(nnn) => format!("JP V0, {}", name(nnn)),
+            Random(x, nn) => format!("RND V{:X}, 0x{:02X}", x, nn),
+            Draw(x, y, n) => format!("DRW V{:X}, V{:X}, {}", x, y, n),
+            SkipKey(x) => format!("SKP V{:X}", x),
+            SkipNotKey(x) => format!("SKNP V{:X}", x),
+            GetDelay(x) => format!("LD V{:X}, DT", x),
+            WaitKey(x) => format!("LD V{:X}, K", x),
+            SetDelay(x) => format!("LD DT, V{:X}", x),
+            SetSound(x) => format!("LD ST, V{:X}", x),
+            AddI(x) => format!("ADD I, V{:X}", x),
+            Font(x) => format!("LD F, V{:X}", x),
+            BigFont(x) => format!("LD HF, V{:X}", x),
+            Bcd(x) => format!("LD B, V{:X}", x),
+            Store(x) => format!("LD [I], V{:X}", x),
+            Read(x) => format!("LD V{:X}, [I]", x),
+            StoreFlags(x) => format!("LD R, V{:X}", x),
+            ReadFlags(x) => format!("LD V{:X}, R", x),
+            Pattern => String::from("LD AUDIO, [I]"),
+            Pitch(x) => format!("LD PITCH, V{:X}", x),
+        }
+    }
+}
@@ -0,0 +1,424 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use device_query::Keycode;
+
+//
+// Which host keys press which keypad keys. Host keys use device_query's
+// Keycode names (Key1, Q, Numpad7, Comma, Up...), a bare digit works for
+// the number row too.
+//
+// Keymap files hold named layouts and per ROM overrides:
+//
+//   # keypad key = host keys
+//   [layout mine]
+//   1 = Key1
+//   C = Key4 Up
+//
+//   [rom pong2.c8]
+//   layout = split
+//   1 = W
+//
+// Layouts in the file replace built in ones of the same name. A ROM section
+// picks the layout for that ROM and rebinds single keypad keys on top of it.
+//
+// Layouts and ROM sections can also make a keypad key autofire while held,
+// some number of presses a second, and bind a host key to a macro: keypad
+// keys held for some frames each, - for none, joined with + to hold several.
+//
+//   turbo 5 = 15
+//   macro dash = G 6/2 -/2 6+5/4
+//
+// Keypad
+// +-+-+-+-+
+// |1|2|3|C|
+// |4|5|6|D|
+// |7|8|9|E|
+// |A|0|B|F|
+// +-+-+-+-+
+const BUILT_IN: &str = "
+[layout qwerty]
+1 = Key1
+2 = Key2
+3 = Key3
+C = Key4
+4 = Q
+5 = W
+6 = E
+D = R
+7 = A
+8 = S
+9 = D
+E = F
+A = Z
+0 = X
+B = C
+F = V
+
+[layout azerty]
+1 = Key1
+2 = Key2
+3 = Key3
+C = Key4
+4 = A
+5 = Z
+6 = E
+D = R
+7 = Q
+8 = S
+9 = D
+E = F
+A = W
+0 = X
+B = C
+F = V
+
+[layout dvorak]
+1 = Key1
+2 = Key2
+3 = Key3
+C = Key4
+4 = Apostrophe
+5 = Comma
+6 = Dot
+D = P
+7 = A
+8 = O
+9 = E
+E = U
+A = Semicolon
+0 = Q
+B = J
+F = K
+
+[layout numpad]
+0 = Numpad0
+1 = Numpad1
+2 = Numpad2
+3 = Numpad3
+4 = Numpad4
+5 = Numpad5
+6 = Numpad6
+7 = Numpad7
+8 = Numpad8
+9 = Numpad9
+A = NumpadDivide
+B = NumpadMultiply
+C = NumpadSubtract
+D = NumpadAdd
+E = Enter
+F = Dot
+
+# left hand has the two left keypad columns, right hand the two right ones
+[layout split]
+1 = Key1
+4 = Q
+7 = A
+A = Z
+2 = Key2
+5 = W
+8 = S
+0 = X
+3 = Key9
+6 = O
+9 = L
+B = Dot
+C = Key0
+D = P
+E = Semicolon
+F = Slash
+";
+
+// Keypad keys from 0x10 up are macros, number key - MACRO in Keymap::macros
+pub const MACRO: u8 = 0x10;
+
+#[derive(Clone, Debug, Default)]
+pub struct Keymap {
+    bindings: Vec<(String, u8)>,
+    // keypad key and presses a second
+    pub turbo: Vec<(u8, u32)>,
+    pub macros: Vec<Macro>,
+}
+
+// A host key that plays keypad masks, each held for some frames
+#[derive(Clone, Debug)]
+pub struct Macro {
+    host: String,
+    pub steps: Vec<(u16, u64)>,
+}
+
+impl Keymap {
+    // The keypad key host key name presses, if any
+    pub fn keypad(&self, name: &str) -> Option<u8> {
+        match self.macros.iter().position(|m| m.host == name) {
+            Some(n) => Some(MACRO + n as u8),
+            None => self.bindings.iter().find(|(host, _)| host == name).map(|(_, key)| *key),
+        }
+    }
+
+    // The first host key that presses keypad key, if any
+    pub fn host(&self, key: u8) -> Option<&str> {
+        self.bindings.iter().find(|(_, k)| *k == key).map(|(host, _)| host.as_str())
+    }
+
+    // How many keys keypad can answer with, keypad keys then macros
+    pub fn keys(&self) -> usize {
+        MACRO as usize + self.macros.len()
+    }
+
+    // Builds the keymap for rom from the built in layouts and text from a
+    // keymap file. layout, when given, wins over the ROM section's choice.
+    pub fn load(file: Option<(&str, &str)>, layout: Option<&str>, rom: &str) -> Result<Keymap, String> {
+        let mut config = Config::default();
+        config.parse("built in", BUILT_IN)?;
+        if let Some((path, text)) = file {
+            config.parse(path, text)?;
+        }
+        let rom = rom.rsplit(['/', '\\']).next().unwrap_or(rom);
+        let over = config.roms.get(rom);
+        let name = layout
+            .or_else(|| over.and_then(|o| o.layout.as_deref()))
+            .unwrap_or("qwerty");
+        let mut keymap = match config.layouts.get(name) {
+            Some(keymap) => keymap.clone(),
+            None => return Err(format!("no keymap layout named {}", name)),
+        };
+        if let Some(over) = over {
+            // rebound keypad keys lose their old host keys, and rebound
+            // host keys stop pressing what they used to
+            let over = &over.keys;
+            for (host, key) in &over.bindings {
+                keymap.bindings.retain(|(h, k)| k != key && h != host);
+                keymap.macros.retain(|m| &m.host != host);
+            }
+            for m in &over.macros {
+                keymap.bindings.retain(|(h, _)| *h != m.host);
+                keymap.macros.retain(|old| old.host != m.host);
+            }
+            for (key, _) in &over.turbo {
+                keymap.turbo.retain(|(k, _)| k != key);
+            }
+            keymap.bindings.extend(over.bindings.iter().cloned());
+            keymap.macros.extend(over.macros.iter().cloned());
+            keymap.turbo.extend(over.turbo.iter().cloned());
+        }
+        if keymap.keys() > 256 {
+            return Err(String::from("too many keymap macros"));
+        }
+        Ok(keymap)
+    }
+}
+
+#[derive(Default)]
+struct RomSection {
+    layout: Option<String>,
+    keys: Keymap,
+}
+
+#[derive(Default)]
+struct Config {
+    layouts: HashMap<String, Keymap>,
+    roms: HashMap<String, RomSection>,
+}
+
+enum Section {
+    None,
+    Layout(String),
+    Rom(String),
+}
+
+impl Config {
+    fn parse(&mut self, path: &str, text: &str) -> Result<(), String> {
+        let mut section = Section::None;
+        for (number, line) in text.lines().enumerate() {
+            let error = |msg: String| format!("{}:{}: {}", path, number + 1, msg);
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            if let Some(header) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+                let mut words = header.split_whitespace();
+                section = match (words.next(), words.next(), words.next()) {
+                    (Some("layout"), Some(name), None) => {
+                        // a file's layout starts from scratch
+                        self.layouts.insert(name.to_string(), Keymap::default());
+                        Section::Layout(name.to_string())
+                    }
+                    (Some("rom"), Some(name), None) => {
+                        self.roms.insert(name.to_string(), RomSection::default());
+                        Section::Rom(name.to_string())
+                    }
+                    _ => return Err(error(format!("expected [layout NAME] or [rom FILE], found [{}]", header))),
+                };
+                continue;
+            }
+            let (left, right) = match line.split_once('=') {
+                Some((l, r)) => (l.trim(), r.trim()),
+                None => return Err(error(format!("expected KEY = HOSTKEYS, found {}", line))),
+            };
+            if let (Section::Rom(rom), "layout") = (&section, left) {
+                self.roms.get_mut(rom).unwrap().layout = Some(right.to_string());
+                continue;
+            }
+            let keymap = match &section {
+                Section::Layout(name) => self.layouts.get_mut(name).unwrap(),
+                Section::Rom(rom) => &mut self.roms.get_mut(rom).unwrap().keys,
+                Section::None => return Err(error(String::from("key binding outside of a section"))),
+            };
+            let words: Vec<&str> = left.split_whitespace().collect();
+            match words.as_slice() {
+                ["turbo", key] => {
+                    let key = keypad_key(key).map_err(error)?;
+                    let rate = match right.parse::<u32>() {
+                        Ok(rate) if (1..=30).contains(&rate) => rate,
+                        _ => return Err(error(format!("turbo rate must be 1-30 presses a second, found {}", right))),
+                    };
+                    keymap.turbo.retain(|(k, _)| *k != key);
+                    keymap.turbo.push((key, rate));
+                }
+                ["macro", _] => {
+                    let mut words = right.split_whitespace();
+                    let host = match words.next() {
+                        Some(host) => host_name(host).map_err(error)?,
+                        None => return Err(error(String::from("expected macro NAME = HOSTKEY STEPS"))),
+                    };
+                    let steps = words.map(step).collect::<Result<Vec<_>, _>>().map_err(error)?;
+                    if steps.is_empty() {
+                        return Err(error(String::from("macro has no steps")));
+                    }
+                    keymap.macros.retain(|m| m.host != host);
+                    keymap.macros.push(Macro { host, steps });
+                }
+                _ => {
+                    let key = keypad_key(left).map_err(error)?;
+                    for host in right.split_whitespace() {
+                        keymap.bindings.push((host_name(host).map_err(error)?, key));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn keypad_key(name: &str) -> Result<u8, String> {
+    match u8::from_str_radix(name, 16) {
+        Ok(key) if name.len() == 1 => Ok(key),
+        _ => Err(format!("{} is not a keypad key 0-F", name)),
+    }
+}
+
+// A macro step, KEYS/FRAMES like 5+6/3, frames are 1 when left out
+fn step(text: &str) -> Result<(u16, u64), String> {
+    let (keys, frames) = match text.split_once('/') {
+        Some((keys, frames)) => match frames.parse() {
+            Ok(frames) if frames > 0 => (keys, frames),
+            _ => return Err(format!("bad frame count in macro step {}", text)),
+        },
+        None => (text, 1),
+    };
+    let mut mask = 0;
+    if keys != "-" {
+        for key in keys.split('+') {
+            mask |= 1 << keypad_key(key)?;
+        }
+    }
+    Ok((mask, frames))
+}
+
+// A bare digit means the number row key
+fn host_name(name: &str) -> Result<String, String> {
+    let name = match name.as_bytes() {
+        [d] if d.is_ascii_digit() => format!("Key{}", *d as char),
+        _ => name.to_string(),
+    };
+    // Keycode::from_str doesn't know Key0 or the numpad keys
+    let numpad = ["Subtract", "Add", "Divide", "Multiply"];
+    let missing = match name.strip_prefix("Numpad") {
+        Some(key) => numpad.contains(&key) || (key.len() == 1 && key.as_bytes()[0].is_ascii_digit()),
+        None => name == "Key0",
+    };
+    if missing || Keycode::from_str(&name).is_ok() {
+        Ok(name)
+    } else {
+        Err(format!("unknown host key {}", name))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Keypad keys 0-F by the first host key for each
+    fn hosts(keymap: &Keymap) -> Vec<&str> {
+        (0..16).map(|key| keymap.host(key).unwrap_or("")).collect()
+    }
+
+    #[test]
+    fn built_in_layouts() {
+        let layouts = [
+            ("qwerty", "X 1 2 3 Q W E A S D Z C Key4 R F V"),
+            ("azerty", "X 1 2 3 A Z E Q S D W C Key4 R F V"),
+            ("dvorak", "Q 1 2 3 Apostrophe Comma Dot A O E Semicolon J Key4 P U K"),
+            (
+                "numpad",
+                "Numpad0 Numpad1 Numpad2 Numpad3 Numpad4 Numpad5 Numpad6 Numpad7 Numpad8 Numpad9 \
+                 NumpadDivide NumpadMultiply NumpadSubtract NumpadAdd Enter Dot",
+            ),
+            ("split", "X 1 2 9 Q W O A S L Z Dot 0 P Semicolon Slash"),
+        ];
+        for (name, expected) in layouts {
+            let keymap = Keymap::load(None, Some(name), "game.ch8").unwrap();
+            let expected: Vec<String> = expected.split(' ').map(|h| host_name(h).unwrap()).collect();
+            assert_eq!(hosts(&keymap), expected, "{}", name);
+            for (key, host) in expected.iter().enumerate() {
+                assert_eq!(keymap.keypad(host), Some(key as u8), "{} {}", name, host);
+            }
+        }
+        let qwerty = Keymap::load(None, None, "game.ch8").unwrap();
+        assert_eq!(hosts(&qwerty)[..4], ["X", "Key1", "Key2", "Key3"]);
+        assert_eq!(qwerty.keypad("Space"), None);
+    }
+
+    #[test]
+    fn rom_sections_rebind_over_the_layout() {
+        let file = "
+            [layout mine]
+            1 = Key1 Up  # two host keys
+            2 = Key2
+
+            [rom pong2.c8]
+            layout = mine
+            2 = W
+            3 = Up
+        ";
+        let keymap = Keymap::load(Some(("keymaps.cfg", file)), None, "roms/pong2.c8").unwrap();
+        assert_eq!(keymap.keypad("Key1"), Some(1));
+        // Up moved to 3, Key2 lost 2 to W
+        assert_eq!(keymap.keypad("Up"), Some(3));
+        assert_eq!(keymap.keypad("W"), Some(2));
+        assert_eq!(keymap.keypad("Key2"), None);
+        // --keymap wins over the ROM's layout but the ROM's keys still apply
+        let keymap = Keymap::load(Some(("keymaps.cfg", file)), Some("qwerty"), "pong2.c8").unwrap();
+        assert_eq!((keymap.keypad("Q"), keymap.keypad("W"), keymap.keypad("Up")), (Some(4), Some(2), Some(3)));
+        assert_eq!(keymap.keypad("Key2"), None);
+        // other ROMs get the plain layout
+        let keymap = Keymap::load(Some(("keymaps.cfg", file)), None, "other.ch8").unwrap();
+        assert_eq!((keymap.keypad("Key2"), keymap.keypad("W")), (Some(2), Some(5)));
+    }
+
+    #[test]
+    fn errors_name_the_line() {
+        let load = |text: &str| Keymap::load(Some(("k.cfg", text)), None, "game.ch8").unwrap_err();
+        assert_eq!(load("[layout a]\n\n1 = Nope"), "k.cfg:3: unknown host key Nope");
+        assert_eq!(load("[layout a]\nG = Q"), "k.cfg:2: G is not a keypad key 0-F");
+        assert_eq!(load("[layout a]\n10 = Q"), "k.cfg:2: 10 is not a keypad key 0-F");
+        assert_eq!(load("# keys\n1 = Q"), "k.cfg:2: key binding outside of a section");
+        assert_eq!(load("[keys]"), "k.cfg:1: expected [layout NAME] or [rom FILE], found [keys]");
+        assert_eq!(load("[rom a.ch8]\n1 Q"), "k.cfg:2: expected KEY = HOSTKEYS, found 1 Q");
+        assert_eq!(
+            Keymap::load(None, Some("colemak"), "game.ch8").unwrap_err(),
+            "no keymap layout named colemak"
+        );
+    }
+}
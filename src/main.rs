@@ -1,29 +1,179 @@
 use rand::prelude::*;
-use std::boxed;
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::mpsc::{channel, Receiver};
 
 extern crate device_query;
 
 use device_query::{Keycode, DeviceQuery, DeviceState};
 
-enum Opcodes {
-    Call = 0,
-    JMP = 1,
-    JSR = 2,
-    EQU = 3,
-    NEQ = 4,
-}
+mod assembler;
+mod audio;
+mod common;
+mod dap;
+mod debug;
+mod disasm;
+mod expr;
+mod filter;
+mod gdb;
+mod gif;
+mod graphics;
+mod hud;
+mod instruction;
+mod keymap;
+mod movie;
+mod palette;
+mod remote;
+mod rewind;
+mod terminal;
+mod trace;
+mod turbo;
+
+use audio::{Bell, Fallback, Pipe, Silent, Square, Wav, WavRecorder};
+use dap::Dap;
+use debug::Debugger;
+use disasm::Syntax;
+use filter::{Filter, FilterMode};
+use gdb::Gdb;
+use gif::GifRecorder;
+use graphics::{Kitty, Sixel};
+use hud::{Meter, Status, HUD_COLUMN, KEYPAD_ROWS};
+use instruction::Instruction::{self, *};
+use keymap::Keymap;
+use movie::{Header, Movie};
+use palette::Palette;
+use remote::RemoteInput;
+use rewind::Rewind;
+use terminal::TerminalInput;
+use trace::Trace;
+use turbo::TurboInput;
+
+// Default instructions executed per second, spread over the 60 Hz frames
+const IPS: u64 = 550;
 
 trait Logger {
     fn log(&self, msg: &str);
 }
+// The beeper, on while the sound timer is non-zero
+trait Audio {
+    fn start(&mut self);
+    fn stop(&mut self);
+    // Called once per 60 Hz frame, after start or stop
+    fn frame(&mut self) {}
+    // Something went wrong with the sound, reported once
+    fn warning(&mut self) -> Option<String> {
+        None
+    }
+    // XO-CHIP: the tone plays the pattern's 128 bits over and over, at
+    // 4000 * 2^((pitch - 64) / 48) bits a second. None is the plain tone.
+    fn pattern(&mut self, _pattern: Option<&[u8; 16]>, _pitch: u8) {}
+}
+impl<A: Audio + ?Sized> Audio for Box<A> {
+    fn start(&mut self) {
+        (**self).start();
+    }
+    fn stop(&mut self) {
+        (**self).stop();
+    }
+    fn frame(&mut self) {
+        (**self).frame();
+    }
+    fn warning(&mut self) -> Option<String> {
+        (**self).warning()
+    }
+    fn pattern(&mut self, pattern: Option<&[u8; 16]>, pitch: u8) {
+        (**self).pattern(pattern, pitch);
+    }
+}
+// Pixels handed to a Screen are brightness, 0 is dark and 0xFF fully lit
 trait Screen {
-    fn draw(&self, gfx: &[u8; 64 * 32]);
+    fn draw(&mut self, gfx: &[u8; 64 * 32]);
+    // Called once per 60 Hz frame with the current display, drawn or not
+    fn frame(&mut self, _gfx: &[u8; 64 * 32]) {}
+    fn hotkey(&mut self, _key: Hotkey) {}
+    // Machine state for a status panel, once per frame while one is shown
+    fn status(&mut self, _status: &Status) {}
+    fn shows_status(&self) -> bool {
+        false
+    }
+}
+impl<S: Screen + ?Sized> Screen for Box<S> {
+    fn draw(&mut self, gfx: &[u8; 64 * 32]) {
+        (**self).draw(gfx);
+    }
+    fn frame(&mut self, gfx: &[u8; 64 * 32]) {
+        (**self).frame(gfx);
+    }
+    fn hotkey(&mut self, key: Hotkey) {
+        (**self).hotkey(key);
+    }
+    fn status(&mut self, status: &Status) {
+        (**self).status(status);
+    }
+    fn shows_status(&self) -> bool {
+        (**self).shows_status()
+    }
+}
+impl<I: Input + ?Sized> Input for Box<I> {
+    fn poll(&mut self, frame: u64, events: &mut VecDeque<KeyEvent>) {
+        (**self).poll(frame, events);
+    }
+    fn hotkey(&mut self) -> Option<Hotkey> {
+        (**self).hotkey()
+    }
+    fn keypad(&mut self, keys: &[u8; 16]) {
+        (**self).keypad(keys);
+    }
 }
 trait Input {
-    fn update_keys(&self, keys: &mut [u8; 16], last: &mut Option<u8>);
+    // Queues the keypad presses and releases since the last poll, in the
+    // order they happened, stamped with the current frame
+    fn poll(&mut self, frame: u64, events: &mut VecDeque<KeyEvent>);
+    // Frontend keys that are not part of the keypad, reported once per press
+    fn hotkey(&mut self) -> Option<Hotkey> {
+        None
+    }
+    // The keypad the machine runs this frame with, once the events and any
+    // movie have been applied
+    fn keypad(&mut self, _keys: &[u8; 16]) {}
+}
+
+// A debugger, --debug, --gdb or --dap, that the machine stops in
+trait Hook {
+    // Called before every instruction, stops there when something asks to
+    fn check(&mut self, chip: &mut Chip8);
+    // Called after every instruction, to see what it did
+    fn after(&mut self, _chip: &Chip8) {}
 }
 
-struct Console {}
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct KeyEvent {
+    key: u8,
+    down: bool,
+    // frame the event happened on, the core applies it no earlier
+    frame: u64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Hotkey {
+    Record,
+    Hud,
+    Quit,
+    // held down and let go
+    Rewind(bool),
+}
+
+struct Console {
+    palette: Palette,
+    hud: bool,
+    // rows the panel used last frame, cleared when it shrinks
+    hud_rows: usize,
+    // the keypad overlay, above the panel
+    keypad: bool,
+    keymap: Keymap,
+    // key changes from the polling thread, started on first poll
+    device: Option<Receiver<(Keycode, bool)>>,
+    hotkeys: VecDeque<Hotkey>,
+}
 
 impl Logger for Console {
     fn log(&self, msg: &str) {
@@ -31,27 +181,84 @@ impl Logger for Console {
     }
 }
 impl Console {
-    fn new() -> Self {
+    fn new(palette: Palette) -> Self {
         print!("\x1B[2J");
-        Console {}
+        Console {
+            palette,
+            hud: false,
+            hud_rows: 0,
+            keypad: false,
+            keymap: Keymap::default(),
+            device: None,
+            hotkeys: VecDeque::new(),
+        }
     }
 }
 impl Screen for Console {
-    fn draw(&self, gfx: &[u8; 64 * 32]) {
+    fn draw(&mut self, gfx: &[u8; 64 * 32]) {
         print!("\x1B[1;1H");
         for row in 0..32 {
             for col in 0..64 {
-                if gfx[col as usize + row as usize * 64] == 1 {
-                    print!("\x1b[0;47;1m \x1b[0m",);
-                } else {
-                    print!(" ");
+                let [r, g, b] = self.palette.shade(gfx[col + row * 64]);
+                print!("\x1b[48;2;{};{};{}m \x1b[0m", r, g, b);
+            }
+            println!();
+        }
+    }
+    fn hotkey(&mut self, key: Hotkey) {
+        if key == Hotkey::Hud {
+            self.hud = !self.hud;
+            if !self.hud {
+                self.clear_hud(self.hud_top());
+            }
+        }
+    }
+    fn status(&mut self, status: &Status) {
+        if self.keypad {
+            let hosts = std::array::from_fn(|key| {
+                let host = self.keymap.host(key as u8).unwrap_or("");
+                // Key1 is labelled 1 and Numpad1 N1 to fit the cell
+                match (host.strip_prefix("Key"), host.strip_prefix("Numpad")) {
+                    (Some(rest), _) if !rest.is_empty() => rest.to_string(),
+                    (_, Some(rest)) => format!("N{}", rest),
+                    _ => host.to_string(),
                 }
+            });
+            for (row, line) in hud::keypad(&status.keys, &hosts).iter().enumerate() {
+                print!("\x1B[{};{}H{}", row + 1, HUD_COLUMN, line);
             }
-            println!("");
         }
+        if !self.hud {
+            return;
+        }
+        let top = self.hud_top();
+        let lines = status.lines();
+        for (row, line) in lines.iter().enumerate() {
+            print!("\x1B[{};{}H{:<24}", top + row + 1, HUD_COLUMN, line);
+        }
+        self.clear_hud(top + lines.len());
+    }
+    fn shows_status(&self) -> bool {
+        self.keypad || self.hud
     }
 }
-/// Keypad                   Keyboard
+impl Console {
+    // Row the panel starts after, below the keypad when it is shown
+    fn hud_top(&self) -> usize {
+        if self.keypad {
+            KEYPAD_ROWS + 1
+        } else {
+            0
+        }
+    }
+    fn clear_hud(&mut self, from: usize) {
+        for row in from..self.hud_rows {
+            print!("\x1B[{};{}H\x1B[K", row + 1, HUD_COLUMN);
+        }
+        self.hud_rows = from;
+    }
+}
+/// Keypad                   Keyboard (qwerty keymap)
 // +-+-+-+-+                +-+-+-+-+
 // |1|2|3|C|                |1|2|3|4|
 // +-+-+-+-+                +-+-+-+-+
@@ -62,46 +269,71 @@ impl Screen for Console {
 // |A|0|B|F|                |Z|X|C|V|
 // +-+-+-+-+                +-+-+-+-+
 impl Input for Console {
-    fn update_keys(&self, emu_keys: &mut [u8;16], last: &mut Option<u8>) {
-        let device_state = DeviceState::new();
-        let keys: Vec<Keycode> = device_state.get_keys();
-        *last = None;
-        
-        let keymap:[Keycode;16] = [
-            Keycode::X,    
-            Keycode::Key1,
-            Keycode::Key2,
-            Keycode::Key3,
-            Keycode::Q,
-            Keycode::W,
-            Keycode::E,
-            Keycode::A,
-            Keycode::S,
-            Keycode::D,
-            Keycode::Z,
-            Keycode::C,
-            Keycode::Key4,
-            Keycode::R,
-            Keycode::F,
-            Keycode::V
-        ];
-        for elem in emu_keys.iter_mut() { *elem = 0; }
-
-        for key in keys.iter() {
-            let pos = keymap.iter().position(|k| k==key );
-            match pos {
-                Some (i) => {
-                    emu_keys[i] = 0xff;
-                    if last.is_none() {
-                        *last = Some(i as u8);
+    fn poll(&mut self, frame: u64, events: &mut VecDeque<KeyEvent>) {
+        let device = self.device.get_or_insert_with(|| {
+            // Polls far faster than the 60 Hz frames so short taps are seen
+            let (send, receive) = channel();
+            std::thread::spawn(move || {
+                let device_state = DeviceState::new();
+                let mut held: Vec<Keycode> = Vec::new();
+                loop {
+                    let keys = device_state.get_keys();
+                    let pressed = keys.iter().filter(|k| !held.contains(k)).map(|k| (k.clone(), true));
+                    let released = held.iter().filter(|k| !keys.contains(k)).map(|k| (k.clone(), false));
+                    for change in pressed.chain(released) {
+                        if send.send(change).is_err() {
+                            return;
+                        }
+                    }
+                    held = keys;
+                    std::thread::sleep(std::time::Duration::from_millis(1));
+                }
+            });
+            receive
+        });
+        for (keycode, down) in device.try_iter() {
+            match (keycode, down) {
+                (Keycode::F9, true) => self.hotkeys.push_back(Hotkey::Record),
+                (Keycode::F1, true) => self.hotkeys.push_back(Hotkey::Hud),
+                (Keycode::F2, down) => self.hotkeys.push_back(Hotkey::Rewind(down)),
+                (keycode, down) => {
+                    if let Some(key) = self.keymap.keypad(&keycode.to_string()) {
+                        events.push_back(KeyEvent { key, down, frame });
                     }
                 }
-                _ => {}
             }
         }
     }
+    fn hotkey(&mut self) -> Option<Hotkey> {
+        self.hotkeys.pop_front()
+    }
+}
+
+// Behaviors that differ between interpreters, off unless asked for
+#[derive(Clone, Copy, Debug, Default)]
+struct Quirks {
+    // DXYN waits for the next display interrupt like the COSMAC VIP
+    display_wait: bool,
+}
+
+impl Quirks {
+    fn enable(&mut self, name: &str) -> bool {
+        match name {
+            "display-wait" => self.display_wait = true,
+            _ => return false,
+        }
+        true
+    }
+    fn names(&self) -> Vec<String> {
+        let mut names = Vec::new();
+        if self.display_wait {
+            names.push(String::from("display-wait"));
+        }
+        names
+    }
 }
 
+#[allow(non_snake_case)]
 struct Chip8 {
     opcode: u16,
     memory: [u8; 4096],
@@ -111,21 +343,53 @@ struct Chip8 {
     pc: u16,
     stack: [u16; 16],
     sp: u16,
+    // calls allowed to nest, at most 16, and the deepest they went this run
+    stack_depth: u16,
+    deepest: u16,
+    // label names and source lines for addresses, from assembling Octo source
+    symbols: BTreeMap<u16, String>,
+    lines: BTreeMap<u16, usize>,
     // hardware
     gfx: [u8; 64 * 32], // 2K 2048 pixels
     hgr: bool,
+    // both count down once per 60 Hz frame
     delay_timer: u8,
-    delay_start: Option<std::time::SystemTime>,
     sound_timer: u8,
-    sound_start: Option<std::time::SystemTime>,
+    // XO-CHIP sound, None until a program loads a pattern
+    pattern: Option<[u8; 16]>,
+    pitch: u8,
+    rng: StdRng,
     key: [u8; 16],
-    last_key: Option<u8>,
+    key_events: VecDeque<KeyEvent>,
+    // FX0A in progress: keys seen down so far and the one pressed since
+    key_wait: Option<([u8; 16], Option<u8>)>,
     // flags
     draw_flag: bool,
+    frames: u64,
+    // instructions run since power on, and since this frame started
+    cycles: u64,
+    frame_cycles: u64,
+    // waiting for the display interrupt, nothing more runs this frame
+    vblank_wait: bool,
+    ips: u64,
+    quirks: Quirks,
+    meter: Meter,
+    movie: Option<Movie>,
+    // the tone is on, sound_timer was non-zero at the end of last frame
+    beeping: bool,
+    // --debug, --gdb or --dap
+    hook: Option<Box<dyn Hook>>,
+    // an instruction that couldn't run, left at pc for the debugger to show
+    fault: Option<String>,
+    trace: Option<Trace>,
+    rewind: Option<Rewind>,
+    // the rewind hotkey is held, frames go backwards
+    rewinding: bool,
     //
     log: Box<dyn Logger>,
     screen: Box<dyn Screen>,
     input: Box<dyn Input>,
+    audio: Box<dyn Audio>,
 }
 
 impl Chip8 {
@@ -140,32 +404,71 @@ impl Chip8 {
             pc: 0x200,
             stack: [0; 16],
             sp: 0,
+            stack_depth: 16,
+            deepest: 0,
+            symbols: BTreeMap::new(),
+            lines: BTreeMap::new(),
             gfx: [0; 64 * 32],
             hgr: false,
             delay_timer: 0,
             sound_timer: 0,
+            pattern: None,
+            pitch: 64,
+            rng: StdRng::from_entropy(),
             key: [0; 16],
-            last_key: None,
+            key_events: VecDeque::new(),
+            key_wait: None,
             draw_flag: false,
+            frames: 0,
+            cycles: 0,
+            frame_cycles: 0,
+            vblank_wait: false,
+            ips: IPS,
+            quirks: Quirks::default(),
+            meter: Meter::new(),
+            movie: None,
+            beeping: false,
+            hook: None,
+            fault: None,
+            trace: None,
+            rewind: None,
+            rewinding: false,
             log,
             screen,
             input,
-            delay_start: None,
-            sound_start: None,
+            audio: Box::new(Silent),
         }
     }
-    fn load(&mut self, name: &str) -> bool {
+    // The hash of the ROM loaded, None when it can't be
+    fn load(&mut self, name: &str) -> Option<u64> {
         self.font();
-        match std::fs::read(name) {
+        // Octo source is assembled on the way in
+        let rom = if name.ends_with(".8o") {
+            match std::fs::read_to_string(name).map(|text| assembler::assemble(name, &text)) {
+                Ok(Ok(program)) => {
+                    self.symbols = program.labels;
+                    self.lines = program.lines;
+                    Ok(program.rom)
+                }
+                Ok(Err(e)) => {
+                    self.log(&e);
+                    return None;
+                }
+                Err(e) => Err(e),
+            }
+        } else {
+            std::fs::read(name)
+        };
+        match rom {
             Ok(buffer) => {
                 for (i, b) in buffer.iter().enumerate() {
                     self.memory[i + 0x200] = *b;
                 }
-                true
+                Some(movie::hash(&buffer))
             }
             _ => {
                 self.log(&format!("file not found {}", name));
-                false
+                None
             }
         }
     }
@@ -196,6 +499,9 @@ impl Chip8 {
     } 
     
     fn draw_x_y_low(&mut self, x: u8, y: u8, n: u8) {
+        if self.quirks.display_wait {
+            self.vblank_wait = true;
+        }
         if self.hgr && n == 0 {
             self.draw_x_y_high(x, y, n);
             return;    
@@ -220,7 +526,7 @@ impl Chip8 {
         self.draw_flag = true;
         self.pc += 2;
     }
-    fn draw_x_y_high(&mut self, x: u8, y: u8, n: u8) {
+    fn draw_x_y_high(&mut self, x: u8, y: u8, _n: u8) {
         let x = self.V[x as usize];
         let y = self.V[y as usize];
         self.V[0xf] = 0;
@@ -244,38 +550,14 @@ impl Chip8 {
         self.draw_flag = true;
         self.pc += 2;
     }
-    fn get_delay(&mut self, x: u8) {
-        if let Some(time) = self.delay_start {
-            if let Ok(elapsed) = time.elapsed() {
-                let as_hertz:u8 = ((elapsed.as_millis() * 60) / 1000) as u8;
-                print!(
-                    "\x1B[3;71Hpc: Elapsed {} Delay {}",
-                    &as_hertz, self.delay_timer
-                );
-                if as_hertz >= self.delay_timer {
-                    self.V[x as usize] = 0;
-                } else {
-                    self.V[x as usize] = self.delay_timer - as_hertz;
-                }
-            }
-        }
-        self.pc += 2;
+    fn delay(&self) -> u8 {
+        self.delay_timer
     }
-    fn get_sound_delay(&mut self, x: u8) {
-        if let Some(time) = self.sound_start {
-            if let Ok(elapsed) = time.elapsed() {
-                let as_hertz:u8 = ((elapsed.as_millis() * 60) / 1000) as u8;
-                print!(
-                    "\x1B[4;71Hpc: Elapsed {} Sound {}",
-                    &as_hertz, self.sound_timer
-                );
-                if as_hertz > self.sound_timer {
-                    self.V[x as usize] = 0;
-                } else {
-                    self.V[x as usize] = self.sound_timer - as_hertz;
-                }
-            }
-        }
+    fn sound(&self) -> u8 {
+        self.sound_timer
+    }
+    fn get_delay(&mut self, x: u8) {
+        self.V[x as usize] = self.delay();
         self.pc += 2;
     }
     // Skip the follow instruction if VX == NN
@@ -301,15 +583,29 @@ impl Chip8 {
         }
     }
     fn start_delay(&mut self, x: u8) {
-        self.delay_start = Some(std::time::SystemTime::now());
         self.delay_timer = self.V[x as usize];
         self.pc += 2;
     }
     fn start_sound_delay(&mut self, x: u8) {
-        self.sound_start = Some(std::time::SystemTime::now());
         self.sound_timer = self.V[x as usize];
         self.pc += 2;
     }
+    fn load_pattern(&mut self) {
+        let mut pattern = [0; 16];
+        for (n, byte) in pattern.iter_mut().enumerate() {
+            *byte = self.memory[(self.I as usize + n) & 0xFFF];
+        }
+        self.pattern = Some(pattern);
+        self.audio.pattern(Some(&pattern), self.pitch);
+        self.pc += 2;
+    }
+    fn set_pitch(&mut self, x: u8) {
+        self.pitch = self.V[x as usize];
+        if self.pattern.is_some() {
+            self.audio.pattern(self.pattern.as_ref(), self.pitch);
+        }
+        self.pc += 2;
+    }
     fn set_i(&mut self, nnn: u16) {
         self.I = nnn;
         self.pc += 2;
@@ -319,12 +615,9 @@ impl Chip8 {
         self.pc += 2;
     }
     fn add_v(&mut self, x: u8, nn: u8) {
-        match self.V[x as usize].overflowing_add(nn) {
-            (v, _) => {
-                self.V[x as usize] = v;
-                self.V[0xF] = 0;
-            }
-        }
+        let (v, _) = self.V[x as usize].overflowing_add(nn);
+        self.V[x as usize] = v;
+        self.V[0xF] = 0;
         self.pc += 2;
     }
     fn set_v_v(&mut self, x: u8, y: u8) {
@@ -333,17 +626,17 @@ impl Chip8 {
     }
     // Set Vx to Vx OR Vy
     fn vx_or_vy(&mut self, x: u8, y: u8) {
-        self.V[x as usize] = self.V[x as usize] | self.V[y as usize];
+        self.V[x as usize] |= self.V[y as usize];
         self.pc += 2;
     }
     // Set Vx to Vx OR Vy
     fn vx_and_vy(&mut self, x: u8, y: u8) {
-        self.V[x as usize] = self.V[x as usize] & self.V[y as usize];
+        self.V[x as usize] &= self.V[y as usize];
         self.pc += 2;
     }
     // Set Vx to Vx OR Vy
     fn vx_xor_vy(&mut self, x: u8, y: u8) {
-        self.V[x as usize] = self.V[x as usize] ^ self.V[y as usize];
+        self.V[x as usize] ^= self.V[y as usize];
         self.pc += 2;
     }
     fn vx_add_vy_carry(&mut self, x:u8, y:u8) {
@@ -421,11 +714,27 @@ impl Chip8 {
         self.pc = self.V[0] as u16 + nnn;
     }
     fn jsr(&mut self, nnn:u16) {
+        if self.sp >= self.stack_depth {
+            self.fault(format!("Stack overflow at {:04X}, calls nest deeper than {}", self.pc, self.stack_depth));
+            return;
+        }
+        let at = self.pc;
         self.stack[self.sp as usize] = self.pc;
         self.pc = nnn;
         self.sp += 1;
+        if self.sp > self.deepest {
+            self.deepest = self.sp;
+            // once, on first getting within two calls of the limit
+            if self.sp == self.stack_depth.saturating_sub(2).max(1) {
+                self.log(&format!("Stack depth {} of {} at {:04X}", self.sp, self.stack_depth, at));
+            }
+        }
     }
     fn ret(&mut self) {
+        if self.sp == 0 {
+            self.fault(format!("Return with an empty stack at {:04X}", self.pc));
+            return;
+        }
         self.pc = self.stack[(self.sp - 1) as usize];
         self.sp -= 1;
         // Returned to last PC, need to advance
@@ -434,11 +743,21 @@ impl Chip8 {
     fn exit(&mut self) {
         self.pc = 0xFFFF;
     }
+    // Stops in the debugger with pc still on the instruction, or ends the
+    // run when there is none
+    fn fault(&mut self, message: String) {
+        if self.hook.is_some() {
+            self.fault = Some(message);
+        } else {
+            self.log(&message);
+            self.exit();
+        }
+    }
     fn native_call(&mut self, nnn: u16) {
-        self.log("Machine language??");
+        self.log(&format!("Machine language?? {:03X}", nnn));
     }
     fn vx_rnd(&mut self, x:u8, nn: u8) {
-        self.V[x as usize] = random::<u8>() & nn;
+        self.V[x as usize] = self.rng.gen::<u8>() & nn;
         self.pc += 2;
     }
     fn i_as_sprite_vx(&mut self, x:u8) {
@@ -451,11 +770,11 @@ impl Chip8 {
     }
 
     fn vx_as_bcd(&mut self, x: u8) {
-        let I = self.I as usize;
+        let i = self.I as usize;
         let x = x as usize;
-        self.memory[I] = (self.V[x] / 100) as u8;
-        self.memory[I + 1] = (self.V[x] / 10) % 10 as u8;
-        self.memory[I + 2] = (self.V[x] % 10) as u8;
+        self.memory[i] = self.V[x] / 100;
+        self.memory[i + 1] = (self.V[x] / 10) % 10;
+        self.memory[i + 2] = self.V[x] % 10;
         self.pc += 2;
     }
 
@@ -507,113 +826,314 @@ impl Chip8 {
             self.pc += 2;
         }
     }
+    // Like the COSMAC VIP, wait for a key to go down and come back up.
+    // Keys already held when the wait starts have to be let go first.
     fn wait_for_next_key(&mut self, x: u8) {
-        // TODO: KET PRESS
-        //
-        if let Some(key) = self.last_key {
-            self.V[x as usize] = key;
-            self.pc += 2;
+        let (seen, pressed) = self.key_wait.unwrap_or((self.key, None));
+        let pressed = pressed.or_else(|| {
+            (0..16u8).find(|&k| self.key[k as usize] != 0 && seen[k as usize] == 0)
+        });
+        match pressed {
+            Some(key) if self.key[key as usize] == 0 => {
+                self.V[x as usize] = key;
+                self.key_wait = None;
+                self.pc += 2;
+            }
+            _ => self.key_wait = Some((self.key, pressed)),
         }
-        
     }
-
-
-
-
+    fn waiting_for_key(&self) -> bool {
+        self.key_wait.is_some()
+    }
 
     fn emulate_cycle(&mut self) -> bool {
         // fetch opcode
         let b0 = self.memory[(self.pc) as usize];
         let b1 = self.memory[(self.pc + 1) as usize];
-        let n0 = b0 >> 4;
-        let n1 = b0 & 0x0F;
-        let n2 = b1 >> 4;
-        let n3 = b1 & 0x0F;
-        let n = n3;
-        let nn = b1;
-        let nnn: u16 = (n1 as u16) << 8 | nn as u16;
-        //let pre_pc = self.pc;
-        print!("\x1B[1;71Hpc: {} {}:{}:{}:{}", self.pc, n0, n1, n2, n3);
-        // decode Opcode
-        // Match based on the 4 bytes
-        match (n0, n1, n2, n3) {
-            (0, 0, 0xC, x) => self.scroll_down(x),
-            (0, 0, 0xF, 0xB) => self.scroll_right(),
-            (0, 0, 0xF, 0xC) => self.scroll_left(),
-            (0, 0, 0xF, 0xD) => self.exit(),
-            (0, 0, 0xF, 0xE) => self.hgr(false),
-            (0, 0, 0xF, 0xF) => self.hgr(true),
-            (0, 0, 0xE, 0) => self.draw_clear(),
-            (0, 0, 0xE, 0xE) => self.ret(),
-            (0, _, _, _) => self.native_call(nnn),
-            (1, _, _, _) => self.jmp(nnn),
-            (2, _, _, _) => self.jsr(nnn),
-            (3, x, _, _) => self.if_vx_eq_nn(x,nn),
-            (4, x, _, _) => self.if_not_eq(x, nn),
-            (5, x, y, 0) => self.if_eq(x, y),
-            (6, x, _, _) => self.set_v(x, nn),
-            (7, x, _, _) => self.add_v(x, nn),
-            (8, x, y, 0) => self.set_v_v(x, y),
-            (8, x, y, 1) => self.vx_or_vy(x, y),
-            (8, x, y, 2) => self.vx_and_vy(x, y),
-            (8, x, y, 3) => self.vx_xor_vy(x, y),
-            (8, x, y, 4) => self.vx_add_vy_carry(x,y),
-            (8, x, y, 5) => self.vx_sub_vy_borrow(x,y),
-            (8, x, y, 6) => self.vx_as_rshift_vy(x,y),
-            (8, x, y, 7) => self.vy_sub_vx_borrow(x,y),
-            (8, x, y, 0xE) => self.vx_as_lshift_vy(x,y),
-            (9, x, y, 0) => self.if_vx_eq_vy(x,y),
-            (0xA, _, _, _) => self.set_i(nnn),
-            (0xB, _, _, _) => self.jmp_v0(nnn),
-            (0xC, x, _, _) => self.vx_rnd(x,nn),
-            (0xD, x, y, n) => self.draw_x_y_low(x, y, n),
-            (0xE, x, 9, 0xE) => self.skip_if_key_vx(x),
-            (0xE, x, 0xA, 1) => self.skip_if_not_key_vx(x),
-            (0xF, x, 0, 7) => self.get_delay(x),
-            (0xF, x, 0, 0xA) => self.wait_for_next_key(x),
-            (0xF, x, 1, 5) => self.start_delay(x),
-            (0xF, x, 1, 8) => self.start_sound_delay(x),
-            (0xF, x, 1, 0xE) => self.i_add_vx(x),
-            (0xF, x, 2, 9) => self.i_as_sprite_vx(x),
-            (0xF, x, 3, 0) => self.i_as_hgr_sprite_vx(x),
-            (0xF, x, 3, 3) => self.vx_as_bcd(x),
-            (0xF, x, 5, 5) => self.store_v0_vx(x),
-            (0xF, x, 6, 5) => self.read_v0_vx(x),
-            (0xF, x, 7, 5) => self.store_rpl_v0_vx(x),
-            (0xF, x, 8, 5) => self.read_rpl_v0_vx(x),
-            _ => {
+        self.opcode = (b0 as u16) << 8 | b1 as u16;
+        let instruction = match Instruction::decode(self.opcode) {
+            Some(instruction) => instruction,
+            None => {
                 self.log("Unknown Opcode");
+                return true;
             }
+        };
+        match instruction {
+            ScrollDown(x) => self.scroll_down(x),
+            ScrollRight => self.scroll_right(),
+            ScrollLeft => self.scroll_left(),
+            Exit => self.exit(),
+            Low => self.hgr(false),
+            High => self.hgr(true),
+            Clear => self.draw_clear(),
+            Return => self.ret(),
+            Native(nnn) => self.native_call(nnn),
+            Jump(nnn) => self.jmp(nnn),
+            Call(nnn) => self.jsr(nnn),
+            SkipEqual(x, nn) => self.if_vx_eq_nn(x, nn),
+            SkipNotEqual(x, nn) => self.if_not_eq(x, nn),
+            SkipEqualV(x, y) => self.if_eq(x, y),
+            Set(x, nn) => self.set_v(x, nn),
+            Add(x, nn) => self.add_v(x, nn),
+            SetV(x, y) => self.set_v_v(x, y),
+            Or(x, y) => self.vx_or_vy(x, y),
+            And(x, y) => self.vx_and_vy(x, y),
+            Xor(x, y) => self.vx_xor_vy(x, y),
+            AddV(x, y) => self.vx_add_vy_carry(x, y),
+            Sub(x, y) => self.vx_sub_vy_borrow(x, y),
+            ShiftRight(x, y) => self.vx_as_rshift_vy(x, y),
+            SubFrom(x, y) => self.vy_sub_vx_borrow(x, y),
+            ShiftLeft(x, y) => self.vx_as_lshift_vy(x, y),
+            SkipNotEqualV(x, y) => self.if_vx_eq_vy(x, y),
+            SetI(nnn) => self.set_i(nnn),
+            JumpV0(nnn) => self.jmp_v0(nnn),
+            Random(x, nn) => self.vx_rnd(x, nn),
+            Draw(x, y, n) => self.draw_x_y_low(x, y, n),
+            SkipKey(x) => self.skip_if_key_vx(x),
+            SkipNotKey(x) => self.skip_if_not_key_vx(x),
+            GetDelay(x) => self.get_delay(x),
+            WaitKey(x) => self.wait_for_next_key(x),
+            SetDelay(x) => self.start_delay(x),
+            SetSound(x) => self.start_sound_delay(x),
+            AddI(x) => self.i_add_vx(x),
+            Font(x) => self.i_as_sprite_vx(x),
+            BigFont(x) => self.i_as_hgr_sprite_vx(x),
+            Bcd(x) => self.vx_as_bcd(x),
+            Store(x) => self.store_v0_vx(x),
+            Read(x) => self.read_v0_vx(x),
+            StoreFlags(x) => self.store_rpl_v0_vx(x),
+            ReadFlags(x) => self.read_rpl_v0_vx(x),
+            Pattern => self.load_pattern(),
+            Pitch(x) => self.set_pitch(x),
         }
         true
     }
-    fn set_keys(&mut self) {}
 
+    // One instruction of a frame, run or replayed
     fn run_tick(&mut self) -> bool {
-        let ret = self.emulate_cycle();
-        ret
+        let pc = self.pc;
+        let waited = self.waiting_for_key();
+        let ran = self.emulate_cycle();
+        self.cycles += 1;
+        self.frame_cycles += 1;
+        // a key wait still waiting was traced when it started
+        let spun = waited && self.waiting_for_key();
+        if let Some(trace) = self.trace.as_mut().filter(|_| !spun) {
+            if let Some(msg) = trace.instruction(self.cycles, self.frames, pc, self.opcode, &self.V, self.I) {
+                self.log(&msg);
+            }
+        }
+        ran
+    }
+
+    // One 60 Hz frame: poll the keys, run this frame's share of IPS and present.
+    // A draw under the display-wait quirk gives up the rest of the frame.
+    fn run_frame(&mut self) {
+        self.input.poll(self.frames, &mut self.key_events);
+        self.apply_key_events();
+        let (frame, keys) = (self.frames, &mut self.key);
+        if let Some(msg) = self.movie.as_mut().and_then(|m| m.keys(frame, keys)) {
+            self.log(&msg);
+        }
+        self.input.keypad(&self.key);
+        match self.input.hotkey() {
+            Some(Hotkey::Quit) => {
+                self.exit();
+                return;
+            }
+            Some(Hotkey::Rewind(down)) => self.rewinding = down,
+            Some(key) => self.screen.hotkey(key),
+            None => {}
+        }
+        if self.rewinding {
+            self.rewind_frame();
+            return;
+        }
+        self.vblank_wait = false;
+        self.frame_cycles = 0;
+        if let Some(mut rewind) = self.rewind.take() {
+            rewind.record(self);
+            self.rewind = Some(rewind);
+        }
+        // the debugger can go back into an earlier frame, so this frame's
+        // share is worked out again each time round
+        while self.frame_cycles < (self.frames + 1) * self.ips / 60 - self.frames * self.ips / 60 {
+            if self.pc == 0xFFFF || self.vblank_wait {
+                break;
+            }
+            if let Some(mut hook) = self.hook.take() {
+                hook.check(self);
+                self.hook = Some(hook);
+                if self.pc == 0xFFFF {
+                    break;
+                }
+            }
+            // no client to stop for, so the fault ends the run after all
+            if let Some(message) = self.fault.take() {
+                self.log(&message);
+                self.exit();
+                break;
+            }
+            self.run_tick();
+            if let Some(mut hook) = self.hook.take() {
+                hook.after(self);
+                self.hook = Some(hook);
+            }
+        }
+        // a sound timer of N sounds for N frames
+        self.beep(self.sound_timer > 0);
+        self.audio.frame();
+        if let Some(msg) = self.audio.warning() {
+            self.log(&msg);
+        }
+        self.delay_timer = self.delay_timer.saturating_sub(1);
+        self.sound_timer = self.sound_timer.saturating_sub(1);
+        let sum = self.checksum();
+        if let Some(msg) = self.movie.as_mut().and_then(|m| m.check(frame, sum)) {
+            self.log(&msg);
+        }
+        let display = self.display();
+        let drawn = self.draw_flag;
+        if self.draw_flag {
+            self.screen.draw(&display);
+            self.draw_flag = false;
+        }
+        self.screen.frame(&display);
+        self.meter.count(self.frame_cycles, drawn as u64);
+        self.show_status();
+        self.frames += 1;
+    }
+
+    fn beep(&mut self, on: bool) {
+        if self.beeping != on {
+            self.beeping = on;
+            if on {
+                self.audio.start();
+            } else {
+                self.audio.stop();
+            }
+        }
+    }
+
+    // A frame of the rewind hotkey: back to the start of the frame before and
+    // show it. The keypad stays as it is now so keys let go meanwhile are not
+    // held again once the machine runs on.
+    fn rewind_frame(&mut self) {
+        let (key, mut events) = (self.key, std::mem::take(&mut self.key_events));
+        let went = match self.rewind.take() {
+            Some(mut rewind) => {
+                let went = rewind.back_frame(self);
+                self.rewind = Some(rewind);
+                went
+            }
+            None => false,
+        };
+        // silent while going back
+        self.beep(false);
+        self.audio.frame();
+        self.key = key;
+        for event in events.iter_mut() {
+            event.frame = self.frames;
+        }
+        self.key_events = events;
+        let display = self.display();
+        if went {
+            self.screen.draw(&display);
+            self.draw_flag = false;
+        }
+        self.screen.frame(&display);
+        self.meter.count(0, went as u64);
+        self.show_status();
+    }
+
+    // Keys change only at frame boundaries so EX9E, EXA1 and FX0A see one
+    // state all frame. A key that changes twice, like a tap shorter than a
+    // frame, gets its second change next frame so the press is never lost.
+    fn apply_key_events(&mut self) {
+        let mut changed = [false; 16];
+        while let Some(event) = self.key_events.front() {
+            let key = event.key as usize;
+            if changed[key] || event.frame > self.frames {
+                break;
+            }
+            changed[key] = true;
+            self.key[key] = if event.down { 0xFF } else { 0 };
+            self.key_events.pop_front();
+        }
+    }
+
+    // Everything that decides what the machine does next
+    fn checksum(&self) -> u64 {
+        let mut state = Vec::with_capacity(4096 + 64 * 32 + 64);
+        state.extend_from_slice(&self.memory);
+        state.extend_from_slice(&self.gfx);
+        state.extend_from_slice(&self.V);
+        state.extend_from_slice(&self.R);
+        for word in [self.I, self.pc, self.sp].iter().chain(self.stack.iter()) {
+            state.extend_from_slice(&word.to_le_bytes());
+        }
+        state.extend_from_slice(&[self.delay_timer, self.sound_timer, self.hgr as u8]);
+        movie::hash(&state)
+    }
+
+    fn show_status(&mut self) {
+        if self.screen.shows_status() {
+            let status = self.status();
+            self.screen.status(&status);
+        }
+    }
+
+    fn status(&self) -> Status {
+        let pc = self.pc as usize;
+        let opcode = match self.memory.get(pc..pc + 2) {
+            Some(word) => (word[0] as u16) << 8 | word[1] as u16,
+            None => 0,
+        };
+        Status {
+            pc: self.pc,
+            opcode,
+            v: self.V,
+            i: self.I,
+            stack: self.stack[..self.sp as usize].to_vec(),
+            delay: self.delay(),
+            sound: self.sound(),
+            ips: self.meter.ips,
+            fps: self.meter.fps,
+            waiting_for_key: self.waiting_for_key(),
+            keys: self.key.map(|k| k != 0),
+        }
     }
 
     fn run(&mut self) {
-        let clock = std::time::SystemTime::now();
-        loop {
-            self.input.update_keys(&mut self.key, &mut self.last_key);
-            match clock.elapsed() {
-                Ok(elapsed) => {
-                    let as_hertz = (elapsed.as_millis() * 550) / 1000;
-                    if as_hertz >= 550 {
-                        self.run_tick();
-                        if self.draw_flag {
-                            self.screen.draw(&self.gfx);
-                            self.draw_flag = false;
-                        }
-                    }
+        let mut clock = std::time::Instant::now();
+        // frames presented, going back counts too
+        let mut presented = 0;
+        while self.pc != 0xFFFF {
+            self.run_frame();
+            presented += 1;
+            let due = std::time::Duration::from_micros(presented * 1_000_000 / 60);
+            match due.checked_sub(clock.elapsed()) {
+                Some(wait) => std::thread::sleep(wait),
+                // far behind, like after sitting in the debugger, so start
+                // over from now rather than rushing to catch up
+                None if clock.elapsed() - due > std::time::Duration::from_millis(100) => {
+                    clock = std::time::Instant::now();
+                    presented = 0;
                 }
-                Err(_) => {}
+                None => {}
             }
         }
         // one last screen draw
-        self.screen.draw(&self.gfx);
+        self.screen.draw(&self.display());
+    }
+
+    // gfx as brightness for the screens
+    fn display(&self) -> [u8; 64 * 32] {
+        let mut display = [0; 64 * 32];
+        for (out, pixel) in display.iter_mut().zip(self.gfx.iter()) {
+            *out = if *pixel == 1 { 0xFF } else { 0 };
+        }
+        display
     }
 
     fn log(&self, msg: &str) {
@@ -622,7 +1142,7 @@ impl Chip8 {
     fn font(&mut self) {
         let font = [
         ///////////////////////////////
-        /// 0x50 start of low res
+        // 0x50 start of low res
             0xf0, 0x90, 0x90, 0x90, 0xf0, // 0
             0x20, 0x60, 0x20, 0x20, 0x70, // 1
             0xF0, 0x10, 0xF0, 0x80, 0xF0, // 2
@@ -640,7 +1160,7 @@ impl Chip8 {
             0xf0, 0x80, 0xe0, 0x80, 0xf0, // E
             0xf0, 0x80, 0xf0, 0x80, 0x80, // F
         ///////////////////////////////////////////////////
-        /// 0xD0 start of high res
+        // 0xD0 start of high res
         ////////////////////////////////////////////////////////////////////////
         0x3C, 0x7E, 0xE7, 0xC3, 0xC3, 0xC3, 0xC3, 0xE7, 0x7E, 0x3C, 
         0x18, 0x38, 0x58, 0x18, 0x18, 0x18, 0x18, 0x18, 0x18, 0x3C,
@@ -667,18 +1187,652 @@ impl Chip8 {
 // 0x200- 0xFFF - Program ROM and RAM
 
 fn main() {
-    let args: Vec<String> = std::env::args().collect();
-    let file = if args.len() == 2 {
-        &args[1]
-    } else {
-        "./rom/test_opcode.ch8"
+    let mut file = String::from("./rom/test_opcode.ch8");
+    let mut record: Option<String> = None;
+    let mut palette = Palette::default();
+    let mut filter = FilterMode::None;
+    let mut backend = String::from("console");
+    let mut scale = 4;
+    let mut ips = IPS;
+    let mut quirks = Quirks::default();
+    let mut stack_depth = 16;
+    let mut rewind_seconds: usize = 60;
+    let mut hud = false;
+    let mut keypad = false;
+    let mut sound = String::from("square");
+    let mut wav: Option<String> = None;
+    let mut debug = false;
+    let mut gdb: Option<String> = None;
+    let mut dap: Option<String> = None;
+    let mut trace: Option<String> = None;
+    let mut trace_pc = None;
+    let mut trace_frames = None;
+    let mut keyboard = String::from("device");
+    let mut key_timeout = 700;
+    let mut layout: Option<String> = None;
+    let mut keymaps: Option<String> = None;
+    let mut movie_record: Option<String> = None;
+    let mut movie_play: Option<String> = None;
+    let mut args = std::env::args().skip(1).peekable();
+    if args.next_if_eq("disasm").is_some() {
+        disassemble(args);
+        return;
+    }
+    if args.next_if_eq("asm").is_some() {
+        assemble(args);
+        return;
+    }
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--record" => record = args.next(),
+            "--palette" => match args.next().as_deref().and_then(Palette::parse) {
+                Some(p) => palette = p,
+                None => {
+                    eprintln!("--palette expects ON:OFF as hex colors, e.g. ffffff:000000");
+                    return;
+                }
+            },
+            "--filter" => match args.next().as_deref().and_then(FilterMode::parse) {
+                Some(mode) => filter = mode,
+                None => {
+                    eprintln!("--filter expects none, or2 or phosphor");
+                    return;
+                }
+            },
+            "--screen" => backend = args.next().unwrap_or_default(),
+            "--scale" => match args.next().and_then(|s| s.parse().ok()) {
+                Some(n) if n > 0 => scale = n,
+                _ => {
+                    eprintln!("--scale expects a positive number");
+                    return;
+                }
+            },
+            "--ips" => match args.next().and_then(|s| s.parse().ok()) {
+                Some(n) if n > 0 => ips = n,
+                _ => {
+                    eprintln!("--ips expects a positive number");
+                    return;
+                }
+            },
+            "--stack-depth" => match args.next().and_then(|s| s.parse().ok()).filter(|d| (1..=16).contains(d)) {
+                Some(d) => stack_depth = d,
+                None => {
+                    eprintln!("--stack-depth expects a number from 1 to 16");
+                    return;
+                }
+            },
+            "--rewind" => match args.next().and_then(|s| s.parse().ok()) {
+                Some(seconds) => rewind_seconds = seconds,
+                None => {
+                    eprintln!("--rewind expects seconds, 0 turns it off");
+                    return;
+                }
+            },
+            "--hud" => hud = true,
+            "--keypad" => keypad = true,
+            "--audio" => sound = args.next().unwrap_or_default(),
+            "--wav" => wav = args.next(),
+            "--debug" => debug = true,
+            "--gdb" => gdb = args.next(),
+            "--dap" => dap = args.next(),
+            "--trace" => trace = args.next(),
+            "--trace-pc" => match args.next().as_deref().and_then(|r| trace::range(r, 16)).filter(|r| r.1 <= 0xFFFF) {
+                Some((from, to)) => trace_pc = Some((from as u16, to as u16)),
+                None => {
+                    eprintln!("--trace-pc expects a hex address range like 200-2FF");
+                    return;
+                }
+            },
+            "--trace-frames" => match args.next().as_deref().and_then(|r| trace::range(r, 10)) {
+                Some(range) => trace_frames = Some(range),
+                None => {
+                    eprintln!("--trace-frames expects a frame range like 0-600");
+                    return;
+                }
+            },
+            "--input" => keyboard = args.next().unwrap_or_default(),
+            "--keymap" => layout = args.next(),
+            "--movie-record" => movie_record = args.next(),
+            "--movie-play" => movie_play = args.next(),
+            "--keymaps" => keymaps = args.next(),
+            "--key-timeout" => match args.next().and_then(|s| s.parse().ok()) {
+                Some(ms) => key_timeout = ms,
+                None => {
+                    eprintln!("--key-timeout expects milliseconds");
+                    return;
+                }
+            },
+            "--quirk" => {
+                let name = args.next().unwrap_or_default();
+                if !quirks.enable(&name) {
+                    eprintln!("unknown quirk {}, known quirks: display-wait", name);
+                    return;
+                }
+            }
+            _ => file = arg,
+        }
+    }
+    // keymaps.cfg in the working directory is picked up when there is one
+    let keymaps = keymaps.or_else(|| {
+        let default = String::from("keymaps.cfg");
+        std::path::Path::new(&default).exists().then_some(default)
+    });
+    let text = match &keymaps {
+        Some(path) => match std::fs::read_to_string(path) {
+            Ok(text) => Some(text),
+            Err(e) => {
+                eprintln!("can't read keymaps {}: {}", path, e);
+                return;
+            }
+        },
+        None => None,
+    };
+    let config = keymaps.as_deref().zip(text.as_deref());
+    let keymap = match Keymap::load(config, layout.as_deref(), &file) {
+        Ok(keymap) => keymap,
+        Err(e) => {
+            eprintln!("{}", e);
+            return;
+        }
     };
-    println!("{}", 137 % 10);
-    let all = Box::new(Console {});
-    let screen = Box::new(Console::new());
-    let input = Box::new(Console::new());
+    if keypad && backend != "console" {
+        eprintln!("--keypad draws next to the console screen, use --screen console");
+        return;
+    }
+    let all = Box::new(Console::new(palette));
+    // F9 toggles recording, --record starts it straight away
+    let recording = record.is_some();
+    let path = record.unwrap_or_else(|| String::from("chip8.gif"));
+    let display: Box<dyn Screen> = match backend.as_str() {
+        "console" => {
+            let mut console = Console::new(palette);
+            console.keypad = keypad;
+            console.keymap = keymap.clone();
+            Box::new(console)
+        }
+        "sixel" => Box::new(Sixel::new(palette, scale)),
+        "kitty" => Box::new(Kitty::new(palette, scale)),
+        _ => {
+            eprintln!("--screen expects console, sixel or kitty");
+            return;
+        }
+    };
+    let recorder = GifRecorder::new(display, palette, path, recording);
+    let screen: Box<dyn Screen> = match filter {
+        FilterMode::None => Box::new(recorder),
+        mode => Box::new(Filter::new(recorder, mode)),
+    };
+    if [debug, gdb.is_some(), dap.is_some()].iter().filter(|on| **on).count() > 1 {
+        eprintln!("--debug, --gdb and --dap all want to stop the machine, use one");
+        return;
+    }
+    if debug && keyboard == "terminal" {
+        eprintln!("--debug reads commands from the terminal, use another --input");
+        return;
+    }
+    let input: Box<dyn Input> = match keyboard.as_str() {
+        "device" => {
+            let mut console = Console::new(palette);
+            console.keymap = keymap.clone();
+            Box::new(console)
+        }
+        "terminal" => Box::new(TerminalInput::new(std::time::Duration::from_millis(key_timeout), keymap.clone(), keypad)),
+        socket if socket.starts_with("socket:") => match RemoteInput::listen(&socket["socket:".len()..]) {
+            Ok(remote) => Box::new(remote),
+            Err(e) => {
+                eprintln!("can't listen on {}: {}", socket, e);
+                return;
+            }
+        },
+        _ => {
+            eprintln!("--input expects device, terminal, socket:/path or socket:127.0.0.1:PORT");
+            return;
+        }
+    };
+    // turbo keys and macros apply whatever the keys come from
+    let input = Box::new(TurboInput::new(input, &keymap));
     let mut emu = Chip8::new(all, screen, input);
-    if emu.load(file) {
-        emu.run();
+    // movies know the ROM by what was loaded, .8o source once assembled
+    let rom = match emu.load(&file) {
+        Some(rom) => rom,
+        None => return,
+    };
+    let mut seed = random::<u64>();
+    let mut movie = None;
+    if let Some(path) = &movie_play {
+        match Movie::play(path) {
+            Ok((play, header)) => {
+                // the movie decides everything the replay depends on
+                seed = header.seed;
+                ips = header.ips;
+                stack_depth = header.stack_depth;
+                quirks = Quirks::default();
+                if let Some(name) = header.quirks.iter().find(|name| !quirks.enable(name)) {
+                    eprintln!("{}: unknown quirk {}", path, name);
+                    return;
+                }
+                movie = Some(play);
+            }
+            Err(e) => {
+                eprintln!("{}", e);
+                return;
+            }
+        }
+    } else if let Some(path) = &movie_record {
+        let header = Header {
+            rom,
+            seed,
+            ips,
+            quirks: quirks.names(),
+            stack_depth,
+        };
+        match Movie::record(path, &header) {
+            Ok(record) => movie = Some(record),
+            Err(e) => {
+                eprintln!("can't record movie to {}: {}", path, e);
+                return;
+            }
+        }
+    }
+    emu.ips = ips;
+    emu.quirks = quirks;
+    emu.stack_depth = stack_depth;
+    emu.rng = StdRng::seed_from_u64(seed);
+    let audio: Box<dyn Audio> = match sound.as_str() {
+        "square" => match Pipe::aplay() {
+            Ok(pipe) => Box::new(Fallback::new(Square::new(pipe), Bell)),
+            Err(e) => {
+                emu.log(&format!("can't start aplay ({}), using the terminal bell", e));
+                Box::new(Bell)
+            }
+        },
+        "bell" => Box::new(Bell),
+        "none" => Box::new(Silent),
+        _ => {
+            eprintln!("--audio expects square, bell or none");
+            return;
+        }
+    };
+    emu.audio = match &wav {
+        Some(path) => match Wav::create(path) {
+            Ok(file) => Box::new(WavRecorder::new(audio, file)),
+            Err(e) => {
+                eprintln!("can't create {}: {}", path, e);
+                return;
+            }
+        },
+        None => audio,
+    };
+    if let Some(msg) = movie.as_ref().and_then(|m| m.check_rom(rom)) {
+        emu.log(&msg);
+    }
+    // going back would leave a movie behind
+    if rewind_seconds > 0 && movie.is_none() {
+        emu.rewind = Some(Rewind::new(rewind_seconds * 60));
+    }
+    emu.movie = movie;
+    if debug {
+        emu.hook = Some(Box::new(Debugger::new()));
+    }
+    if let Some(address) = &gdb {
+        match Gdb::listen(address) {
+            Ok(stub) => {
+                emu.log(&format!("waiting for gdb on {}", address));
+                emu.hook = Some(Box::new(stub));
+            }
+            Err(e) => {
+                eprintln!("can't listen on {}: {}", address, e);
+                return;
+            }
+        }
+    }
+    if let Some(address) = &dap {
+        // source breakpoints need the .8o the ROM was assembled from
+        let source = Some(file.as_str()).filter(|f| f.ends_with(".8o"));
+        match Dap::listen(address, source) {
+            Ok(server) => {
+                emu.log(&format!("waiting for a DAP client on {}", address));
+                emu.hook = Some(Box::new(server));
+            }
+            Err(e) => {
+                eprintln!("can't listen on {}: {}", address, e);
+                return;
+            }
+        }
+    }
+    if let Some(path) = &trace {
+        match Trace::create(path) {
+            Ok(mut t) => {
+                t.addresses = trace_pc;
+                t.frames = trace_frames;
+                emu.trace = Some(t);
+            }
+            Err(e) => {
+                eprintln!("can't create {}: {}", path, e);
+                return;
+            }
+        }
+    }
+    if hud {
+        emu.screen.hotkey(Hotkey::Hud);
+    }
+    emu.run();
+}
+
+// chip-great disasm [--syntax octo|classic] rom.ch8
+fn disassemble(mut args: impl Iterator<Item = String>) {
+    let mut syntax = Syntax::Octo;
+    let mut file = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--syntax" => match args.next().as_deref().and_then(Syntax::parse) {
+                Some(s) => syntax = s,
+                None => {
+                    eprintln!("--syntax expects octo or classic");
+                    return;
+                }
+            },
+            _ => file = Some(arg),
+        }
+    }
+    let file = match file {
+        Some(file) => file,
+        None => {
+            eprintln!("usage: chip-great disasm [--syntax octo|classic] rom.ch8");
+            return;
+        }
+    };
+    match std::fs::read(&file) {
+        Ok(rom) => print!("{}", disasm::listing(&rom, syntax)),
+        Err(e) => eprintln!("can't read {}: {}", file, e),
+    }
+}
+
+// chip-great asm prog.8o [-o prog.ch8]
+fn assemble(mut args: impl Iterator<Item = String>) {
+    let mut source = None;
+    let mut out = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-o" => out = args.next(),
+            _ => source = Some(arg),
+        }
+    }
+    let source = match source {
+        Some(source) => source,
+        None => {
+            eprintln!("usage: chip-great asm prog.8o [-o prog.ch8]");
+            return;
+        }
+    };
+    let out = out.unwrap_or_else(|| format!("{}.ch8", source.strip_suffix(".8o").unwrap_or(&source)));
+    let text = match std::fs::read_to_string(&source) {
+        Ok(text) => text,
+        Err(e) => {
+            eprintln!("can't read {}: {}", source, e);
+            return;
+        }
+    };
+    match assembler::assemble(&source, &text) {
+        Ok(program) => {
+            if let Err(e) = std::fs::write(&out, program.rom) {
+                eprintln!("can't write {}: {}", out, e);
+            }
+        }
+        Err(e) => eprintln!("{}", e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    // Keeps what is logged, draws nothing and has no keys of its own
+    #[derive(Clone, Default)]
+    pub(crate) struct Headless(pub(crate) Rc<RefCell<Vec<String>>>);
+
+    impl Logger for Headless {
+        fn log(&self, msg: &str) {
+            self.0.borrow_mut().push(msg.to_string());
+        }
+    }
+    impl Screen for Headless {
+        fn draw(&mut self, _gfx: &[u8; 64 * 32]) {}
+    }
+    impl Input for Headless {
+        fn poll(&mut self, _frame: u64, _events: &mut VecDeque<KeyEvent>) {}
+    }
+
+    // A machine with program at 0x200, and what it logs
+    pub(crate) fn machine(program: &[u8]) -> (Chip8, Headless) {
+        let headless = Headless::default();
+        let mut chip = Chip8::new(
+            Box::new(headless.clone()),
+            Box::new(headless.clone()),
+            Box::new(headless.clone()),
+        );
+        chip.memory[0x200..0x200 + program.len()].copy_from_slice(program);
+        (chip, headless)
+    }
+
+    fn temp(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("chip-great-{}-{}", name, std::process::id()));
+        path.to_string_lossy().into_owned()
+    }
+
+    fn event(key: u8, down: bool, frame: u64) -> KeyEvent {
+        KeyEvent { key, down, frame }
+    }
+
+    #[test]
+    fn tap_shorter_than_a_frame() {
+        let (mut chip, _) = machine(&[]);
+        chip.key_events.extend([event(5, true, 0), event(5, false, 0)]);
+        chip.apply_key_events();
+        assert_eq!(chip.key[5], 0xFF);
+        assert_eq!(chip.key_events, [event(5, false, 0)]);
+        chip.frames = 1;
+        chip.apply_key_events();
+        assert_eq!(chip.key[5], 0);
+        assert!(chip.key_events.is_empty());
+    }
+
+    #[test]
+    fn events_wait_for_their_frame() {
+        let (mut chip, _) = machine(&[]);
+        chip.key_events.push_back(event(3, true, 2));
+        chip.apply_key_events();
+        chip.frames = 1;
+        chip.apply_key_events();
+        assert_eq!(chip.key[3], 0);
+        chip.frames = 2;
+        chip.apply_key_events();
+        assert_eq!(chip.key[3], 0xFF);
+    }
+
+    #[test]
+    fn events_keep_their_order() {
+        let (mut chip, _) = machine(&[]);
+        chip.key_events.extend([event(1, true, 0), event(2, true, 0), event(1, false, 0), event(2, false, 0)]);
+        chip.apply_key_events();
+        assert_eq!((chip.key[1], chip.key[2]), (0xFF, 0xFF));
+        // key 2's release waits behind key 1's second change
+        assert_eq!(chip.key_events, [event(1, false, 0), event(2, false, 0)]);
+        chip.frames = 1;
+        chip.apply_key_events();
+        assert_eq!((chip.key[1], chip.key[2]), (0, 0));
+        assert!(chip.key_events.is_empty());
+    }
+
+    #[test]
+    fn skip_sees_a_tap_for_the_whole_frame() {
+        // skp v0 twice
+        let (mut chip, _) = machine(&[0xE0, 0x9E, 0x00, 0xE0, 0xE0, 0x9E]);
+        chip.V[0] = 5;
+        chip.key_events.extend([event(5, true, 0), event(5, false, 0)]);
+        chip.apply_key_events();
+        chip.emulate_cycle();
+        assert_eq!(chip.pc, 0x204);
+        chip.emulate_cycle();
+        assert_eq!(chip.pc, 0x208);
+    }
+
+    #[test]
+    fn key_wait_needs_a_press_and_release() {
+        // v0 := key
+        let (mut chip, _) = machine(&[0xF0, 0x0A]);
+        chip.emulate_cycle();
+        assert!(chip.waiting_for_key());
+        // pressed this frame, still down the next
+        chip.key_events.push_back(event(7, true, 0));
+        chip.apply_key_events();
+        chip.emulate_cycle();
+        chip.frames = 1;
+        chip.apply_key_events();
+        chip.emulate_cycle();
+        assert_eq!(chip.pc, 0x200);
+        assert!(chip.waiting_for_key());
+        chip.key_events.push_back(event(7, false, 2));
+        chip.frames = 2;
+        chip.apply_key_events();
+        chip.emulate_cycle();
+        assert_eq!((chip.pc, chip.V[0]), (0x202, 7));
+        assert!(!chip.waiting_for_key());
+    }
+
+    #[test]
+    fn key_wait_ignores_keys_already_held() {
+        let (mut chip, _) = machine(&[0xF0, 0x0A]);
+        chip.key[3] = 0xFF;
+        chip.emulate_cycle();
+        // letting go of a key held before the wait is not a key press
+        chip.key[3] = 0;
+        chip.emulate_cycle();
+        assert_eq!(chip.pc, 0x200);
+        // pressing it again is
+        chip.key[3] = 0xFF;
+        chip.emulate_cycle();
+        chip.key[3] = 0;
+        chip.emulate_cycle();
+        assert_eq!((chip.pc, chip.V[0]), (0x202, 3));
+    }
+
+    #[test]
+    fn key_wait_without_a_release_waits_on() {
+        let (mut chip, _) = machine(&[0xF0, 0x0A]);
+        chip.emulate_cycle();
+        chip.key[9] = 0xFF;
+        for _ in 0..100 {
+            chip.emulate_cycle();
+        }
+        assert_eq!(chip.pc, 0x200);
+        assert!(chip.waiting_for_key());
+        // another key pressed and released meanwhile doesn't count, 9 came first
+        chip.key[4] = 0xFF;
+        chip.emulate_cycle();
+        chip.key[4] = 0;
+        chip.emulate_cycle();
+        assert_eq!(chip.pc, 0x200);
+        chip.key[9] = 0;
+        chip.emulate_cycle();
+        assert_eq!((chip.pc, chip.V[0]), (0x202, 9));
+    }
+
+    #[test]
+    fn display_wait_ends_the_frame() {
+        // v0 := 1, sprite v0 v0 5, v0 += 1, loop again
+        let program = [0x60, 0x01, 0xD0, 0x05, 0x70, 0x01, 0x12, 0x06];
+        let (mut chip, _) = machine(&program);
+        chip.quirks.enable("display-wait");
+        chip.run_frame();
+        assert_eq!((chip.pc, chip.V[0], chip.frame_cycles), (0x204, 1, 2));
+        assert!(chip.vblank_wait);
+        chip.run_frame();
+        assert_eq!((chip.pc, chip.V[0]), (0x206, 2));
+        assert!(!chip.vblank_wait);
+        // without the quirk the frame carries on past the sprite
+        let (mut chip, _) = machine(&program);
+        chip.run_frame();
+        assert_eq!((chip.pc, chip.V[0]), (0x206, 2));
+    }
+
+    #[test]
+    fn stack_faults_end_the_run() {
+        // each call calls the next
+        let (mut chip, log) = machine(&[0x22, 0x02, 0x22, 0x04, 0x22, 0x06]);
+        chip.stack_depth = 2;
+        chip.run_frame();
+        assert_eq!(chip.pc, 0xFFFF);
+        assert_eq!(chip.stack[..2], [0x200, 0x202]);
+        let expected = ["Stack depth 1 of 2 at 0200", "Stack overflow at 0204, calls nest deeper than 2"];
+        assert_eq!(*log.0.borrow(), expected);
+        let (mut chip, log) = machine(&[0x00, 0xEE]);
+        chip.run_frame();
+        assert_eq!(chip.pc, 0xFFFF);
+        assert_eq!(*log.0.borrow(), ["Return with an empty stack at 0200"]);
+    }
+
+    // Ends the run at the first fault, keeping what the machine was then
+    struct Stop(Rc<RefCell<Vec<(String, u16, u16)>>>);
+
+    impl Hook for Stop {
+        fn check(&mut self, chip: &mut Chip8) {
+            if let Some(fault) = chip.fault.take() {
+                self.0.borrow_mut().push((fault, chip.pc, chip.sp));
+                chip.exit();
+            }
+        }
+    }
+
+    #[test]
+    fn stack_faults_stop_in_the_debugger() {
+        let (mut chip, log) = machine(&[0x22, 0x02, 0x22, 0x04, 0x22, 0x06]);
+        let stops = Rc::new(RefCell::new(Vec::new()));
+        chip.hook = Some(Box::new(Stop(stops.clone())));
+        chip.stack_depth = 2;
+        chip.run_frame();
+        let expected = (String::from("Stack overflow at 0204, calls nest deeper than 2"), 0x204, 2);
+        assert_eq!(*stops.borrow(), [expected]);
+        assert_eq!(chip.stack[..2], [0x200, 0x202]);
+        assert_eq!(*log.0.borrow(), ["Stack depth 1 of 2 at 0200"]);
+    }
+
+    #[test]
+    fn wav_follows_emulated_frames() {
+        let program = [
+            0xA2, 0x0E, // i := tone
+            0xF0, 0x02, // audio
+            0x60, 0x70, // v0 := 112
+            0xF0, 0x3A, // pitch := v0
+            0x60, 0x1E, // v0 := 30
+            0xF0, 0x18, // buzzer := v0
+            0x12, 0x0C, // loop again
+            // tone
+            0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00,
+            0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00,
+        ];
+        let (mut chip, _) = machine(&program);
+        let path = temp("tone.wav");
+        chip.audio = Box::new(WavRecorder::new(Silent, Wav::create(&path).unwrap()));
+        for _ in 0..60 {
+            chip.run_frame();
+        }
+        // the header is brought up to date on the way out
+        drop(chip);
+        let wav = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let data = u32::from_le_bytes([wav[40], wav[41], wav[42], wav[43]]) as usize;
+        assert_eq!(data, 60 * 735 * 2);
+        assert_eq!(wav.len(), 44 + data);
+        let samples: Vec<i16> = wav[44..].chunks(2).map(|s| i16::from_le_bytes([s[0], s[1]])).collect();
+        // a sound timer of 30 sounds for the first 30 frames
+        let (tone, after) = samples.split_at(30 * 735);
+        assert!(tone.iter().all(|s| *s != 0));
+        assert!(after.iter().all(|s| *s == 0));
+        // pitch 112 plays 8000 bits a second, a 16 bit period is 500 Hz
+        let edges = tone.windows(2).filter(|w| (w[0] > 0) != (w[1] > 0)).count();
+        assert!((499..=501).contains(&edges), "{}", edges);
     }
 }
@@ -0,0 +1,269 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+//
+// Input movies: everything needed to replay a session exactly. A movie is
+// a text file, a header then one line per 60 Hz frame with the keypad as a
+// 16 bit mask (bit n is key n), and every CHECK_EVERY frames a checksum of
+// the machine to catch desyncs.
+//
+//   chip-great movie 1
+//   rom 9f2c04d1a33b7e10
+//   seed 1234
+//   ips 550
+//   quirks display-wait
+//   stack-depth 16
+//   k 0000
+//   k 0002
+//   c 60 5be1f0c94d2a8e77
+//
+const MAGIC: &str = "chip-great movie 1";
+const CHECK_EVERY: u64 = 60;
+
+// FNV-1a, for the ROM hash and machine checksums
+pub fn hash(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |h, b| (h ^ *b as u64).wrapping_mul(0x100000001b3))
+}
+
+// What the machine has to match for a movie to replay
+pub struct Header {
+    pub rom: u64,
+    pub seed: u64,
+    pub ips: u64,
+    pub quirks: Vec<String>,
+    pub stack_depth: u16,
+}
+
+enum Mode {
+    Record(BufWriter<File>),
+    Play { keys: Vec<u16>, checks: HashMap<u64, u64> },
+}
+
+pub struct Movie {
+    mode: Mode,
+    rom: u64,
+    finished: bool,
+}
+
+impl Movie {
+    pub fn record(path: &str, header: &Header) -> std::io::Result<Movie> {
+        let mut out = BufWriter::new(File::create(path)?);
+        writeln!(out, "{}", MAGIC)?;
+        writeln!(out, "rom {:016x}", header.rom)?;
+        writeln!(out, "seed {}", header.seed)?;
+        writeln!(out, "ips {}", header.ips)?;
+        writeln!(out, "quirks {}", header.quirks.join(" "))?;
+        writeln!(out, "stack-depth {}", header.stack_depth)?;
+        Ok(Movie {
+            mode: Mode::Record(out),
+            rom: header.rom,
+            finished: false,
+        })
+    }
+
+    pub fn play(path: &str) -> Result<(Movie, Header), String> {
+        let text = std::fs::read_to_string(path).map_err(|e| format!("can't read movie {}: {}", path, e))?;
+        let mut lines = text.lines().enumerate();
+        if lines.next().map(|(_, l)| l) != Some(MAGIC) {
+            return Err(format!("{} is not a movie", path));
+        }
+        let mut header = Header {
+            rom: 0,
+            seed: 0,
+            ips: crate::IPS,
+            quirks: Vec::new(),
+            stack_depth: 16,
+        };
+        let mut keys = Vec::new();
+        let mut checks = HashMap::new();
+        for (number, line) in lines {
+            let error = || format!("{}:{}: can't read {}", path, number + 1, line);
+            let mut words = line.split_whitespace();
+            match (words.next(), words.next(), words.next()) {
+                (Some("rom"), Some(rom), None) => header.rom = u64::from_str_radix(rom, 16).map_err(|_| error())?,
+                (Some("seed"), Some(seed), None) => header.seed = seed.parse().map_err(|_| error())?,
+                (Some("ips"), Some(ips), None) => header.ips = ips.parse().map_err(|_| error())?,
+                (Some("stack-depth"), Some(depth), None) => {
+                    header.stack_depth = depth.parse().ok().filter(|d| (1..=16).contains(d)).ok_or_else(error)?
+                }
+                (Some("quirks"), ..) => header.quirks = line.split_whitespace().skip(1).map(String::from).collect(),
+                (Some("k"), Some(mask), None) => keys.push(u16::from_str_radix(mask, 16).map_err(|_| error())?),
+                (Some("c"), Some(frame), Some(sum)) => {
+                    let frame = frame.parse().map_err(|_| error())?;
+                    checks.insert(frame, u64::from_str_radix(sum, 16).map_err(|_| error())?);
+                }
+                (None, ..) => {}
+                _ => return Err(error()),
+            }
+        }
+        let movie = Movie {
+            mode: Mode::Play { keys, checks },
+            rom: header.rom,
+            finished: false,
+        };
+        Ok((movie, header))
+    }
+
+    // Compares the loaded ROM with the one the movie was made on
+    pub fn check_rom(&self, rom: u64) -> Option<String> {
+        if rom != self.rom {
+            Some(format!("movie desync: ROM hash {:016x}, movie was recorded on {:016x}", rom, self.rom))
+        } else {
+            None
+        }
+    }
+
+    // Called with the keypad each frame. Recording writes it out, playback
+    // replaces it with the movie's until the movie runs out.
+    pub fn keys(&mut self, frame: u64, keypad: &mut [u8; 16]) -> Option<String> {
+        match &mut self.mode {
+            Mode::Record(out) => {
+                let mask = (0..16).filter(|k| keypad[*k] != 0).fold(0u16, |m, k| m | 1 << k);
+                writeln!(out, "k {:04x}", mask).err().map(|e| format!("movie recording failed: {}", e))
+            }
+            Mode::Play { keys, .. } => match keys.get(frame as usize) {
+                Some(mask) => {
+                    for (k, key) in keypad.iter_mut().enumerate() {
+                        *key = if mask & 1 << k != 0 { 0xFF } else { 0 };
+                    }
+                    None
+                }
+                None if !self.finished => {
+                    self.finished = true;
+                    Some(format!("movie finished after {} frames", keys.len()))
+                }
+                None => None,
+            },
+        }
+    }
+
+    // Called with the machine checksum at the end of each frame
+    pub fn check(&mut self, frame: u64, sum: u64) -> Option<String> {
+        if !frame.is_multiple_of(CHECK_EVERY) {
+            return None;
+        }
+        match &mut self.mode {
+            Mode::Record(out) => writeln!(out, "c {} {:016x}", frame, sum)
+                .err()
+                .map(|e| format!("movie recording failed: {}", e)),
+            Mode::Play { checks, .. } => match checks.get(&frame) {
+                Some(expected) if *expected != sum => Some(format!(
+                    "movie desync at frame {}: checksum {:016x}, expected {:016x}",
+                    frame, sum, expected
+                )),
+                _ => None,
+            },
+        }
+    }
+}
+
+impl Drop for Movie {
+    fn drop(&mut self) {
+        if let Mode::Record(out) = &mut self.mode {
+            let _ = out.flush();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn path(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("chip-great-{}-{}.movie", name, std::process::id()));
+        path.to_string_lossy().into_owned()
+    }
+
+    fn header() -> Header {
+        Header {
+            rom: 0x9f2c04d1a33b7e10,
+            seed: 1234,
+            ips: 700,
+            quirks: vec![String::from("display-wait")],
+            stack_depth: 12,
+        }
+    }
+
+    // Records frames of keys with a checksum of frame * 7
+    fn record(path: &str, frames: &[u16]) {
+        let mut movie = Movie::record(path, &header()).unwrap();
+        for (frame, mask) in frames.iter().enumerate() {
+            let mut keypad = [0; 16];
+            for (k, key) in keypad.iter_mut().enumerate() {
+                *key = if mask & 1 << k != 0 { 0xFF } else { 0 };
+            }
+            assert_eq!(movie.keys(frame as u64, &mut keypad), None);
+            assert_eq!(movie.check(frame as u64, frame as u64 * 7), None);
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let path = path("round-trip");
+        let frames: Vec<u16> = (0..130).map(|f| if f % 3 == 0 { 0x8001 } else { f as u16 & 0x0F0 }).collect();
+        record(&path, &frames);
+        let (mut movie, read) = Movie::play(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(read.rom, 0x9f2c04d1a33b7e10);
+        assert_eq!(read.seed, 1234);
+        assert_eq!(read.ips, 700);
+        assert_eq!(read.quirks, ["display-wait"]);
+        assert_eq!(read.stack_depth, 12);
+        assert_eq!(movie.check_rom(read.rom), None);
+        for (frame, mask) in frames.iter().enumerate() {
+            // playback overrides whatever is held
+            let mut keypad = [0xFF; 16];
+            assert_eq!(movie.keys(frame as u64, &mut keypad), None);
+            for (k, key) in keypad.iter().enumerate() {
+                assert_eq!(*key != 0, mask & 1 << k != 0, "frame {} key {:X}", frame, k);
+            }
+            assert_eq!(movie.check(frame as u64, frame as u64 * 7), None);
+        }
+        let mut keypad = [0; 16];
+        assert_eq!(movie.keys(130, &mut keypad), Some(String::from("movie finished after 130 frames")));
+        assert_eq!(movie.keys(131, &mut keypad), None);
+    }
+
+    #[test]
+    fn desync() {
+        let path = path("desync");
+        record(&path, &[0; 121]);
+        let (mut movie, _) = Movie::play(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            movie.check_rom(1),
+            Some(String::from(
+                "movie desync: ROM hash 0000000000000001, movie was recorded on 9f2c04d1a33b7e10"
+            ))
+        );
+        assert_eq!(movie.check(60, 60 * 7), None);
+        // only checked frames are compared
+        assert_eq!(movie.check(61, 0), None);
+        assert_eq!(
+            movie.check(120, 0),
+            Some(String::from(
+                "movie desync at frame 120: checksum 0000000000000000, expected 0000000000000348"
+            ))
+        );
+    }
+
+    #[test]
+    fn bad_files() {
+        let path = path("bad");
+        std::fs::write(&path, "not a movie\n").unwrap();
+        assert_eq!(Movie::play(&path).err(), Some(format!("{} is not a movie", path)));
+        std::fs::write(&path, format!("{}\nseed 1\nk zz\n", MAGIC)).unwrap();
+        assert_eq!(Movie::play(&path).err(), Some(format!("{}:3: can't read k zz", path)));
+        std::fs::write(&path, format!("{}\nstack-depth 17\n", MAGIC)).unwrap();
+        assert_eq!(Movie::play(&path).err(), Some(format!("{}:2: can't read stack-depth 17", path)));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn fnv1a() {
+        assert_eq!(hash(b""), 0xcbf29ce484222325);
+        assert_eq!(hash(b"a"), 0xaf63dc4c8601ec8c);
+        assert_eq!(hash(b"foobar"), 0x85944171f73967e8);
+    }
+}
@@ -0,0 +1,62 @@
+// Brightness is quantized to this many shades for indexed color output
+pub const SHADES: usize = 16;
+
+// Colors used to present the 1 bit display, shared by every Screen
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Palette {
+    pub on: [u8; 3],
+    pub off: [u8; 3],
+}
+
+impl Default for Palette {
+    fn default() -> Self {
+        Palette {
+            on: [0xFF, 0xFF, 0xFF],
+            off: [0x00, 0x00, 0x00],
+        }
+    }
+}
+
+impl Palette {
+    // "ffffff:000000" is lit pixels then dark pixels
+    pub fn parse(text: &str) -> Option<Palette> {
+        let mut parts = text.split(':');
+        let on = parse_color(parts.next()?)?;
+        let off = parse_color(parts.next()?)?;
+        if parts.next().is_some() {
+            return None;
+        }
+        Some(Palette { on, off })
+    }
+
+    // Blend from off to on by brightness, 0 is off and 0xFF is on
+    pub fn shade(&self, level: u8) -> [u8; 3] {
+        let mut color = [0; 3];
+        for (c, (on, off)) in color.iter_mut().zip(self.on.iter().zip(self.off.iter())) {
+            let on = *on as u32 * level as u32;
+            let off = *off as u32 * (0xFF - level as u32);
+            *c = ((on + off) / 0xFF) as u8;
+        }
+        color
+    }
+
+    // The SHADES colors from off to on, indexed by shade_index
+    pub fn shades(&self) -> Vec<[u8; 3]> {
+        (0..SHADES)
+            .map(|i| self.shade((i * 0xFF / (SHADES - 1)) as u8))
+            .collect()
+    }
+}
+
+pub fn shade_index(level: u8) -> usize {
+    (level as usize * (SHADES - 1) + 0x7F) / 0xFF
+}
+
+fn parse_color(text: &str) -> Option<[u8; 3]> {
+    let text = text.trim_start_matches('#');
+    if text.len() != 6 {
+        return None;
+    }
+    let value = u32::from_str_radix(text, 16).ok()?;
+    Some([(value >> 16) as u8, (value >> 8) as u8, value as u8])
+}
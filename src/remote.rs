@@ -0,0 +1,353 @@
+use std::collections::VecDeque;
+use std::io::{BufRead, BufReader, Write};
+#[cfg(unix)]
+use std::os::unix::fs::FileTypeExt;
+#[cfg(unix)]
+use std::os::unix::net::UnixListener;
+use std::sync::mpsc::{channel, Receiver, Sender};
+
+use serde_json::{json, Value};
+
+use crate::common::listen_local;
+use crate::{Input, KeyEvent};
+
+//
+// Keypad driven by another process, for bots and automated tests. Listens
+// on a Unix socket (socket:/path) or localhost TCP (socket:127.0.0.1:PORT)
+// and takes one JSON object per line, answering each with one line:
+//
+//   {"cmd": "press", "key": 5}             {"ok": true}
+//   {"cmd": "release", "key": "A"}         {"ok": true}
+//   {"cmd": "tap", "key": 1, "frames": 3}  {"ok": true}
+//   {"cmd": "state"}                       {"ok": true, "frame": 120, "keys": [1]}
+//
+// Keys are 0-15 or a hex digit string. state lists the keys the machine
+// saw down last frame, whoever pressed them, and the frame it is on.
+//
+// The socket file goes when the emulator exits. TCP only listens on
+// loopback addresses, Unix sockets need a Unix system.
+//
+pub struct RemoteInput {
+    commands: Receiver<(Value, Sender<Value>)>,
+    down: [bool; 16],
+    // the keypad as the machine last saw it
+    keypad: [u8; 16],
+    // taps waiting for their release frame
+    taps: Vec<(u8, u64)>,
+    // the Unix socket file to remove on the way out
+    socket: Option<String>,
+}
+
+impl RemoteInput {
+    pub fn listen(address: &str) -> std::io::Result<Self> {
+        let (send, commands) = channel();
+        if address.contains('/') {
+            let mut input = RemoteInput::new(commands);
+            input.socket = Some(listen_unix(address, send)?);
+            return Ok(input);
+        }
+        let listener = listen_local(address)?;
+        std::thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                if let Ok(writer) = stream.try_clone() {
+                    serve(BufReader::new(stream), writer, send.clone());
+                }
+            }
+        });
+        Ok(RemoteInput::new(commands))
+    }
+
+    fn new(commands: Receiver<(Value, Sender<Value>)>) -> Self {
+        RemoteInput {
+            commands,
+            down: [false; 16],
+            keypad: [0; 16],
+            taps: Vec::new(),
+            socket: None,
+        }
+    }
+
+    fn run(&mut self, command: &Value, frame: u64, events: &mut VecDeque<KeyEvent>) -> Result<Value, String> {
+        let mut set = |down: &mut [bool; 16], key: u8, pressed: bool| {
+            if down[key as usize] != pressed {
+                down[key as usize] = pressed;
+                events.push_back(KeyEvent { key, down: pressed, frame });
+            }
+        };
+        match command["cmd"].as_str() {
+            Some("press") => set(&mut self.down, key(command)?, true),
+            Some("release") => {
+                let key = key(command)?;
+                self.taps.retain(|(k, _)| *k != key);
+                set(&mut self.down, key, false);
+            }
+            Some("tap") => {
+                let key = key(command)?;
+                let frames = command["frames"].as_u64().unwrap_or(1).max(1);
+                set(&mut self.down, key, true);
+                self.taps.retain(|(k, _)| *k != key);
+                self.taps.push((key, frame + frames));
+            }
+            Some("state") => {
+                let keys: Vec<usize> = (0..16).filter(|k| self.keypad[*k] != 0).collect();
+                return Ok(json!({ "ok": true, "frame": frame, "keys": keys }));
+            }
+            Some(other) => return Err(format!("unknown cmd {}", other)),
+            None => return Err(String::from("missing cmd")),
+        }
+        Ok(json!({ "ok": true }))
+    }
+}
+
+impl Input for RemoteInput {
+    fn poll(&mut self, frame: u64, events: &mut VecDeque<KeyEvent>) {
+        let down = &mut self.down;
+        self.taps.retain(|(key, until)| {
+            if *until > frame {
+                return true;
+            }
+            down[*key as usize] = false;
+            events.push_back(KeyEvent { key: *key, down: false, frame });
+            false
+        });
+        while let Ok((command, reply)) = self.commands.try_recv() {
+            let answer = self
+                .run(&command, frame, events)
+                .unwrap_or_else(|e| json!({ "ok": false, "error": e }));
+            // the client may have hung up, nothing to tell it then
+            let _ = reply.send(answer);
+        }
+    }
+    fn keypad(&mut self, keys: &[u8; 16]) {
+        self.keypad = *keys;
+    }
+}
+
+fn key(command: &Value) -> Result<u8, String> {
+    let key = match &command["key"] {
+        Value::Number(n) => n.as_u64(),
+        Value::String(s) if s.len() == 1 => u64::from_str_radix(s, 16).ok(),
+        _ => None,
+    };
+    match key {
+        Some(k) if k < 16 => Ok(k as u8),
+        _ => Err(format!("key must be 0-15 or 0-F, got {}", command["key"])),
+    }
+}
+
+impl Drop for RemoteInput {
+    fn drop(&mut self) {
+        if let Some(path) = &self.socket {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+// Listens on the Unix socket at path, returning the path to remove later
+#[cfg(unix)]
+fn listen_unix(path: &str, send: Sender<(Value, Sender<Value>)>) -> std::io::Result<String> {
+    // a socket left over from an earlier run would stop the bind,
+    // anything else there isn't ours to remove
+    match std::fs::symlink_metadata(path) {
+        Ok(meta) if meta.file_type().is_socket() => std::fs::remove_file(path)?,
+        Ok(_) => {
+            let message = format!("{} is already there and not a socket", path);
+            return Err(std::io::Error::new(std::io::ErrorKind::AlreadyExists, message));
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+    let listener = UnixListener::bind(path)?;
+    std::thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            if let Ok(writer) = stream.try_clone() {
+                serve(BufReader::new(stream), writer, send.clone());
+            }
+        }
+    });
+    Ok(path.to_string())
+}
+
+#[cfg(not(unix))]
+fn listen_unix(_path: &str, _send: Sender<(Value, Sender<Value>)>) -> std::io::Result<String> {
+    let message = "Unix sockets need a Unix system, use socket:127.0.0.1:PORT";
+    Err(std::io::Error::new(std::io::ErrorKind::Unsupported, message))
+}
+
+// One client, handled on its own thread so a slow one can't hold up others
+fn serve<R: BufRead + Send + 'static, W: Write + Send + 'static>(
+    reader: R,
+    mut writer: W,
+    commands: Sender<(Value, Sender<Value>)>,
+) {
+    std::thread::spawn(move || {
+        for line in reader.lines() {
+            let line = match line {
+                Ok(line) if line.trim().is_empty() => continue,
+                Ok(line) => line,
+                Err(_) => break,
+            };
+            let answer = match serde_json::from_str::<Value>(&line) {
+                Ok(command) => {
+                    let (send, receive) = channel();
+                    if commands.send((command, send)).is_err() {
+                        break;
+                    }
+                    match receive.recv() {
+                        Ok(answer) => answer,
+                        Err(_) => break,
+                    }
+                }
+                Err(e) => json!({ "ok": false, "error": e.to_string() }),
+            };
+            if writeln!(writer, "{}", answer).is_err() {
+                break;
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(unix)]
+    use std::os::unix::net::UnixStream;
+
+    fn input() -> RemoteInput {
+        RemoteInput::new(channel().1)
+    }
+
+    fn run(input: &mut RemoteInput, command: &str, frame: u64, events: &mut VecDeque<KeyEvent>) -> Value {
+        let command = serde_json::from_str(command).unwrap();
+        input.run(&command, frame, events).unwrap_or_else(|e| json!({ "ok": false, "error": e }))
+    }
+
+    fn path(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("chip-great-{}-{}", name, std::process::id()));
+        path.to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn keys() {
+        for (text, expected) in [("5", 5), ("15", 15), ("\"a\"", 10), ("\"F\"", 15)] {
+            assert_eq!(key(&json!({ "key": serde_json::from_str::<Value>(text).unwrap() })), Ok(expected));
+        }
+        for bad in ["16", "-1", "\"10\"", "\"g\"", "null", "1.5"] {
+            let command = json!({ "key": serde_json::from_str::<Value>(bad).unwrap() });
+            assert_eq!(key(&command), Err(format!("key must be 0-15 or 0-F, got {}", bad)));
+        }
+    }
+
+    #[test]
+    fn press_release_tap() {
+        let mut input = input();
+        let mut events = VecDeque::new();
+        assert_eq!(run(&mut input, r#"{"cmd": "press", "key": 5}"#, 3, &mut events), json!({ "ok": true }));
+        // pressing again changes nothing
+        run(&mut input, r#"{"cmd": "press", "key": 5}"#, 3, &mut events);
+        run(&mut input, r#"{"cmd": "release", "key": "5"}"#, 4, &mut events);
+        run(&mut input, r#"{"cmd": "tap", "key": "c", "frames": 2}"#, 4, &mut events);
+        let expected = [
+            KeyEvent { key: 5, down: true, frame: 3 },
+            KeyEvent { key: 5, down: false, frame: 4 },
+            KeyEvent { key: 12, down: true, frame: 4 },
+        ];
+        assert_eq!(events, expected);
+        events.clear();
+        input.poll(5, &mut events);
+        assert!(events.is_empty());
+        input.poll(6, &mut events);
+        assert_eq!(events, [KeyEvent { key: 12, down: false, frame: 6 }]);
+    }
+
+    #[test]
+    fn bad_commands() {
+        let mut input = input();
+        let mut events = VecDeque::new();
+        let answer = run(&mut input, r#"{"cmd": "jump"}"#, 0, &mut events);
+        assert_eq!(answer, json!({ "ok": false, "error": "unknown cmd jump" }));
+        let answer = run(&mut input, r#"{"key": 1}"#, 0, &mut events);
+        assert_eq!(answer, json!({ "ok": false, "error": "missing cmd" }));
+        let answer = run(&mut input, r#"{"cmd": "press"}"#, 0, &mut events);
+        assert_eq!(answer, json!({ "ok": false, "error": "key must be 0-15 or 0-F, got null" }));
+        assert!(events.is_empty());
+    }
+
+    #[test]
+    fn state_is_what_the_machine_sees() {
+        let mut input = input();
+        let mut events = VecDeque::new();
+        run(&mut input, r#"{"cmd": "press", "key": 1}"#, 7, &mut events);
+        // key 1 isn't applied yet, key 9 came from somewhere else
+        let mut keypad = [0; 16];
+        keypad[9] = 0xFF;
+        input.keypad(&keypad);
+        let answer = run(&mut input, r#"{"cmd": "state"}"#, 7, &mut events);
+        assert_eq!(answer, json!({ "ok": true, "frame": 7, "keys": [9] }));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn socket_round_trip() {
+        let path = path("round-trip.sock");
+        let mut input = RemoteInput::listen(&path).unwrap();
+        let client = {
+            let path = path.clone();
+            std::thread::spawn(move || {
+                let mut stream = UnixStream::connect(&path).unwrap();
+                let mut answers = BufReader::new(stream.try_clone().unwrap()).lines();
+                let mut ask = |line: &str| {
+                    writeln!(stream, "{}", line).unwrap();
+                    answers.next().unwrap().unwrap()
+                };
+                vec![ask(r#"{"cmd": "press", "key": 2}"#), ask("not json"), ask(r#"{"cmd": "state"}"#)]
+            })
+        };
+        let mut events = VecDeque::new();
+        let mut keypad = [0; 16];
+        while !client.is_finished() {
+            input.poll(0, &mut events);
+            for event in events.drain(..) {
+                keypad[event.key as usize] = if event.down { 0xFF } else { 0 };
+            }
+            input.keypad(&keypad);
+            std::thread::sleep(std::time::Duration::from_millis(1));
+        }
+        let answers = client.join().unwrap();
+        drop(input);
+        assert!(std::fs::symlink_metadata(&path).is_err(), "{} is still there", path);
+        assert_eq!(answers[0], r#"{"ok":true}"#);
+        assert!(answers[1].starts_with(r#"{"error":"expected ident"#), "{}", answers[1]);
+        assert_eq!(answers[2], r#"{"frame":0,"keys":[2],"ok":true}"#);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn stale_socket_is_replaced() {
+        let path = path("stale.sock");
+        drop(UnixListener::bind(&path).unwrap());
+        let input = RemoteInput::listen(&path).unwrap();
+        assert!(std::fs::symlink_metadata(&path).unwrap().file_type().is_socket());
+        drop(input);
+        assert!(std::fs::symlink_metadata(&path).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn other_files_are_left_alone() {
+        let path = path("victim.txt");
+        std::fs::write(&path, "keep me").unwrap();
+        let e = RemoteInput::listen(&path).err().unwrap();
+        assert_eq!(e.kind(), std::io::ErrorKind::AlreadyExists);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "keep me");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn tcp_is_local_only() {
+        let e = RemoteInput::listen("0.0.0.0:0").err().unwrap();
+        assert_eq!(e.kind(), std::io::ErrorKind::InvalidInput);
+        assert!(RemoteInput::listen("127.0.0.1:0").is_ok());
+        assert!(RemoteInput::listen("localhost:0").is_ok());
+    }
+}
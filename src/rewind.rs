@@ -0,0 +1,452 @@
+use std::collections::VecDeque;
+
+use rand::rngs::StdRng;
+
+use crate::{Chip8, KeyEvent};
+
+// Ceiling on what the older snapshots take, whatever --rewind asks for
+const BUDGET: usize = 32 << 20;
+
+//
+// The machine as it was at the start of each frame, before its first
+// instruction, for going back. The newest is kept whole and each older one
+// as the bytes that differ from the one after it, so a frame where little
+// happened costs little and dropping the oldest is just forgetting it.
+// Going back to an instruction restores the frame it ran in and runs the
+// frame up to it again.
+//
+pub struct Rewind {
+    newest: Option<Snapshot>,
+    // oldest first, each one back from the one after it, the last from newest
+    older: VecDeque<Delta>,
+    bytes: usize,
+    // older frames to keep
+    frames: usize,
+}
+
+// memory, gfx, V, R and the keypad back to back
+const MEMORY: usize = 4096;
+const GFX: usize = 64 * 32;
+const SIZE: usize = MEMORY + GFX + 16 * 3;
+
+#[derive(Clone, Debug, PartialEq)]
+struct Snapshot {
+    bytes: Vec<u8>,
+    rest: Rest,
+    rng: StdRng,
+}
+
+// Everything else, small enough to keep whole
+#[derive(Clone, Debug, PartialEq)]
+struct Rest {
+    opcode: u16,
+    i: u16,
+    pc: u16,
+    stack: [u16; 16],
+    sp: u16,
+    deepest: u16,
+    hgr: bool,
+    delay_timer: u8,
+    sound_timer: u8,
+    beeping: bool,
+    pattern: Option<[u8; 16]>,
+    pitch: u8,
+    key_events: VecDeque<KeyEvent>,
+    key_wait: Option<([u8; 16], Option<u8>)>,
+    frames: u64,
+    cycles: u64,
+}
+
+// An older snapshot as what to put back over the one after it
+struct Delta {
+    runs: Vec<(usize, Vec<u8>)>,
+    rest: Rest,
+    // only when a random number was drawn in between
+    rng: Option<StdRng>,
+}
+
+impl Rewind {
+    pub fn new(frames: usize) -> Self {
+        Rewind {
+            newest: None,
+            older: VecDeque::new(),
+            bytes: 0,
+            frames,
+        }
+    }
+
+    // Called at the start of every frame
+    pub fn record(&mut self, chip: &Chip8) {
+        let snapshot = take(chip);
+        if let Some(newest) = self.newest.take() {
+            if newest.rest.frames < snapshot.rest.frames {
+                self.push(Delta::new(&newest, &snapshot));
+            } else if let Some(delta) = self.older.pop_back() {
+                // the same frame again after going back, the one before it
+                // was kept against the state this replaces
+                self.bytes -= delta.size();
+                let older = delta.apply(&newest);
+                self.push(Delta::new(&older, &snapshot));
+            }
+        }
+        self.newest = Some(snapshot);
+        while self.older.len() > self.frames || self.bytes > BUDGET {
+            match self.older.pop_front() {
+                Some(delta) => self.bytes -= delta.size(),
+                None => break,
+            }
+        }
+    }
+
+    fn push(&mut self, delta: Delta) {
+        self.bytes += delta.size();
+        self.older.push_back(delta);
+    }
+
+    // Makes the snapshot before newest the newest, false when there is none
+    fn pop(&mut self) -> bool {
+        match (self.older.pop_back(), &mut self.newest) {
+            (Some(delta), Some(newest)) => {
+                self.bytes -= delta.size();
+                *newest = delta.apply(newest);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    // Back to the start of the frame before, false when it is gone
+    pub fn back_frame(&mut self, chip: &mut Chip8) -> bool {
+        let newest = match &self.newest {
+            Some(newest) => newest,
+            None => return false,
+        };
+        // the frame just run goes back to its own start first
+        if chip.frames <= newest.rest.frames && !self.pop() {
+            return false;
+        }
+        restore(chip, self.newest.as_ref().unwrap());
+        true
+    }
+
+    // Back n instructions, or as far as the snapshots go. How many it went.
+    pub fn back(&mut self, chip: &mut Chip8, n: u64) -> u64 {
+        let now = chip.cycles;
+        let target = now.saturating_sub(n);
+        while self.newest.as_ref().is_some_and(|s| s.rest.cycles > target) && self.pop() {}
+        let newest = match &self.newest {
+            Some(newest) => newest,
+            None => return 0,
+        };
+        restore(chip, newest);
+        // as run_frame ran it, up to a draw that ended the frame at most
+        while chip.cycles < target && chip.pc != 0xFFFF && !chip.vblank_wait {
+            chip.run_tick();
+        }
+        now - chip.cycles
+    }
+}
+
+impl Delta {
+    // What turns newer back into older. Runs of changes closer together than
+    // the cost of starting a run are kept as one.
+    fn new(older: &Snapshot, newer: &Snapshot) -> Delta {
+        let mut runs: Vec<(usize, Vec<u8>)> = Vec::new();
+        let mut last_end = None;
+        for (at, (old, new)) in older.bytes.iter().zip(&newer.bytes).enumerate() {
+            if old == new {
+                continue;
+            }
+            match (runs.last_mut(), last_end) {
+                (Some((_, run)), Some(end)) if at - end < 8 => run.extend_from_slice(&older.bytes[end..=at]),
+                _ => runs.push((at, vec![*old])),
+            }
+            last_end = Some(at + 1);
+        }
+        Delta {
+            runs,
+            rest: older.rest.clone(),
+            rng: (older.rng != newer.rng).then(|| older.rng.clone()),
+        }
+    }
+
+    fn apply(&self, newer: &Snapshot) -> Snapshot {
+        let mut bytes = newer.bytes.clone();
+        for (at, run) in &self.runs {
+            bytes[*at..*at + run.len()].copy_from_slice(run);
+        }
+        Snapshot {
+            bytes,
+            rest: self.rest.clone(),
+            rng: self.rng.clone().unwrap_or_else(|| newer.rng.clone()),
+        }
+    }
+
+    fn size(&self) -> usize {
+        let runs: usize = self.runs.iter().map(|(_, run)| run.len() + std::mem::size_of::<(usize, Vec<u8>)>()).sum();
+        let rng = self.rng.as_ref().map_or(0, std::mem::size_of_val);
+        std::mem::size_of::<Delta>() + runs + self.rest.key_events.len() * std::mem::size_of::<KeyEvent>() + rng
+    }
+}
+
+fn take(chip: &Chip8) -> Snapshot {
+    let mut bytes = Vec::with_capacity(SIZE);
+    bytes.extend_from_slice(&chip.memory);
+    bytes.extend_from_slice(&chip.gfx);
+    bytes.extend_from_slice(&chip.V);
+    bytes.extend_from_slice(&chip.R);
+    bytes.extend_from_slice(&chip.key);
+    Snapshot {
+        bytes,
+        rest: Rest {
+            opcode: chip.opcode,
+            i: chip.I,
+            pc: chip.pc,
+            stack: chip.stack,
+            sp: chip.sp,
+            deepest: chip.deepest,
+            hgr: chip.hgr,
+            delay_timer: chip.delay_timer,
+            sound_timer: chip.sound_timer,
+            beeping: chip.beeping,
+            pattern: chip.pattern,
+            pitch: chip.pitch,
+            key_events: chip.key_events.clone(),
+            key_wait: chip.key_wait,
+            frames: chip.frames,
+            cycles: chip.cycles,
+        },
+        rng: chip.rng.clone(),
+    }
+}
+
+fn restore(chip: &mut Chip8, snapshot: &Snapshot) {
+    let (memory, rest) = snapshot.bytes.split_at(MEMORY);
+    let (gfx, rest) = rest.split_at(GFX);
+    chip.memory.copy_from_slice(memory);
+    chip.gfx.copy_from_slice(gfx);
+    chip.V.copy_from_slice(&rest[..16]);
+    chip.R.copy_from_slice(&rest[16..32]);
+    chip.key.copy_from_slice(&rest[32..48]);
+    let rest = &snapshot.rest;
+    chip.opcode = rest.opcode;
+    chip.I = rest.i;
+    chip.pc = rest.pc;
+    chip.stack = rest.stack;
+    chip.sp = rest.sp;
+    chip.deepest = rest.deepest;
+    chip.hgr = rest.hgr;
+    chip.delay_timer = rest.delay_timer;
+    chip.sound_timer = rest.sound_timer;
+    chip.beep(rest.beeping);
+    chip.audio.pattern(rest.pattern.as_ref(), rest.pitch);
+    chip.pattern = rest.pattern;
+    chip.pitch = rest.pitch;
+    chip.key_events = rest.key_events.clone();
+    chip.key_wait = rest.key_wait;
+    chip.frames = rest.frames;
+    chip.cycles = rest.cycles;
+    chip.rng = snapshot.rng.clone();
+    chip.vblank_wait = false;
+    chip.frame_cycles = 0;
+    // the screen has to catch up with gfx
+    chip.draw_flag = true;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::machine;
+    use crate::{Audio, Hook};
+    use rand::SeedableRng;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    // v0 := random, i := 0x300 + v0, save v0, v1 += 1, sound := v1, again
+    const PROGRAM: [u8; 14] = [0xC0, 0xFF, 0xA3, 0x00, 0xF0, 0x1E, 0xF0, 0x55, 0x71, 0x01, 0xF1, 0x18, 0x12, 0x00];
+
+    fn rewinding(program: &[u8], frames: usize) -> Chip8 {
+        let (mut chip, _) = machine(program);
+        chip.rng = StdRng::seed_from_u64(1);
+        chip.rewind = Some(Rewind::new(frames));
+        chip
+    }
+
+    // Runs frames, returning the machine as it was at the start of each
+    fn run(chip: &mut Chip8, frames: usize) -> Vec<Snapshot> {
+        let mut starts = Vec::new();
+        for _ in 0..frames {
+            starts.push(take(chip));
+            chip.run_frame();
+        }
+        starts
+    }
+
+    fn back_frame(chip: &mut Chip8) -> bool {
+        let mut rewind = chip.rewind.take().unwrap();
+        let went = rewind.back_frame(chip);
+        chip.rewind = Some(rewind);
+        went
+    }
+
+    #[test]
+    fn back_frame_restores_each_frame_exactly() {
+        let mut chip = rewinding(&PROGRAM, 100);
+        let starts = run(&mut chip, 20);
+        for start in starts.iter().rev() {
+            assert!(back_frame(&mut chip));
+            assert_eq!(take(&chip), *start, "frame {}", start.rest.frames);
+        }
+        assert!(!back_frame(&mut chip));
+        assert_eq!(take(&chip), starts[0]);
+    }
+
+    #[test]
+    fn deltas_keep_what_changed() {
+        let mut chip = rewinding(&PROGRAM, 100);
+        let starts = run(&mut chip, 3);
+        let rewind = chip.rewind.as_ref().unwrap();
+        assert_eq!(rewind.older.len(), 2);
+        let delta = &rewind.older[1];
+        // a byte of memory, V0, V1 and VF, not whole copies
+        let changed: usize = delta.runs.iter().map(|(_, run)| run.len()).sum();
+        assert!(changed < 32, "{} bytes", changed);
+        assert!(delta.rng.is_some());
+        assert_eq!(delta.apply(&starts[2]), starts[1]);
+        assert_eq!(rewind.bytes, rewind.older.iter().map(Delta::size).sum::<usize>());
+        // no random numbers drawn, no generator kept, just V1
+        let mut chip = rewinding(&[0x71, 0x01, 0x12, 0x00], 100);
+        let starts = run(&mut chip, 3);
+        let v1 = MEMORY + GFX + 1;
+        let delta = &chip.rewind.as_ref().unwrap().older[1];
+        assert!(delta.rng.is_none());
+        assert_eq!(delta.runs, [(v1, vec![starts[1].bytes[v1]])]);
+    }
+
+    #[test]
+    fn oldest_frames_go_first() {
+        let mut chip = rewinding(&PROGRAM, 5);
+        let starts = run(&mut chip, 20);
+        assert_eq!(chip.rewind.as_ref().unwrap().older.len(), 5);
+        for start in starts[14..].iter().rev() {
+            assert!(back_frame(&mut chip));
+            assert_eq!(take(&chip), *start);
+        }
+        assert!(!back_frame(&mut chip));
+    }
+
+    #[test]
+    fn budget_drops_the_oldest() {
+        let (chip, _) = machine(&PROGRAM);
+        let mut rewind = Rewind::new(1000);
+        rewind.record(&chip);
+        let rest = rewind.newest.as_ref().unwrap().rest.clone();
+        for _ in 0..40 {
+            rewind.push(Delta {
+                runs: vec![(0, vec![0; 1 << 20])],
+                rest: rest.clone(),
+                rng: None,
+            });
+        }
+        let mut chip = chip;
+        chip.frames = 1;
+        chip.memory[0x400] = 1;
+        rewind.record(&chip);
+        assert!(rewind.bytes <= BUDGET);
+        assert!(rewind.bytes > BUDGET - (2 << 20));
+        // the frame just recorded stays
+        assert_eq!(rewind.older.back().unwrap().runs, [(0x400, vec![0])]);
+        assert_eq!(rewind.bytes, rewind.older.iter().map(Delta::size).sum::<usize>());
+    }
+
+    #[test]
+    fn same_frame_again_after_going_back() {
+        let mut chip = rewinding(&PROGRAM, 100);
+        let starts = run(&mut chip, 10);
+        assert!(back_frame(&mut chip));
+        assert!(back_frame(&mut chip));
+        assert_eq!(take(&chip), starts[8]);
+        // frame 8 again, differently this time
+        chip.memory[0x400] = 0xAB;
+        let again = run(&mut chip, 1);
+        assert!(back_frame(&mut chip));
+        assert_eq!(take(&chip), again[0]);
+        assert!(back_frame(&mut chip));
+        assert_eq!(take(&chip), starts[7]);
+        assert_eq!(chip.rewind.as_ref().unwrap().older.len(), 7);
+    }
+
+    // The machine after each instruction, by cycle
+    struct After(Rc<RefCell<Vec<Snapshot>>>);
+
+    impl Hook for After {
+        fn check(&mut self, _chip: &mut Chip8) {}
+        fn after(&mut self, chip: &Chip8) {
+            self.0.borrow_mut().push(take(chip));
+        }
+    }
+
+    #[test]
+    fn back_replays_instructions() {
+        // v1 := random, sprite v1 v1 1, v2 += 1, jump to the start
+        let program = [0xC1, 0x3F, 0xD1, 0x11, 0x72, 0x01, 0x12, 0x00];
+        let mut chip = rewinding(&program, 100);
+        chip.quirks.enable("display-wait");
+        let after = Rc::new(RefCell::new(vec![take(&chip)]));
+        chip.hook = Some(Box::new(After(after.clone())));
+        run(&mut chip, 10);
+        let mut target = chip.cycles;
+        for n in [1, 2, 5, 13] {
+            target -= n;
+            let mut rewind = chip.rewind.take().unwrap();
+            assert_eq!(rewind.back(&mut chip, n), n);
+            chip.rewind = Some(rewind);
+            assert_eq!(chip.cycles, target);
+            let mut expected = after.borrow()[target as usize].clone();
+            // back to a draw that ended its frame is the start of the next
+            if chip.frames == expected.rest.frames + 1 {
+                assert_eq!(chip.frame_cycles, 0);
+                expected.rest.frames += 1;
+            }
+            assert_eq!(take(&chip), expected, "back to {}", target);
+        }
+    }
+
+    // Whether the beeper is on and the pattern it plays
+    #[derive(Clone, Default)]
+    struct Beeper(Rc<RefCell<(bool, Option<[u8; 16]>)>>);
+
+    impl Audio for Beeper {
+        fn start(&mut self) {
+            self.0.borrow_mut().0 = true;
+        }
+        fn stop(&mut self) {
+            self.0.borrow_mut().0 = false;
+        }
+        fn pattern(&mut self, pattern: Option<&[u8; 16]>, _pitch: u8) {
+            self.0.borrow_mut().1 = pattern.copied();
+        }
+    }
+
+    #[test]
+    fn restore_puts_the_sound_back() {
+        // v0 := 2, sound := v0, audio, wait a while
+        let program = [0x60, 0x02, 0xF0, 0x18, 0xF0, 0x02, 0x12, 0x06];
+        let mut chip = rewinding(&program, 100);
+        let beeper = Beeper::default();
+        chip.audio = Box::new(beeper.clone());
+        run(&mut chip, 1);
+        assert!(chip.pattern.is_some());
+        assert_eq!(*beeper.0.borrow(), (true, chip.pattern));
+        run(&mut chip, 4);
+        assert!(!beeper.0.borrow().0);
+        // the second frame started with the sound on
+        while chip.frames > 2 {
+            back_frame(&mut chip);
+        }
+        assert_eq!(*beeper.0.borrow(), (true, chip.pattern));
+        // and the first before any of it
+        while back_frame(&mut chip) {}
+        assert_eq!(*beeper.0.borrow(), (false, None));
+    }
+}
@@ -0,0 +1,440 @@
+use std::collections::VecDeque;
+use std::io::{Read, Write};
+use std::process::{Command, Stdio};
+use std::sync::mpsc::{channel, Receiver};
+use std::time::{Duration, Instant};
+
+use crate::hud::keypad_at;
+use crate::keymap::Keymap;
+use crate::{Hotkey, Input, KeyEvent};
+
+//
+// Keyboard input read from the terminal itself, so it works over SSH, in a
+// plain TTY or in a container with no display server. Terminals only send
+// key presses (and auto repeats), so a key counts as held until timeout
+// passes without hearing from it again. Terminals that speak the kitty
+// keyboard protocol report releases and don't need the timeout.
+// https://sw.kovidgoyal.net/kitty/keyboard-protocol/
+//
+// With mouse on, clicks on the keypad overlay hold the key under the
+// pointer until the button comes up.
+//
+
+// How long an ESC waits for the rest of a sequence before it counts as the
+// Escape key on its own
+const ESCAPE_WAIT: Duration = Duration::from_millis(50);
+
+pub struct TerminalInput {
+    bytes: Receiver<u8>,
+    keymap: Keymap,
+    pending: Vec<u8>,
+    // when the last byte came in
+    heard: Instant,
+    timeout: Duration,
+    // when each keypad key lets go if nothing else is heard from it
+    // macros from the keymap come after the 16 keypad keys
+    until: Vec<Option<Instant>>,
+    // held until a release event arrives
+    latched: Vec<bool>,
+    down: Vec<bool>,
+    events: Vec<(u8, bool)>,
+    hotkeys: VecDeque<Hotkey>,
+    // when the rewind key lets go, timing out like a keypad key
+    rewind: Option<Instant>,
+    mouse: bool,
+    // the keypad key the mouse button is holding
+    clicked: Option<u8>,
+    saved: Option<String>,
+}
+
+// What one complete sequence from the terminal meant, keys by keymap name
+#[derive(Debug, PartialEq)]
+enum Event {
+    Press(String),
+    Release(String),
+    Hotkey(Hotkey),
+    // mouse button down on the keypad overlay, or up anywhere
+    Click(u8),
+    Unclick,
+    Ignore,
+}
+
+impl TerminalInput {
+    pub fn new(timeout: Duration, keymap: Keymap, mouse: bool) -> Self {
+        let saved = stty(&["-g"]).map(|s| s.trim().to_string());
+        // no line buffering, echo or signals, output processing stays on
+        stty(&["-icanon", "-echo", "-isig", "-ixon", "min", "1"]);
+        // ask for release events, terminals without the protocol ignore this
+        print!("\x1B[>11u");
+        if mouse {
+            // button presses and releases, reported in SGR form
+            print!("\x1B[?1000h\x1B[?1006h");
+        }
+        let _ = std::io::stdout().flush();
+
+        let (send, bytes) = channel();
+        std::thread::spawn(move || {
+            let stdin = std::io::stdin();
+            for byte in stdin.lock().bytes() {
+                match byte {
+                    Ok(b) if send.send(b).is_ok() => {}
+                    _ => break,
+                }
+            }
+        });
+        let keys = keymap.keys();
+        TerminalInput {
+            bytes,
+            keymap,
+            pending: Vec::new(),
+            heard: Instant::now(),
+            timeout,
+            until: vec![None; keys],
+            latched: vec![false; keys],
+            down: vec![false; keys],
+            events: Vec::new(),
+            hotkeys: VecDeque::new(),
+            rewind: None,
+            mouse,
+            clicked: None,
+            saved,
+        }
+    }
+
+    fn set(&mut self, key: u8, down: bool) {
+        if self.down[key as usize] != down {
+            self.down[key as usize] = down;
+            self.events.push((key, down));
+        }
+    }
+
+    fn apply(&mut self, event: Event) {
+        match event {
+            Event::Press(name) => {
+                if let Some(i) = self.keymap.keypad(&name) {
+                    self.until[i as usize] = Some(Instant::now() + self.timeout);
+                    self.set(i, true);
+                }
+            }
+            Event::Release(name) => {
+                if let Some(i) = self.keymap.keypad(&name) {
+                    self.until[i as usize] = None;
+                    self.latched[i as usize] = false;
+                    self.set(i, false);
+                }
+            }
+            Event::Hotkey(Hotkey::Rewind(true)) => {
+                if self.rewind.replace(Instant::now() + self.timeout).is_none() {
+                    self.hotkeys.push_back(Hotkey::Rewind(true));
+                }
+            }
+            Event::Hotkey(Hotkey::Rewind(false)) => {
+                if self.rewind.take().is_some() {
+                    self.hotkeys.push_back(Hotkey::Rewind(false));
+                }
+            }
+            Event::Hotkey(key) => self.hotkeys.push_back(key),
+            Event::Click(key) => {
+                if let Some(old) = self.clicked.replace(key) {
+                    self.latched[old as usize] = false;
+                    self.set(old, false);
+                }
+                self.latched[key as usize] = true;
+                self.set(key, true);
+            }
+            Event::Unclick => {
+                if let Some(key) = self.clicked.take() {
+                    self.latched[key as usize] = false;
+                    self.until[key as usize] = None;
+                    self.set(key, false);
+                }
+            }
+            Event::Ignore => {}
+        }
+    }
+}
+
+impl Input for TerminalInput {
+    fn poll(&mut self, frame: u64, events: &mut VecDeque<KeyEvent>) {
+        let before = self.pending.len();
+        self.pending.extend(self.bytes.try_iter());
+        let now = Instant::now();
+        if self.pending.len() != before {
+            self.heard = now;
+        }
+        loop {
+            while let Some((used, event, kitty)) = decode(&self.pending) {
+                self.pending.drain(..used);
+                if let (true, Event::Press(name)) = (kitty, &event) {
+                    if let Some(i) = self.keymap.keypad(name) {
+                        self.latched[i as usize] = true;
+                    }
+                }
+                self.apply(event);
+            }
+            // nothing followed the ESC, so it was the key
+            if self.pending.first() == Some(&0x1B) && now - self.heard >= ESCAPE_WAIT {
+                self.pending.remove(0);
+                self.apply(Event::Press(String::from("Escape")));
+            } else {
+                break;
+            }
+        }
+        for i in 0..self.down.len() {
+            if !self.latched[i] && self.until[i].is_none_or(|t| t <= now) {
+                self.set(i as u8, false);
+            }
+        }
+        if self.rewind.is_some_and(|t| t <= now) {
+            self.rewind = None;
+            self.hotkeys.push_back(Hotkey::Rewind(false));
+        }
+        for (key, down) in self.events.drain(..) {
+            events.push_back(KeyEvent { key, down, frame });
+        }
+    }
+    fn hotkey(&mut self) -> Option<Hotkey> {
+        self.hotkeys.pop_front()
+    }
+}
+
+impl Drop for TerminalInput {
+    fn drop(&mut self) {
+        print!("\x1B[<u");
+        if self.mouse {
+            print!("\x1B[?1000l\x1B[?1006l");
+        }
+        let _ = std::io::stdout().flush();
+        if let Some(saved) = &self.saved {
+            stty(&[saved.as_str()]);
+        }
+    }
+}
+
+// Runs stty against the terminal on stdin, returning what it printed
+fn stty(args: &[&str]) -> Option<String> {
+    let output = Command::new("stty")
+        .args(args)
+        .stdin(Stdio::inherit())
+        .output()
+        .ok()?;
+    if output.status.success() {
+        String::from_utf8(output.stdout).ok()
+    } else {
+        None
+    }
+}
+
+// Decodes the first complete sequence in bytes. Returns how many bytes it
+// used, what it meant and whether it was a kitty protocol key that will be
+// released by an event, or None when more bytes are needed.
+fn decode(bytes: &[u8]) -> Option<(usize, Event, bool)> {
+    match bytes {
+        [] => None,
+        // Ctrl-C, signals are off so this is the way out
+        [0x03, ..] => Some((1, Event::Hotkey(Hotkey::Quit), false)),
+        [0x1B, b'O', b'P', ..] => Some((3, Event::Hotkey(Hotkey::Hud), false)),
+        [0x1B, b'O', b'Q', ..] => Some((3, Event::Hotkey(Hotkey::Rewind(true)), false)),
+        [0x1B, b'O'] | [0x1B] => None,
+        [0x1B, b'[', rest @ ..] => {
+            // CSI parameters then a final byte in @ to ~
+            let end = rest.iter().position(|b| (0x40..=0x7E).contains(b))?;
+            let params = std::str::from_utf8(&rest[..end]).unwrap_or("");
+            Some((end + 3, csi(params, rest[end]), rest[end] == b'u'))
+        }
+        [0x1B, _, ..] => Some((1, Event::Ignore, false)),
+        [b, ..] => Some((1, key_name(*b as u32).map_or(Event::Ignore, Event::Press), false)),
+    }
+}
+
+// The keymap name of a character or kitty protocol key code
+fn key_name(code: u32) -> Option<String> {
+    let name = match code {
+        // kitty's keypad keys live in the private use area
+        57399..=57408 => return Some(format!("Numpad{}", code - 57399)),
+        57410 => "NumpadDivide",
+        57411 => "NumpadMultiply",
+        57412 => "NumpadSubtract",
+        57413 => "NumpadAdd",
+        57414 | 13 | 10 => "Enter",
+        57417 => "Left",
+        57418 => "Right",
+        57419 => "Up",
+        57420 => "Down",
+        9 => "Tab",
+        27 => "Escape",
+        32 => "Space",
+        127 | 8 => "Backspace",
+        0x27 => "Apostrophe",
+        0x2C => "Comma",
+        0x2D => "Minus",
+        0x2E => "Dot",
+        0x2F => "Slash",
+        0x3B => "Semicolon",
+        0x3D => "Equal",
+        0x5B => "LeftBracket",
+        0x5C => "BackSlash",
+        0x5D => "RightBracket",
+        0x60 => "Grave",
+        0x30..=0x39 => return Some(format!("Key{}", code - 0x30)),
+        0x41..=0x5A | 0x61..=0x7A => return Some((code as u8 as char).to_ascii_uppercase().to_string()),
+        _ => return None,
+    };
+    Some(name.to_string())
+}
+
+// Kitty protocol keys are CSI code;modifiers:event u, function keys keep
+// their legacy final byte. event is 1 press, 2 repeat, 3 release.
+fn csi(params: &str, last: u8) -> Event {
+    if let Some(mouse) = params.strip_prefix('<') {
+        return click(mouse, last);
+    }
+    let mut fields = params.split(';');
+    let code: u32 = fields.next().and_then(|c| c.split(':').next()?.parse().ok()).unwrap_or(1);
+    let mut modifiers = fields.next().unwrap_or("1").split(':');
+    let ctrl = modifiers.next().and_then(|m| m.parse::<u32>().ok()).is_some_and(|m| m.saturating_sub(1) & 4 != 0);
+    let released = modifiers.next() == Some("3");
+    match (last, code) {
+        (b'u', 99) if ctrl && !released => Event::Hotkey(Hotkey::Quit),
+        (b'u', c) => match key_name(c) {
+            Some(name) if released => Event::Release(name),
+            Some(name) => Event::Press(name),
+            None => Event::Ignore,
+        },
+        (b'A', _) if !released => Event::Press(String::from("Up")),
+        (b'B', _) if !released => Event::Press(String::from("Down")),
+        (b'C', _) if !released => Event::Press(String::from("Right")),
+        (b'D', _) if !released => Event::Press(String::from("Left")),
+        (b'A', _) => Event::Release(String::from("Up")),
+        (b'B', _) => Event::Release(String::from("Down")),
+        (b'C', _) => Event::Release(String::from("Right")),
+        (b'D', _) => Event::Release(String::from("Left")),
+        (b'P', 1) if !released => Event::Hotkey(Hotkey::Hud),
+        (b'~', 11) if !released => Event::Hotkey(Hotkey::Hud),
+        (b'Q', 1) | (b'~', 12) => Event::Hotkey(Hotkey::Rewind(!released)),
+        (b'~', 20) if !released => Event::Hotkey(Hotkey::Record),
+        _ => Event::Ignore,
+    }
+}
+
+// SGR mouse reports are CSI < button;column;row then M for a press or m
+// for a release. Only the left button counts.
+fn click(params: &str, last: u8) -> Event {
+    let fields: Vec<usize> = params.split(';').filter_map(|f| f.parse().ok()).collect();
+    match (fields.as_slice(), last) {
+        ([0, column, row], b'M') => keypad_at(*column, *row).map_or(Event::Ignore, Event::Click),
+        ([0, ..], b'm') => Event::Unclick,
+        _ => Event::Ignore,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn press(name: &str) -> Event {
+        Event::Press(name.to_string())
+    }
+
+    // Input from bytes sent down a channel instead of the terminal
+    fn input(keymap: Keymap) -> (TerminalInput, std::sync::mpsc::Sender<u8>) {
+        let (send, bytes) = channel();
+        let keys = keymap.keys();
+        let input = TerminalInput {
+            bytes,
+            keymap,
+            pending: Vec::new(),
+            heard: Instant::now(),
+            timeout: Duration::from_secs(60),
+            until: vec![None; keys],
+            latched: vec![false; keys],
+            down: vec![false; keys],
+            events: Vec::new(),
+            hotkeys: VecDeque::new(),
+            rewind: None,
+            mouse: false,
+            clicked: None,
+            saved: None,
+        };
+        (input, send)
+    }
+
+    #[test]
+    fn key_names() {
+        assert_eq!(key_name(b'q' as u32).as_deref(), Some("Q"));
+        assert_eq!(key_name(b'Q' as u32).as_deref(), Some("Q"));
+        assert_eq!(key_name(b'7' as u32).as_deref(), Some("Key7"));
+        assert_eq!(key_name(b';' as u32).as_deref(), Some("Semicolon"));
+        assert_eq!(key_name(13).as_deref(), Some("Enter"));
+        assert_eq!(key_name(27).as_deref(), Some("Escape"));
+        assert_eq!(key_name(57399).as_deref(), Some("Numpad0"));
+        assert_eq!(key_name(57408).as_deref(), Some("Numpad9"));
+        assert_eq!(key_name(57414).as_deref(), Some("Enter"));
+        assert_eq!(key_name(57409), None);
+        assert_eq!(key_name(b'!' as u32), None);
+    }
+
+    #[test]
+    fn kitty_keys() {
+        assert_eq!(csi("113", b'u'), press("Q"));
+        assert_eq!(csi("113;1:1", b'u'), press("Q"));
+        assert_eq!(csi("113;1:2", b'u'), press("Q"));
+        assert_eq!(csi("113;1:3", b'u'), Event::Release(String::from("Q")));
+        // shifted keys report the base key first
+        assert_eq!(csi("97:65;2", b'u'), press("A"));
+        assert_eq!(csi("99;5", b'u'), Event::Hotkey(Hotkey::Quit));
+        assert_eq!(csi("99;5:3", b'u'), Event::Release(String::from("C")));
+        assert_eq!(csi("57441", b'u'), Event::Ignore);
+        assert_eq!(csi("1;1:3", b'A'), Event::Release(String::from("Up")));
+        assert_eq!(csi("1;1:3", b'Q'), Event::Hotkey(Hotkey::Rewind(false)));
+    }
+
+    #[test]
+    fn legacy_keys() {
+        assert_eq!(csi("", b'A'), press("Up"));
+        assert_eq!(csi("", b'D'), press("Left"));
+        assert_eq!(csi("11", b'~'), Event::Hotkey(Hotkey::Hud));
+        assert_eq!(csi("12", b'~'), Event::Hotkey(Hotkey::Rewind(true)));
+        assert_eq!(csi("20", b'~'), Event::Hotkey(Hotkey::Record));
+        assert_eq!(csi("3", b'~'), Event::Ignore);
+    }
+
+    #[test]
+    fn decode_sequences() {
+        assert_eq!(decode(b"w"), Some((1, press("W"), false)));
+        assert_eq!(decode(b"\x03"), Some((1, Event::Hotkey(Hotkey::Quit), false)));
+        assert_eq!(decode(b"\x1BOPx"), Some((3, Event::Hotkey(Hotkey::Hud), false)));
+        assert_eq!(decode(b"\x1B[Ax"), Some((3, press("Up"), false)));
+        assert_eq!(decode(b"\x1B[113;1:3u"), Some((10, Event::Release(String::from("Q")), true)));
+        assert_eq!(decode(b"\x1B[<0;67;1M"), Some((10, Event::Click(1), false)));
+        assert_eq!(decode(b"\x1B[<0;67;1m"), Some((10, Event::Unclick, false)));
+        // alt and a key
+        assert_eq!(decode(b"\x1Bw"), Some((1, Event::Ignore, false)));
+        // the rest of the sequence is still to come
+        assert_eq!(decode(b""), None);
+        assert_eq!(decode(b"\x1B"), None);
+        assert_eq!(decode(b"\x1BO"), None);
+        assert_eq!(decode(b"\x1B[113;1"), None);
+    }
+
+    #[test]
+    fn lone_escape_is_the_key() {
+        let keymap = Keymap::load(Some(("test", "[layout esc]\n0 = Escape\n")), Some("esc"), "").unwrap();
+        let (mut input, send) = input(keymap);
+        let mut events = VecDeque::new();
+        send.send(0x1B).unwrap();
+        input.poll(0, &mut events);
+        assert!(events.is_empty());
+        assert_eq!(input.pending, [0x1B]);
+        input.heard -= ESCAPE_WAIT;
+        input.poll(1, &mut events);
+        assert!(input.pending.is_empty());
+        assert_eq!(events.pop_front().map(|e| (e.key, e.down, e.frame)), Some((0, true, 1)));
+        // an arrow key sent all at once is not an escape
+        for &b in b"\x1B[A" {
+            send.send(b).unwrap();
+        }
+        input.poll(2, &mut events);
+        assert!(input.pending.is_empty() && events.is_empty());
+    }
+}
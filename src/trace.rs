@@ -0,0 +1,177 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+use crate::instruction::Instruction;
+
+//
+// One line per instruction run, with the state it left behind, for
+// diffing runs against each other: cycle, frame, pc, opcode, instruction,
+// V0-VF and I.
+//
+//          106     11  0218 F015  delay := v0          60 00 00 00 29 02 ... 00  0050
+//
+// Lines can be limited to a range of addresses and a range of frames. An
+// FX0A wait is traced when it starts and when its key comes, not on each
+// time round while it waits.
+//
+pub struct Trace {
+    out: Box<dyn Write>,
+    pub addresses: Option<(u16, u16)>,
+    pub frames: Option<(u64, u64)>,
+    failed: bool,
+}
+
+impl Trace {
+    pub fn create(path: &str) -> std::io::Result<Trace> {
+        Ok(Trace::new(Box::new(BufWriter::new(File::create(path)?))))
+    }
+
+    pub fn new(out: Box<dyn Write>) -> Trace {
+        Trace {
+            out,
+            addresses: None,
+            frames: None,
+            failed: false,
+        }
+    }
+
+    // Called after each instruction with where it was and what it was
+    pub fn instruction(&mut self, cycle: u64, frame: u64, pc: u16, opcode: u16, v: &[u8; 16], i: u16) -> Option<String> {
+        let wanted = self.addresses.is_none_or(|(from, to)| (from..=to).contains(&pc))
+            && self.frames.is_none_or(|(from, to)| (from..=to).contains(&frame));
+        if !wanted || self.failed {
+            return None;
+        }
+        let text = Instruction::decode(opcode)
+            .and_then(|i| i.octo(&|a| format!("0x{:03X}", a)))
+            .unwrap_or_else(|| String::from("?"));
+        let v: Vec<String> = v.iter().map(|r| format!("{:02X}", r)).collect();
+        let line = format!(
+            "{:>10} {:>6}  {:04X} {:04X}  {:<20} {}  {:04X}",
+            cycle,
+            frame,
+            pc,
+            opcode,
+            text,
+            v.join(" "),
+            i
+        );
+        match writeln!(self.out, "{}", line) {
+            Ok(()) => None,
+            Err(e) => {
+                self.failed = true;
+                Some(format!("trace stopped: {}", e))
+            }
+        }
+    }
+}
+
+impl Drop for Trace {
+    fn drop(&mut self) {
+        let _ = self.out.flush();
+    }
+}
+
+// FROM-TO, both ends included, a single number is a range of one
+pub fn range(text: &str, radix: u32) -> Option<(u64, u64)> {
+    let (from, to) = text.split_once('-').unwrap_or((text, text));
+    let number = |text: &str| {
+        let text = if radix == 16 { text.strip_prefix("0x").unwrap_or(text) } else { text };
+        u64::from_str_radix(text, radix).ok()
+    };
+    let (from, to) = (number(from)?, number(to)?);
+    (from <= to).then_some((from, to))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::machine;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    // Keeps what was written where the test can still see it
+    #[derive(Clone, Default)]
+    struct Shared(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Shared {
+        fn lines(&self) -> Vec<String> {
+            String::from_utf8_lossy(&self.0.borrow()).lines().map(String::from).collect()
+        }
+    }
+
+    #[test]
+    fn lines() {
+        let out = Shared::default();
+        let mut trace = Trace::new(Box::new(out.clone()));
+        let mut v = [0; 16];
+        v[..6].copy_from_slice(&[0x60, 0, 0, 0, 0x29, 0x02]);
+        assert_eq!(trace.instruction(106, 11, 0x218, 0xF015, &v, 0x50), None);
+        assert_eq!(trace.instruction(107, 11, 0x21A, 0x2ABC, &v, 0x50), None);
+        assert_eq!(trace.instruction(108, 11, 0x21C, 0xF0FF, &[0; 16], 0), None);
+        let zeros = " 00".repeat(10);
+        assert_eq!(
+            out.lines(),
+            [
+                format!("       106     11  0218 F015  delay := v0          60 00 00 00 29 02{}  0050", zeros),
+                format!("       107     11  021A 2ABC  :call 0xABC          60 00 00 00 29 02{}  0050", zeros),
+                format!("       108     11  021C F0FF  ?                    00{}{}  0000", zeros, " 00".repeat(5)),
+            ]
+        );
+    }
+
+    #[test]
+    fn filters() {
+        let out = Shared::default();
+        let mut trace = Trace::new(Box::new(out.clone()));
+        trace.addresses = Some((0x202, 0x204));
+        trace.frames = Some((2, 3));
+        for (frame, pc) in [(1, 0x202), (2, 0x200), (2, 0x202), (3, 0x204), (3, 0x206), (4, 0x204)] {
+            trace.instruction(0, frame, pc, 0x00E0, &[0; 16], 0);
+        }
+        let traced: Vec<String> = out.lines().iter().map(|l| l[11..23].to_string()).collect();
+        assert_eq!(traced, ["     2  0202", "     3  0204"]);
+    }
+
+    #[test]
+    fn ranges() {
+        assert_eq!(range("200-2FF", 16), Some((0x200, 0x2FF)));
+        assert_eq!(range("0x200-0x2ff", 16), Some((0x200, 0x2FF)));
+        assert_eq!(range("300", 16), Some((0x300, 0x300)));
+        assert_eq!(range("60-120", 10), Some((60, 120)));
+        assert_eq!(range("0x0x200", 16), None);
+        assert_eq!(range("0x10", 10), None);
+        assert_eq!(range("2FF-200", 16), None);
+        assert_eq!(range("200-", 16), None);
+        assert_eq!(range("1-2-3", 10), None);
+    }
+
+    #[test]
+    fn key_wait_is_traced_once() {
+        // v0 := 5, v1 := key, jump to itself
+        let (mut chip, _) = machine(&[0x60, 0x05, 0xF1, 0x0A, 0x12, 0x04]);
+        let out = Shared::default();
+        chip.trace = Some(Trace::new(Box::new(out.clone())));
+        for _ in 0..10 {
+            chip.run_tick();
+        }
+        chip.key[3] = 0xFF;
+        chip.run_tick();
+        chip.key[3] = 0;
+        chip.run_tick();
+        chip.run_tick();
+        let pcs: Vec<String> = out.lines().iter().map(|l| l[19..28].to_string()).collect();
+        assert_eq!(pcs, ["0200 6005", "0202 F10A", "0202 F10A", "0204 1204"]);
+        assert_eq!(chip.V[1], 3);
+    }
+}
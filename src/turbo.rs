@@ -0,0 +1,224 @@
+use std::collections::VecDeque;
+
+use crate::keymap::{Keymap, MACRO};
+use crate::{Hotkey, Input, KeyEvent};
+
+//
+// Turbo keys and macros from the keymap, wrapped around whichever input
+// the keys come from. A turbo key held down alternates between pressed and
+// released at its rate, a macro key plays its steps from the frame it was
+// pressed. Keys that are neither pass through untouched.
+//
+pub struct TurboInput<I> {
+    inner: I,
+    // frames in one press and release, 0 for keys without turbo
+    period: [u64; 16],
+    macros: Vec<Vec<(u16, u64)>>,
+    held: [bool; 16],
+    pressed_at: [u64; 16],
+    // macros playing and the frame each started on
+    running: Vec<(usize, u64)>,
+    // keypad as the core was last told
+    out: [bool; 16],
+    raw: VecDeque<KeyEvent>,
+}
+
+impl<I: Input> TurboInput<I> {
+    pub fn new(inner: I, keymap: &Keymap) -> Self {
+        let mut period = [0; 16];
+        for (key, rate) in &keymap.turbo {
+            period[*key as usize] = (60 / *rate as u64).max(2);
+        }
+        TurboInput {
+            inner,
+            period,
+            macros: keymap.macros.iter().map(|m| m.steps.clone()).collect(),
+            held: [false; 16],
+            pressed_at: [u64::MAX; 16],
+            running: Vec::new(),
+            out: [false; 16],
+            raw: VecDeque::new(),
+        }
+    }
+
+    // Keys the running macros hold at frame, dropping finished ones and
+    // ones started after frame, when rewinding went back past their start
+    fn macro_mask(&mut self, frame: u64) -> u16 {
+        let macros = &self.macros;
+        let mut mask = 0;
+        self.running.retain(|(n, start)| {
+            let mut at = match frame.checked_sub(*start) {
+                Some(at) => at,
+                None => return false,
+            };
+            for (keys, frames) in &macros[*n] {
+                if at < *frames {
+                    mask |= keys;
+                    return true;
+                }
+                at -= frames;
+            }
+            false
+        });
+        mask
+    }
+}
+
+impl<I: Input> Input for TurboInput<I> {
+    fn poll(&mut self, frame: u64, events: &mut VecDeque<KeyEvent>) {
+        self.inner.poll(frame, &mut self.raw);
+        let active = self.macro_mask(frame);
+        while let Some(event) = self.raw.pop_front() {
+            let key = event.key as usize;
+            if event.key >= MACRO {
+                let n = key - MACRO as usize;
+                if event.down && n < self.macros.len() {
+                    // pressing a macro again starts it over
+                    self.running.retain(|(m, _)| *m != n);
+                    self.running.push((n, frame));
+                }
+                continue;
+            }
+            self.held[key] = event.down;
+            if self.period[key] == 0 && active & 1 << key == 0 {
+                // plain keys keep every change, taps inside a frame too
+                self.out[key] = event.down;
+                events.push_back(event);
+            } else if event.down {
+                self.pressed_at[key] = frame;
+            }
+        }
+        let active = self.macro_mask(frame);
+        for key in 0..16 {
+            let period = self.period[key];
+            let turbo = period == 0
+                || (frame.wrapping_sub(self.pressed_at[key]) % period < period.div_ceil(2));
+            // a turbo tap shorter than a frame still fires once
+            let held = self.held[key] || self.pressed_at[key] == frame;
+            let down = held && turbo || active & 1 << key != 0;
+            if down != self.out[key] {
+                self.out[key] = down;
+                events.push_back(KeyEvent { key: key as u8, down, frame });
+            }
+        }
+    }
+    fn hotkey(&mut self) -> Option<Hotkey> {
+        self.inner.hotkey()
+    }
+    fn keypad(&mut self, keys: &[u8; 16]) {
+        self.inner.keypad(keys);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Hands out each event on its frame
+    struct Script(Vec<KeyEvent>);
+
+    impl Input for Script {
+        fn poll(&mut self, frame: u64, events: &mut VecDeque<KeyEvent>) {
+            events.extend(self.0.iter().filter(|e| e.frame == frame).copied());
+        }
+    }
+
+    fn event(key: u8, down: bool, frame: u64) -> KeyEvent {
+        KeyEvent { key, down, frame }
+    }
+
+    fn poll<I: Input>(input: &mut TurboInput<I>, frame: u64) -> Vec<KeyEvent> {
+        let mut events = VecDeque::new();
+        input.poll(frame, &mut events);
+        events.into()
+    }
+
+    fn turbo(key: u8, rate: u32, script: Vec<KeyEvent>) -> TurboInput<Script> {
+        let mut keymap = Keymap::default();
+        keymap.turbo.push((key, rate));
+        TurboInput::new(Script(script), &keymap)
+    }
+
+    #[test]
+    fn turbo_periods() {
+        for (rate, period) in [(1, 60), (7, 8), (15, 4), (20, 3), (30, 2)] {
+            assert_eq!(turbo(5, rate, Vec::new()).period[5], period, "{} a second", rate);
+        }
+        // faster than every other frame can't be seen, it would never let go
+        assert_eq!(turbo(5, 45, Vec::new()).period[5], 2);
+        assert_eq!(turbo(5, 15, Vec::new()).period[4], 0);
+    }
+
+    #[test]
+    fn turbo_alternates_while_held() {
+        let mut input = turbo(5, 15, vec![event(5, true, 10), event(5, false, 19)]);
+        let mut down = Vec::new();
+        for frame in 10..22 {
+            for e in poll(&mut input, frame) {
+                assert_eq!(e.key, 5);
+                down.push((frame, e.down));
+            }
+        }
+        // two frames pressed, two released, then let go for good
+        assert_eq!(down, [(10, true), (12, false), (14, true), (16, false), (18, true), (19, false)]);
+    }
+
+    #[test]
+    fn turbo_odd_periods_press_longer() {
+        let mut input = turbo(2, 20, vec![event(2, true, 0)]);
+        let mut down = Vec::new();
+        for frame in 0..6 {
+            poll(&mut input, frame);
+            down.push(input.out[2]);
+        }
+        assert_eq!(down, [true, true, false, true, true, false]);
+    }
+
+    #[test]
+    fn turbo_let_go_while_released_sends_nothing() {
+        let mut input = turbo(5, 15, vec![event(5, true, 0), event(5, false, 3)]);
+        assert_eq!(poll(&mut input, 0), [event(5, true, 0)]);
+        assert_eq!(poll(&mut input, 2), [event(5, false, 2)]);
+        assert_eq!(poll(&mut input, 3), []);
+        assert_eq!(poll(&mut input, 4), []);
+    }
+
+    #[test]
+    fn turbo_tap_fires_once() {
+        let mut input = turbo(5, 15, vec![event(5, true, 7), event(5, false, 7)]);
+        assert_eq!(poll(&mut input, 7), [event(5, true, 7)]);
+        assert_eq!(poll(&mut input, 8), [event(5, false, 8)]);
+        assert_eq!(poll(&mut input, 11), []);
+    }
+
+    #[test]
+    fn plain_keys_pass_through() {
+        let mut input = turbo(5, 15, vec![event(3, true, 1), event(3, false, 1)]);
+        assert_eq!(poll(&mut input, 1), [event(3, true, 1), event(3, false, 1)]);
+    }
+
+    #[test]
+    fn macro_plays_its_steps() {
+        let script = Script(vec![event(MACRO, true, 10), event(MACRO, false, 10)]);
+        let mut input = TurboInput::new(script, &Keymap::default());
+        input.macros = vec![vec![(1 << 5, 2), (1 << 5 | 1 << 6, 1)]];
+        assert_eq!(poll(&mut input, 10), [event(5, true, 10)]);
+        assert_eq!(poll(&mut input, 11), []);
+        assert_eq!(poll(&mut input, 12), [event(6, true, 12)]);
+        assert_eq!(poll(&mut input, 13), [event(5, false, 13), event(6, false, 13)]);
+        assert!(input.running.is_empty());
+    }
+
+    #[test]
+    fn rewinding_past_a_macro_cancels_it() {
+        let script = Script(vec![event(MACRO, true, 10)]);
+        let mut input = TurboInput::new(script, &Keymap::default());
+        input.macros = vec![vec![(1 << 5, 30)]];
+        assert_eq!(poll(&mut input, 10), [event(5, true, 10)]);
+        assert_eq!(poll(&mut input, 11), []);
+        assert_eq!(poll(&mut input, 4), [event(5, false, 4)]);
+        assert!(input.running.is_empty());
+        // playing forward again presses it again
+        assert_eq!(poll(&mut input, 10), [event(5, true, 10)]);
+    }
+}